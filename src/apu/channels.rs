@@ -1,4 +1,5 @@
 use super::SequencesToFire;
+use crate::snapshot::{Snapshot, StateReader, StateWriter};
 use crate::utils::frame_sequencer::FrameSequencer;
 use blip_buf::BlipBuf;
 
@@ -326,11 +327,69 @@ impl Channel for SquareChannel {
     }
 }
 
+// The sample buffer is left as it is, so playback carries on from what was already generated.
+impl Snapshot for SquareChannel {
+    fn save_state(&self, state: &mut StateWriter) {
+        if let Some(sweep) = &self.sweep {
+            state.u8(u8::from(sweep));
+        }
+        state.u8(u8::from(&self.duty) | self.duty.length);
+        state.u8(self.duty.phase);
+        state.u8(u8::from(&self.volume_envelope));
+        state.u8(self.volume_envelope.current_volume);
+        state.u32(self.volume_envelope.sequence.timer);
+        state.u16(self.frequency.frequency);
+        state.u8(match self.trigger {
+            Trigger::Stopped => 0,
+            Trigger::Playing => 1,
+            Trigger::Restart => 2,
+        });
+        state.bool(matches!(self.play_mode, PlayMode::Counter));
+        // Sampling cycles count from the start of the buffer's frame, so only the distance to the
+        // next sample is comparable.
+        if !state.includes_output() {
+            state.u32(
+                self.next_sample_cycle
+                    .wrapping_sub(self.current_sampling_cycle),
+            );
+            return;
+        }
+        state.u32(self.current_sampling_cycle);
+        state.u32(self.next_sample_cycle);
+        state.u32(self.last_sample as u32);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) {
+        if self.sweep.is_some() {
+            self.sweep = Some(Sweep::from(state.u8()));
+        }
+        self.duty = Duty::from(state.u8());
+        self.duty.phase = state.u8() % 8;
+        self.volume_envelope = VolumeEnvelope::from(state.u8());
+        self.volume_envelope.current_volume = state.u8().min(VOLUME_MAX);
+        self.volume_envelope.sequence.timer = state.u32();
+        self.frequency.frequency = state.u16() & 0x07FF;
+        self.trigger = match state.u8() {
+            1 => Trigger::Playing,
+            2 => Trigger::Restart,
+            _ => Trigger::Stopped,
+        };
+        self.play_mode = match state.bool() {
+            true => PlayMode::Counter,
+            false => PlayMode::Consecutive,
+        };
+        self.current_sampling_cycle = state.u32();
+        self.next_sample_cycle = state.u32();
+        self.last_sample = state.u32() as i32;
+    }
+}
+
 pub(super) struct NoiseRegister {}
 
 impl NoiseRegister {
+    // Bits that can't be read back read as 1, as they do on hardware.
     pub fn read_nr10(channel: &SquareChannel) -> u8 {
-        u8::from(channel.sweep.as_ref().unwrap())
+        0x80 | u8::from(channel.sweep.as_ref().unwrap())
     }
 
     pub fn write_nr10(value: u8, channel: &mut SquareChannel) {
@@ -338,7 +397,7 @@ impl NoiseRegister {
     }
 
     pub fn read_nrx1(channel: &SquareChannel) -> u8 {
-        u8::from(&channel.duty)
+        0x3F | u8::from(&channel.duty)
     }
 
     pub fn write_nrx1(value: u8, channel: &mut SquareChannel) {
@@ -355,7 +414,7 @@ impl NoiseRegister {
 
     pub fn read_nrx3(_channel: &SquareChannel) -> u8 {
         // Frequencies are unreadable
-        0
+        0xFF
     }
 
     pub fn write_nrx3(value: u8, channel: &mut SquareChannel) {
@@ -370,7 +429,7 @@ impl NoiseRegister {
             PlayMode::Counter => 1,
             PlayMode::Consecutive => 0,
         } << 6;
-        play_mode
+        0xBF | play_mode
     }
 
     pub fn write_nrx4(value: u8, channel: &mut SquareChannel) {
@@ -1,5 +1,5 @@
-use super::AudioLoop;
-use crate::cpu::{CPU, CPU_CLOCK_RATE_HZ};
+use crate::cpu::CPU_CLOCK_RATE_HZ;
+use crate::emulator::Emulation;
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use cpal::{BufferSize, Stream};
 
@@ -12,7 +12,7 @@ pub enum CpalCreationError {
 }
 
 impl CpalAudioLoop {
-    pub fn new(mut cpu: CPU) -> Result<Self, CpalCreationError> {
+    pub fn new(mut emulation: Emulation) -> Result<Self, CpalCreationError> {
         let audio_host = cpal::default_host();
         let audio_device = audio_host.default_output_device();
         let audio_supported_configs_range =
@@ -38,19 +38,11 @@ impl CpalAudioLoop {
             cpal::SupportedBufferSize::Unknown => BufferSize::Default,
         };
 
-        cpu.bus
-            .apu
-            .initialize_buffers(audio_config.sample_rate.0, CPU_CLOCK_RATE_HZ);
+        emulation.initialize_audio(audio_config.sample_rate.0, CPU_CLOCK_RATE_HZ);
 
         let stream = audio_device.unwrap().build_output_stream(
             &audio_config,
-            move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-                let samples_needed = data.len() / 2;
-                <dyn AudioLoop>::run_cycles_for_desired_samples(samples_needed as u32, &mut cpu);
-                let mut samples = cpu.bus.apu.gather_samples();
-                let flattened_samples = samples.interleave();
-                data[..flattened_samples.len()].copy_from_slice(flattened_samples.as_slice());
-            },
+            move |data: &mut [f32], _: &cpal::OutputCallbackInfo| emulation.fill_buffer(data),
             |err| eprintln!("Error occurred on the output audio stream: {:?}", err),
         );
 
@@ -62,5 +54,3 @@ impl CpalAudioLoop {
         Ok(audio_player)
     }
 }
-
-impl AudioLoop for CpalAudioLoop {}
@@ -1,41 +1,45 @@
 use self::channels::{Channel, NoiseRegister, SquareChannel, StereoOutput};
-use crate::cpu::{CPU, CPU_CLOCK_RATE_HZ};
+use crate::cpu::CPU_CLOCK_RATE_HZ;
+use crate::model::Model;
+use crate::snapshot::{Snapshot, StateReader, StateWriter};
 use crate::utils::frame_sequencer::FrameSequencer;
 
 mod channels;
 pub mod cpal_audio_output;
+pub mod time_stretch;
 
 pub struct APU {
     square_with_sweep: SquareChannel,
     square_without_sweep: SquareChannel,
     sequencers: AudioSequencers,
     cycles: u32,
+    wave_ram: [u8; WAVE_RAM_SIZE],
 }
 
-pub trait AudioLoop {}
+const WAVE_RAM_BEGIN: usize = 0xFF30;
+const WAVE_RAM_END: usize = 0xFF3F;
+const WAVE_RAM_SIZE: usize = WAVE_RAM_END - WAVE_RAM_BEGIN + 1;
 
-impl dyn AudioLoop {
-    pub fn run_cycles_for_desired_samples(samples_needed: u32, cpu: &mut CPU) {
-        let cycles_to_run = cpu
-            .bus
-            .apu
-            .cycles_needed_to_generate_samples(samples_needed);
-        let mut cycles_ran = 0;
-        while cycles_ran < cycles_to_run {
-            let instruction_cycles = cpu.step_single_instruction();
-            cycles_ran += instruction_cycles as u32;
-        }
-        cpu.end_frame();
-    }
-}
+// Wave RAM is not cleared at power on. Monochrome models start with a pattern that varies between
+// units, this being a typical one, while the CGB and AGB start with alternating 0x00 and 0xFF.
+const DMG_WAVE_RAM: [u8; WAVE_RAM_SIZE] = [
+    0x84, 0x40, 0x43, 0xAA, 0x2D, 0x78, 0x92, 0x3C, 0x60, 0x59, 0x59, 0xB0, 0x34, 0xB8, 0x2E, 0xDA,
+];
+const CGB_WAVE_RAM: [u8; WAVE_RAM_SIZE] = [
+    0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF,
+];
 
 impl APU {
-    pub fn new() -> Self {
+    pub fn new(model: Model) -> Self {
         APU {
             square_with_sweep: SquareChannel::new_with_sweep(),
             square_without_sweep: SquareChannel::new_without_sweep(),
             sequencers: AudioSequencers::new(),
             cycles: 0,
+            wave_ram: match model.is_colour() {
+                true => CGB_WAVE_RAM,
+                false => DMG_WAVE_RAM,
+            },
         }
     }
 
@@ -95,7 +99,7 @@ impl APU {
 
     pub fn supports_io_register(address: usize) -> bool {
         match address {
-            0xFF10..=0xFF19 => true,
+            0xFF10..=0xFF19 | WAVE_RAM_BEGIN..=WAVE_RAM_END => true,
             _ => false,
         }
     }
@@ -111,6 +115,7 @@ impl APU {
             0xFF17 => NoiseRegister::read_nrx2(&self.square_without_sweep),
             0xFF18 => NoiseRegister::read_nrx3(&self.square_without_sweep),
             0xFF19 => NoiseRegister::read_nrx4(&self.square_without_sweep),
+            WAVE_RAM_BEGIN..=WAVE_RAM_END => self.wave_ram[address - WAVE_RAM_BEGIN],
             _ => panic!("Unknown command when reading from APU IO register!"),
         }
     }
@@ -126,11 +131,38 @@ impl APU {
             0xFF17 => NoiseRegister::write_nrx2(value, &mut self.square_without_sweep),
             0xFF18 => NoiseRegister::write_nrx3(value, &mut self.square_without_sweep),
             0xFF19 => NoiseRegister::write_nrx4(value, &mut self.square_without_sweep),
+            WAVE_RAM_BEGIN..=WAVE_RAM_END => self.wave_ram[address - WAVE_RAM_BEGIN] = value,
             _ => panic!("Unknown command when writing to APU IO register!"),
         }
     }
 }
 
+impl Snapshot for APU {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.square_with_sweep.save_state(state);
+        self.square_without_sweep.save_state(state);
+        state.u32(self.sequencers.frame_sequencer.timer);
+        state.u32(self.sequencers.length_sequencer.timer);
+        state.u32(self.sequencers.volume_sequencer.timer);
+        state.u32(self.sequencers.sweep_sequencer.timer);
+        state.bytes(&self.wave_ram);
+        if state.includes_output() {
+            state.u32(self.cycles);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) {
+        self.square_with_sweep.load_state(state);
+        self.square_without_sweep.load_state(state);
+        self.sequencers.frame_sequencer.timer = state.u32();
+        self.sequencers.length_sequencer.timer = state.u32();
+        self.sequencers.volume_sequencer.timer = state.u32();
+        self.sequencers.sweep_sequencer.timer = state.u32();
+        state.bytes(&mut self.wave_ram);
+        self.cycles = state.u32();
+    }
+}
+
 #[derive(Copy, Clone)]
 struct AudioSequencers {
     frame_sequencer: FrameSequencer,
@@ -0,0 +1,101 @@
+use super::channels::StereoOutput;
+use std::collections::VecDeque;
+
+// Changes how long the sound lasts without changing its pitch, so that the emulation can run
+// faster or slower than the audio device plays. The output is built from grains of the input,
+// each faded in and out and laid over the last by half its length. At normal speed the grains
+// are taken one after another and add back up to the input. Faster speeds skip some of the input
+// between grains and slower speeds take some of it twice.
+const GRAIN_SIZE: usize = 1024;
+const HOP_SIZE: usize = GRAIN_SIZE / 2;
+
+pub struct TimeStretch {
+    window: Vec<f32>,
+    input: VecDeque<[f32; 2]>,
+    // Where the next grain starts in the input, which can fall between samples at some speeds.
+    input_position: f64,
+    // The faded out half of the last grain, which the next one is added to.
+    overlap: Vec<[f32; 2]>,
+    output: VecDeque<[f32; 2]>,
+}
+
+impl Default for TimeStretch {
+    fn default() -> Self {
+        // A Hann window, which adds up to one wherever two of them overlap by half.
+        let window = (0..GRAIN_SIZE)
+            .map(|index| {
+                let phase = 2.0 * std::f32::consts::PI * index as f32 / GRAIN_SIZE as f32;
+                0.5 - 0.5 * phase.cos()
+            })
+            .collect();
+        TimeStretch {
+            window,
+            input: VecDeque::new(),
+            input_position: 0.0,
+            overlap: vec![[0.0; 2]; HOP_SIZE],
+            output: VecDeque::new(),
+        }
+    }
+}
+
+impl TimeStretch {
+    pub fn push(&mut self, samples: StereoOutput) {
+        self.input.extend(
+            samples
+                .left
+                .iter()
+                .zip(samples.right.iter())
+                .map(|(&left, &right)| [left, right]),
+        );
+    }
+
+    // The input still needed before the next grain can be taken.
+    pub fn input_needed(&self) -> usize {
+        (self.input_position.round() as usize + GRAIN_SIZE).saturating_sub(self.input.len())
+    }
+
+    pub fn output_available(&self) -> usize {
+        self.output.len()
+    }
+
+    // Adds half a grain of output, moving through the input by that times the speed.
+    pub fn add_grain(&mut self, speed: f64) {
+        let start = self.input_position.round() as usize;
+        if start + GRAIN_SIZE > self.input.len() {
+            return;
+        }
+        for index in 0..HOP_SIZE {
+            let sample = self.input[start + index];
+            let weight = self.window[index];
+            self.output.push_back([
+                self.overlap[index][0] + sample[0] * weight,
+                self.overlap[index][1] + sample[1] * weight,
+            ]);
+        }
+        for index in 0..HOP_SIZE {
+            let sample = self.input[start + HOP_SIZE + index];
+            let weight = self.window[HOP_SIZE + index];
+            self.overlap[index] = [sample[0] * weight, sample[1] * weight];
+        }
+
+        self.input_position += HOP_SIZE as f64 * speed;
+        let consumed = (self.input_position as usize).min(self.input.len());
+        self.input.drain(..consumed);
+        self.input_position -= consumed as f64;
+    }
+
+    // Fills the buffer with interleaved samples, with silence for any that aren't ready.
+    pub fn take_output(&mut self, data: &mut [f32]) {
+        for frame in data.chunks_mut(2) {
+            let sample = self.output.pop_front().unwrap_or([0.0; 2]);
+            for (output, &value) in frame.iter_mut().zip(sample.iter()) {
+                *output = value;
+            }
+        }
+    }
+
+    // Drops everything buffered, when the sound jumps to somewhere else.
+    pub fn clear(&mut self) {
+        *self = TimeStretch::default();
+    }
+}
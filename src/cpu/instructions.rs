@@ -40,6 +40,7 @@ pub(super) enum Instruction {
     RES(u8, ArithmeticSource),
     SET(u8, ArithmeticSource),
     HALT,
+    STOP,
     DI,
     EI,
     RETI,
@@ -447,6 +448,7 @@ impl Instruction {
                 0xE1 => Some(Instruction::POP(WordRegister::HL)),
                 0xF1 => Some(Instruction::POP(WordRegister::AF)),
                 0x76 => Some(Instruction::HALT),
+                0x10 => Some(Instruction::STOP),
                 0xF3 => Some(Instruction::DI),
                 0xFB => Some(Instruction::EI),
                 0xD9 => Some(Instruction::RETI),
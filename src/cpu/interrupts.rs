@@ -19,13 +19,13 @@ impl Interrupt {
     }
 
     pub(super) fn is_interrupt_enabled(&self, bus: &MemoryBus) -> bool {
-        let interrupt_enabled_byte = bus.read_byte(Interrupt::INTERRUPT_ENABLE_ADDRESS);
+        let interrupt_enabled_byte = bus.peek_byte(Interrupt::INTERRUPT_ENABLE_ADDRESS);
         let mask: u8 = self.interrupt_byte_mask();
         (interrupt_enabled_byte & mask) == mask
     }
 
     pub(super) fn is_interrupt_flag_set(&self, bus: &MemoryBus) -> bool {
-        let interrupt_flag_byte = bus.read_byte(Interrupt::INTERRUPT_FLAG_ADDRESS);
+        let interrupt_flag_byte = bus.peek_byte(Interrupt::INTERRUPT_FLAG_ADDRESS);
         let mask = self.interrupt_byte_mask();
         (interrupt_flag_byte & mask) == mask
     }
@@ -39,14 +39,14 @@ impl Interrupt {
     }
 
     fn set_interrupt_flag_to_value(&self, value: bool, bus: &mut MemoryBus) {
-        let interrupt_flag_byte = bus.read_byte(Interrupt::INTERRUPT_FLAG_ADDRESS);
+        let interrupt_flag_byte = bus.peek_byte(Interrupt::INTERRUPT_FLAG_ADDRESS);
         let mask = self.interrupt_byte_mask();
         let new_flag_byte = if value {
             interrupt_flag_byte.bitor(mask)
         } else {
             interrupt_flag_byte.bitand(mask.not())
         };
-        bus.write_byte(new_flag_byte, Interrupt::INTERRUPT_FLAG_ADDRESS);
+        bus.poke_byte(new_flag_byte, Interrupt::INTERRUPT_FLAG_ADDRESS);
     }
 
     fn interrupt_byte_mask(&self) -> u8 {
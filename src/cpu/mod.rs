@@ -1,58 +1,242 @@
 mod instructions;
 pub mod interrupts;
 mod registers;
+pub mod speed;
 pub mod timers;
+pub mod trace;
 
 use self::instructions::{
     AddressContainingRegister, ArithmeticSource, IncrementDecrementTarget, Instruction,
     JumpCondition, JumpTarget, LoadType, RestartTarget, RotateDirection,
 };
+use super::memory::boot_rom::BootRom;
 use super::memory::cartridge::Cartridge;
 use super::memory::MemoryBus;
+use crate::debugger::CallStack;
+use crate::model::Model;
+use crate::movie::{MovieSession, MovieStatus};
+use crate::snapshot::{Snapshot, StateReader, StateWriter};
+use crate::utils::crc32;
 use interrupts::{Interrupt, InterruptsToSet};
 use registers::Registers;
 use std::ops::{BitAnd, BitOr, BitXor, Not};
+use trace::Tracer;
 
 pub struct CPU {
     registers: Registers,
     pub bus: MemoryBus,
     interrupt_master_enable: bool,
     halted: bool,
+    tracer: Option<Tracer>,
+    call_stack: Option<CallStack>,
+    // Frames start every CYCLES_PER_FRAME cycles from power on, which is when new input is read.
+    frame: u64,
+    frame_cycles: u32,
+    movie: Option<MovieSession>,
+    power_on_state: Vec<u8>,
 }
 
 pub const CPU_CLOCK_RATE_HZ: u32 = 4194304;
+pub const CYCLES_PER_FRAME: u32 = 70224;
+// A, F, B, C, D, E, H, L, then SP and PC as little endian words.
+pub const REGISTER_FILE_SIZE: usize = 12;
+const HEADER_CHECKSUM: u16 = 0x014D;
+const SNAPSHOT_MAGIC: &[u8; 4] = b"GBSS";
+// Bumped whenever the layout of the saved state changes.
+const SNAPSHOT_VERSION: u8 = 2;
 
 impl CPU {
-    pub fn new(cart: Option<Cartridge>) -> Self {
-        CPU {
+    // Without a boot ROM the CPU starts at 0x0100 in the state the boot ROM would have left.
+    pub fn new(cart: Option<Cartridge>, model: Model, boot_rom: Option<BootRom>) -> Self {
+        let skip_boot = boot_rom.is_none();
+        let mut cpu = CPU {
             registers: Registers::new(),
-            bus: MemoryBus::new(cart),
+            bus: MemoryBus::new(cart, model, boot_rom),
             interrupt_master_enable: true,
             halted: false,
+            tracer: None,
+            call_stack: None,
+            frame: 0,
+            frame_cycles: 0,
+            movie: None,
+            power_on_state: Vec::new(),
+        };
+        if skip_boot {
+            cpu.bus.skip_boot();
+            let header_checksum = cpu.bus.peek_byte(HEADER_CHECKSUM);
+            cpu.registers = Registers::post_boot(model, cpu.bus.cgb_mode(), header_checksum);
+            cpu.interrupt_master_enable = false;
+        }
+        cpu.power_on_state = cpu.snapshot();
+        cpu
+    }
+
+    // Starts again from power on with the same cartridge, clearing everything else. The frame
+    // counter keeps going so that rewinding and movies never see time go backwards, though movies
+    // don't record resets and so won't play back past one.
+    pub fn power_cycle(&mut self) {
+        if let Some(MovieStatus::Recording) = self.movie_status() {
+            eprintln!("Resets aren't recorded in movies, so this one won't play back past here");
         }
+        let (frame, power_on_state) = (self.frame, std::mem::take(&mut self.power_on_state));
+        self.restore(&power_on_state)
+            .expect("The power on state always fits");
+        self.power_on_state = power_on_state;
+        self.frame = frame;
+    }
+
+    // Runs the boot ROM again, or starts the game again without one, keeping what was in RAM.
+    pub fn reset(&mut self) {
+        let ram = self.bus.ram_contents();
+        self.power_cycle();
+        self.bus.restore_ram_contents(&ram);
+    }
+
+    // The state of the whole machine, which can be restored into a CPU created with the same
+    // cartridge and model.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut state = StateWriter::default();
+        state.bytes(SNAPSHOT_MAGIC);
+        state.u8(SNAPSHOT_VERSION);
+        self.save_state(&mut state);
+        state.into_bytes()
     }
 
+    // A snapshot that doesn't fit this machine leaves it as it was.
+    pub fn restore(&mut self, snapshot: &[u8]) -> Result<(), String> {
+        let mut state = StateReader::new(snapshot);
+        let mut magic = [0; 4];
+        state.bytes(&mut magic);
+        if &magic != SNAPSHOT_MAGIC {
+            return Err("This is not a snapshot".to_string());
+        }
+        let version = state.u8();
+        if version != SNAPSHOT_VERSION {
+            return Err(format!(
+                "The snapshot is from version {} of the format, but version {} is needed",
+                version, SNAPSHOT_VERSION
+            ));
+        }
+        let mut backup = StateWriter::default();
+        self.save_state(&mut backup);
+        let backup = backup.into_bytes();
+        self.load_state(&mut state);
+        if !state.is_complete() {
+            self.load_state(&mut StateReader::new(&backup));
+            return Err("The snapshot is not from this cartridge and model".to_string());
+        }
+        if self.call_stack.is_some() {
+            self.call_stack = Some(CallStack::default());
+        }
+        Ok(())
+    }
+
+    // A checksum of the state other than the display, to check that two runs are in sync.
+    pub fn state_checksum(&self) -> u32 {
+        let mut state = StateWriter::comparable();
+        self.save_state(&mut state);
+        crc32(&state.into_bytes())
+    }
+
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    pub fn attach_movie(&mut self, movie: MovieSession) {
+        self.movie = Some(movie);
+    }
+
+    pub fn movie_status(&self) -> Option<&MovieStatus> {
+        self.movie.as_ref().map(|movie| movie.status())
+    }
+
+    pub fn attach_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    pub fn enable_call_stack_tracking(&mut self) {
+        self.call_stack = Some(CallStack::default());
+    }
+
+    pub fn call_stack(&self) -> Option<&CallStack> {
+        self.call_stack.as_ref()
+    }
+
+    pub fn program_counter(&self) -> u16 {
+        self.registers.pc
+    }
+
+    pub fn register_bytes(&self) -> [u8; REGISTER_FILE_SIZE] {
+        self.registers.to_bytes()
+    }
+
+    pub fn set_register_bytes(&mut self, bytes: &[u8; REGISTER_FILE_SIZE]) {
+        self.registers.set_from_bytes(bytes);
+    }
+
+    // Returns the cycles that passed at the normal clock rate, which is half the CPU cycles in
+    // double speed mode.
     pub fn step_single_instruction(&mut self) -> u8 {
-        let cycles_this_instruction = if self.halted {
+        let cycles_this_instruction = if let Some(cycles) = self.bus.hdma.take_stall_cycles() {
+            cycles
+        } else if self.halted {
             4
         } else {
             self.run_next_instruction()
         };
+        let real_time_cycles = self.bus.speed.real_time_cycles(cycles_this_instruction);
 
+        self.frame_cycles += real_time_cycles as u32;
+        if self.frame_cycles >= CYCLES_PER_FRAME {
+            self.frame_cycles -= CYCLES_PER_FRAME;
+            self.start_frame();
+        }
         self.run_interrupts(cycles_this_instruction);
 
         self.bus.timer.step(cycles_this_instruction);
-        self.bus.apu.step(cycles_this_instruction);
-        cycles_this_instruction
+        self.bus.apu.step(real_time_cycles);
+        real_time_cycles
+    }
+
+    // Runs to the start of the next frame, returning the cycles that were run.
+    pub fn run_frame(&mut self) -> u32 {
+        let frame = self.frame;
+        let mut cycles = 0;
+        while self.frame == frame {
+            cycles += self.step_single_instruction() as u32;
+        }
+        self.end_frame();
+        cycles
     }
 
     pub fn end_frame(&mut self) {
         self.bus.apu.end_frame();
-        self.bus.ppu.render();
+        if let Some(tracer) = &mut self.tracer {
+            tracer.flush();
+        }
+    }
+
+    // Input only changes between frames so that a movie can replay it at exactly the same points.
+    fn start_frame(&mut self) {
+        self.frame += 1;
+        let lagged = !self.bus.input.take_polled();
+        let mut joypads = self.bus.input.next_joypads();
+        if let Some(mut movie) = self.movie.take() {
+            movie.start_frame(self, lagged, &mut joypads);
+            self.movie = Some(movie);
+        }
+        let interrupts = self.bus.input.swap_to_next_joypad_state(joypads);
+        if interrupts.is_interrupt_set(Interrupt::Joypad) {
+            Interrupt::Joypad.set_interrupt_flag(&mut self.bus);
+        }
     }
 
     fn run_next_instruction(&mut self) -> u8 {
+        if let Some(tracer) = &mut self.tracer {
+            tracer.trace(&self.registers, &self.bus);
+        }
         let instruction = self.next_instruction().unwrap();
+        self.bus.ppu.hold_register_writes();
         let (next_pc, cycles) = self.execute(instruction);
         self.registers.pc = next_pc;
         cycles
@@ -67,12 +251,19 @@ impl CPU {
             Interrupt::Joypad,
         ];
 
-        let ppu_interrupts = self.bus.ppu.step(cycles);
-        let joypad_interrupts = self.bus.input.step();
+        let ppu_interrupts = self.bus.ppu.step(
+            self.bus.speed.real_time_cycles(cycles),
+            self.bus.speed.real_time_cycles(4),
+        );
+        // H-Blank DMA is paused while the CPU is halted.
+        if self.bus.ppu.entered_hblank() && !self.halted {
+            self.bus.run_hblank_dma();
+        }
+        let serial_interrupts = self.bus.serial.step(cycles);
 
         let mut interrupts_to_flag = InterruptsToSet::default();
         interrupts_to_flag.union(ppu_interrupts);
-        interrupts_to_flag.union(joypad_interrupts);
+        interrupts_to_flag.union(serial_interrupts);
 
         for interrupt in all_interrupts.iter() {
             if interrupts_to_flag.is_interrupt_set(*interrupt) {
@@ -91,11 +282,12 @@ impl CPU {
         }
     }
 
+    // Instruction fetches aren't data accesses, so watchpoints don't see them.
     fn next_instruction(&self) -> Result<Instruction, String> {
-        let mut instruction_byte = self.bus.read_byte(self.registers.pc);
+        let mut instruction_byte = self.bus.peek_byte(self.registers.pc);
         let prefix_instruction = instruction_byte == 0xCB;
         if prefix_instruction {
-            instruction_byte = self.bus.read_byte(self.registers.pc.wrapping_add(1));
+            instruction_byte = self.bus.peek_byte(self.registers.pc.wrapping_add(1));
         }
 
         Instruction::from_byte(instruction_byte, prefix_instruction).ok_or(format!(
@@ -497,6 +689,15 @@ impl CPU {
                 self.halted = true;
                 (self.registers.pc.wrapping_add(1), 4)
             }
+            Instruction::STOP => {
+                // Without a pending speed switch STOP waits for a button press, which wakes the
+                // CPU through the joypad interrupt the same way as HALT.
+                if !self.bus.speed.try_switch() {
+                    self.halted = true;
+                }
+                self.bus.timer.write_io_register(0, 0xFF04);
+                (self.registers.pc.wrapping_add(2), 4)
+            }
             Instruction::DI => {
                 self.interrupt_master_enable = false;
                 (self.registers.pc.wrapping_add(1), 4)
@@ -557,12 +758,12 @@ impl CPU {
     }
 
     fn read_next_word(&self) -> u16 {
-        (self.bus.read_byte(self.registers.pc + 2) as u16) << 8
-            | self.bus.read_byte(self.registers.pc + 1) as u16
+        (self.bus.peek_byte(self.registers.pc + 2) as u16) << 8
+            | self.bus.peek_byte(self.registers.pc + 1) as u16
     }
 
     fn read_next_byte(&self) -> u8 {
-        self.bus.read_byte(self.registers.pc + 1)
+        self.bus.peek_byte(self.registers.pc + 1)
     }
 
     fn add(&mut self, value: u8) -> u8 {
@@ -731,6 +932,7 @@ impl CPU {
         let address_to_return_to = self.registers.pc.wrapping_add(3);
         if take_jump {
             self.push(address_to_return_to);
+            self.record_call(address_if_taken);
             address_if_taken
         } else {
             address_to_return_to
@@ -739,7 +941,11 @@ impl CPU {
 
     fn ret(&mut self, take_jump: bool) -> u16 {
         if take_jump {
-            self.pop()
+            let return_address = self.pop();
+            if let Some(call_stack) = &mut self.call_stack {
+                call_stack.unwind(self.registers.sp);
+            }
+            return_address
         } else {
             self.registers.pc.wrapping_add(1)
         }
@@ -748,9 +954,16 @@ impl CPU {
     fn restart(&mut self, restart_target: RestartTarget) -> u16 {
         let address_to_return_to = self.registers.pc.wrapping_add(1);
         self.push(address_to_return_to);
+        self.record_call(restart_target as u16);
         restart_target as u16
     }
 
+    fn record_call(&mut self, target: u16) {
+        if let Some(call_stack) = &mut self.call_stack {
+            call_stack.push(self.registers.pc, target, self.registers.sp);
+        }
+    }
+
     fn rotate_through_carry(
         &mut self,
         value: u8,
@@ -860,12 +1073,36 @@ impl CPU {
     fn interrupt(&mut self, interrupt: Interrupt) {
         self.halted = false;
         self.push(self.registers.pc);
-        self.registers.pc = match interrupt {
+        let handler = match interrupt {
             Interrupt::VBlank => 0x40,
             Interrupt::LCDStat => 0x48,
             Interrupt::Timer => 0x50,
             Interrupt::Serial => 0x58,
             Interrupt::Joypad => 0x60,
         };
+        self.record_call(handler);
+        self.registers.pc = handler;
+    }
+}
+
+impl Snapshot for CPU {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.registers.to_bytes());
+        state.bool(self.interrupt_master_enable);
+        state.bool(self.halted);
+        state.u64(self.frame);
+        state.u32(self.frame_cycles);
+        self.bus.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) {
+        let mut registers = [0; REGISTER_FILE_SIZE];
+        state.bytes(&mut registers);
+        self.registers.set_from_bytes(&registers);
+        self.interrupt_master_enable = state.bool();
+        self.halted = state.bool();
+        self.frame = state.u64();
+        self.frame_cycles = state.u32() % CYCLES_PER_FRAME;
+        self.bus.load_state(state);
     }
 }
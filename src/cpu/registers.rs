@@ -1,3 +1,5 @@
+use crate::model::Model;
+
 pub(super) struct Registers {
     pub a: u8,
     pub b: u8,
@@ -70,6 +72,36 @@ impl Registers {
         }
     }
 
+    // The registers as each boot ROM leaves them. The DMG and MGB set the half carry and carry
+    // flags unless the cartridge header checksum is 0.
+    pub(super) fn post_boot(model: Model, cgb_mode: bool, header_checksum: u8) -> Self {
+        let flags = if header_checksum == 0 { 0x80 } else { 0xB0 };
+        let [a, f, b, c, d, e, h, l] = match model {
+            Model::DMG0 => [0x01, 0x00, 0xFF, 0x13, 0x00, 0xC1, 0x84, 0x03],
+            Model::DMG => [0x01, flags, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+            Model::MGB => [0xFF, flags, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+            Model::SGB => [0x01, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60],
+            Model::CGB if cgb_mode => [0x11, 0x80, 0x00, 0x00, 0xFF, 0x56, 0x00, 0x0D],
+            Model::AGB if cgb_mode => [0x11, 0x00, 0x01, 0x00, 0xFF, 0x56, 0x00, 0x0D],
+            // For DMG games B holds the title checksum the boot ROM looked up its colours with,
+            // taken here as that of an unrecognised title.
+            Model::CGB => [0x11, 0x80, 0x00, 0x00, 0x00, 0x08, 0x00, 0x7C],
+            Model::AGB => [0x11, 0x00, 0x01, 0x00, 0x00, 0x08, 0x00, 0x7C],
+        };
+        Registers {
+            a,
+            b,
+            c,
+            d,
+            e,
+            f: FlagsRegister::from(f),
+            h,
+            l,
+            pc: 0x0100,
+            sp: 0xFFFE,
+        }
+    }
+
     pub(super) fn get_bc(&self) -> u16 {
         ((self.b as u16) << 8) | (self.c as u16)
     }
@@ -105,4 +137,34 @@ impl Registers {
         self.a = ((value & 0xFF00) >> 8) as u8;
         self.f = FlagsRegister::from((value & 0x00FF) as u8);
     }
+
+    pub(super) fn to_bytes(&self) -> [u8; super::REGISTER_FILE_SIZE] {
+        [
+            self.a,
+            u8::from(self.f),
+            self.b,
+            self.c,
+            self.d,
+            self.e,
+            self.h,
+            self.l,
+            (self.sp & 0x00FF) as u8,
+            ((self.sp & 0xFF00) >> 8) as u8,
+            (self.pc & 0x00FF) as u8,
+            ((self.pc & 0xFF00) >> 8) as u8,
+        ]
+    }
+
+    pub(super) fn set_from_bytes(&mut self, bytes: &[u8; super::REGISTER_FILE_SIZE]) {
+        self.a = bytes[0];
+        self.f = FlagsRegister::from(bytes[1]);
+        self.b = bytes[2];
+        self.c = bytes[3];
+        self.d = bytes[4];
+        self.e = bytes[5];
+        self.h = bytes[6];
+        self.l = bytes[7];
+        self.sp = (bytes[8] as u16) | ((bytes[9] as u16) << 8);
+        self.pc = (bytes[10] as u16) | ((bytes[11] as u16) << 8);
+    }
 }
@@ -0,0 +1,63 @@
+use crate::snapshot::{Snapshot, StateReader, StateWriter};
+
+// KEY1 (0xFF4D). Writing bit 0 arms a speed switch, which the next STOP instruction performs.
+#[derive(Default)]
+pub struct SpeedSwitch {
+    double_speed: bool,
+    switch_armed: bool,
+}
+
+impl SpeedSwitch {
+    pub fn is_double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    // Returns whether a switch was armed, in which case STOP performs it instead of stopping.
+    pub fn try_switch(&mut self) -> bool {
+        if !self.switch_armed {
+            return false;
+        }
+        self.switch_armed = false;
+        self.double_speed = !self.double_speed;
+        true
+    }
+
+    // The PPU and APU keep running at normal speed when the CPU is in double speed mode.
+    pub fn real_time_cycles(&self, cycles: u8) -> u8 {
+        if self.double_speed {
+            cycles / 2
+        } else {
+            cycles
+        }
+    }
+
+    pub fn supports_io_register(&self, address: usize) -> bool {
+        matches!(address, 0xFF4D)
+    }
+
+    pub fn read_io_register(&self, address: usize) -> u8 {
+        match address {
+            0xFF4D => 0x7E | ((self.double_speed as u8) << 7) | (self.switch_armed as u8),
+            _ => panic!("Reading from unknown speed switch register!"),
+        }
+    }
+
+    pub fn write_io_register(&mut self, value: u8, address: usize) {
+        match address {
+            0xFF4D => self.switch_armed = (value & 1) != 0,
+            _ => panic!("Writing to unknown speed switch register!"),
+        }
+    }
+}
+
+impl Snapshot for SpeedSwitch {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.double_speed);
+        state.bool(self.switch_armed);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) {
+        self.double_speed = state.bool();
+        self.switch_armed = state.bool();
+    }
+}
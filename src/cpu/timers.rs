@@ -1,3 +1,4 @@
+use crate::snapshot::{Snapshot, StateReader, StateWriter};
 use crate::utils::frame_sequencer::FrameSequencer;
 
 pub struct Timers {
@@ -21,6 +22,10 @@ impl Timers {
         }
     }
 
+    pub fn set_divider(&mut self, divider: u8) {
+        self.divider = divider;
+    }
+
     pub fn supports_io_register(&self, address: usize) -> bool {
         matches!(address, 0xFF04)
     }
@@ -39,3 +44,15 @@ impl Timers {
         }
     }
 }
+
+impl Snapshot for Timers {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.divider);
+        state.u32(self.divider_sequencer.timer);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) {
+        self.divider = state.u8();
+        self.divider_sequencer.timer = state.u32();
+    }
+}
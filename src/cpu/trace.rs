@@ -0,0 +1,163 @@
+use super::registers::Registers;
+use crate::debugger::symbols::SymbolTable;
+use crate::memory::MemoryBus;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+// Writes one line per executed instruction in the Gameboy Doctor log format:
+// A:00 F:11 B:22 C:33 D:44 E:55 H:66 L:77 SP:8888 PC:9999 PCMEM:AA,BB,CC,DD
+// With symbols attached, each line is suffixed with " ; Label+$offset", which tools expecting the
+// plain format will not accept.
+pub struct Tracer {
+    output: BufWriter<File>,
+    symbols: Option<Arc<SymbolTable>>,
+    start: TraceCondition,
+    stop: Option<TraceCondition>,
+    state: TraceState,
+    instructions_seen: u64,
+    instructions_traced: u64,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum TraceCondition {
+    Immediately,
+    Boot,
+    ProgramCounter(u16),
+    Instructions(u64),
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum TraceState {
+    Waiting,
+    Tracing,
+    Finished,
+}
+
+impl FromStr for TraceCondition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        match s {
+            "now" => return Ok(TraceCondition::Immediately),
+            "boot" => return Ok(TraceCondition::Boot),
+            _ => {}
+        }
+        if let Some(pc) = s.strip_prefix("pc:") {
+            let pc = pc.trim_start_matches("0x");
+            return u16::from_str_radix(pc, 16)
+                .map(TraceCondition::ProgramCounter)
+                .map_err(|err| format!("Invalid program counter {}: {}", pc, err));
+        }
+        if let Some(count) = s.strip_prefix("count:") {
+            return count
+                .parse::<u64>()
+                .map(TraceCondition::Instructions)
+                .map_err(|err| format!("Invalid instruction count {}: {}", count, err));
+        }
+        Err(format!(
+            "Unknown trace condition {} (expected now, boot, pc:<hex> or count:<n>)",
+            s
+        ))
+    }
+}
+
+impl Tracer {
+    pub fn new(
+        path: &Path,
+        start: TraceCondition,
+        stop: Option<TraceCondition>,
+    ) -> std::io::Result<Self> {
+        Ok(Tracer {
+            output: BufWriter::new(File::create(path)?),
+            symbols: None,
+            start,
+            stop,
+            state: TraceState::Waiting,
+            instructions_seen: 0,
+            instructions_traced: 0,
+        })
+    }
+
+    pub fn with_symbols(mut self, symbols: Arc<SymbolTable>) -> Self {
+        self.symbols = Some(symbols);
+        self
+    }
+
+    pub(super) fn trace(&mut self, registers: &Registers, bus: &MemoryBus) {
+        self.instructions_seen += 1;
+        match self.state {
+            TraceState::Finished => return,
+            TraceState::Waiting => {
+                let start = match self.start {
+                    TraceCondition::Immediately => true,
+                    TraceCondition::Boot => bus.finished_boot(),
+                    TraceCondition::ProgramCounter(pc) => registers.pc == pc,
+                    TraceCondition::Instructions(count) => self.instructions_seen > count,
+                };
+                if !start {
+                    return;
+                }
+                self.state = TraceState::Tracing;
+            }
+            TraceState::Tracing => {}
+        }
+
+        let stop = match self.stop {
+            Some(TraceCondition::ProgramCounter(pc)) => registers.pc == pc,
+            Some(TraceCondition::Instructions(count)) => self.instructions_traced >= count,
+            Some(TraceCondition::Boot) => bus.finished_boot(),
+            Some(TraceCondition::Immediately) => true,
+            None => false,
+        };
+        if stop {
+            self.state = TraceState::Finished;
+            self.flush();
+            return;
+        }
+
+        let pc = registers.pc;
+        let result = write!(
+            self.output,
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            registers.a,
+            u8::from(registers.f),
+            registers.b,
+            registers.c,
+            registers.d,
+            registers.e,
+            registers.h,
+            registers.l,
+            registers.sp,
+            pc,
+            bus.peek_byte(pc),
+            bus.peek_byte(pc.wrapping_add(1)),
+            bus.peek_byte(pc.wrapping_add(2)),
+            bus.peek_byte(pc.wrapping_add(3)),
+        )
+        .and_then(|_| match &self.symbols {
+            Some(symbols) => {
+                match symbols.lookup(bus.banked_address(pc)) {
+                    Some((label, 0)) => writeln!(self.output, " ; {}", label),
+                    Some((label, offset)) => writeln!(self.output, " ; {}+${:X}", label, offset),
+                    None => writeln!(self.output),
+                }
+            }
+            None => writeln!(self.output),
+        });
+        if let Err(err) = result {
+            eprintln!("Stopping instruction trace, could not write: {}", err);
+            self.state = TraceState::Finished;
+        }
+        self.instructions_traced += 1;
+    }
+
+    pub fn flush(&mut self) {
+        if let Err(err) = self.output.flush() {
+            eprintln!("Could not flush instruction trace: {}", err);
+        }
+    }
+}
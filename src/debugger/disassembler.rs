@@ -0,0 +1,144 @@
+use super::symbols::SymbolTable;
+use crate::memory::MemoryBus;
+
+const REGISTERS: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+const REGISTER_PAIRS: [&str; 4] = ["BC", "DE", "HL", "SP"];
+const STACK_PAIRS: [&str; 4] = ["BC", "DE", "HL", "AF"];
+const CONDITIONS: [&str; 4] = ["NZ", "Z", "NC", "C"];
+const ALU: [&str; 8] = [
+    "ADD A,", "ADC A,", "SUB", "SBC A,", "AND", "XOR", "OR", "CP",
+];
+const ROTATIONS: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SWAP", "SRL"];
+const ACCUMULATOR_OPS: [&str; 8] = ["RLCA", "RRCA", "RLA", "RRA", "DAA", "CPL", "SCF", "CCF"];
+
+// Disassembles the instruction at the address, returning its text and length. Jump, call and
+// absolute memory operands are shown with the closest label when there are symbols.
+pub fn disassemble(bus: &MemoryBus, address: u16, symbols: Option<&SymbolTable>) -> (String, u16) {
+    let opcode = bus.peek_byte(address);
+    let byte = bus.peek_byte(address.wrapping_add(1));
+    let word = byte as u16 | (bus.peek_byte(address.wrapping_add(2)) as u16) << 8;
+    let target = |address: u16| match symbols
+        .and_then(|symbols| symbols.lookup(bus.banked_address(address)))
+    {
+        Some((name, 0)) => name.to_string(),
+        Some((name, offset)) => format!("{}+${:X}", name, offset),
+        None => format!("${:04X}", address),
+    };
+    let relative = address.wrapping_add(2).wrapping_add(byte as i8 as u16);
+
+    let x = (opcode >> 6) as usize;
+    let y = ((opcode >> 3) & 7) as usize;
+    let z = (opcode & 7) as usize;
+    let (p, q) = (y >> 1, y & 1);
+    let (text, length) = match (x, z) {
+        (0, 0) => match y {
+            0 => ("NOP".to_string(), 1),
+            1 => (format!("LD ({}),SP", target(word)), 3),
+            2 => ("STOP".to_string(), 2),
+            3 => (format!("JR {}", target(relative)), 2),
+            _ => (format!("JR {},{}", CONDITIONS[y - 4], target(relative)), 2),
+        },
+        (0, 1) if q == 0 => (format!("LD {},${:04X}", REGISTER_PAIRS[p], word), 3),
+        (0, 1) => (format!("ADD HL,{}", REGISTER_PAIRS[p]), 1),
+        (0, 2) => {
+            let pointer = ["(BC)", "(DE)", "(HL+)", "(HL-)"][p];
+            match q {
+                0 => (format!("LD {},A", pointer), 1),
+                _ => (format!("LD A,{}", pointer), 1),
+            }
+        }
+        (0, 3) if q == 0 => (format!("INC {}", REGISTER_PAIRS[p]), 1),
+        (0, 3) => (format!("DEC {}", REGISTER_PAIRS[p]), 1),
+        (0, 4) => (format!("INC {}", REGISTERS[y]), 1),
+        (0, 5) => (format!("DEC {}", REGISTERS[y]), 1),
+        (0, 6) => (format!("LD {},${:02X}", REGISTERS[y], byte), 2),
+        (0, _) => (ACCUMULATOR_OPS[y].to_string(), 1),
+        (1, _) if y == 6 && z == 6 => ("HALT".to_string(), 1),
+        (1, _) => (format!("LD {},{}", REGISTERS[y], REGISTERS[z]), 1),
+        (2, _) => (format!("{} {}", ALU[y], REGISTERS[z]), 1),
+        (_, 0) => match y {
+            0..=3 => (format!("RET {}", CONDITIONS[y]), 1),
+            4 => (format!("LDH ({}),A", target(0xFF00 | byte as u16)), 2),
+            5 => (format!("ADD SP,{}", byte as i8), 2),
+            6 => (format!("LDH A,({})", target(0xFF00 | byte as u16)), 2),
+            _ => (format!("LD HL,SP{:+}", byte as i8), 2),
+        },
+        (_, 1) if q == 0 => (format!("POP {}", STACK_PAIRS[p]), 1),
+        (_, 1) => match p {
+            0 => ("RET".to_string(), 1),
+            1 => ("RETI".to_string(), 1),
+            2 => ("JP HL".to_string(), 1),
+            _ => ("LD SP,HL".to_string(), 1),
+        },
+        (_, 2) => match y {
+            0..=3 => (format!("JP {},{}", CONDITIONS[y], target(word)), 3),
+            4 => ("LD (C),A".to_string(), 1),
+            5 => (format!("LD ({}),A", target(word)), 3),
+            6 => ("LD A,(C)".to_string(), 1),
+            _ => (format!("LD A,({})", target(word)), 3),
+        },
+        (_, 3) => match y {
+            0 => (format!("JP {}", target(word)), 3),
+            1 => (disassemble_prefixed(byte), 2),
+            6 => ("DI".to_string(), 1),
+            7 => ("EI".to_string(), 1),
+            _ => (format!("DB ${:02X}", opcode), 1),
+        },
+        (_, 4) if y < 4 => (format!("CALL {},{}", CONDITIONS[y], target(word)), 3),
+        (_, 5) if q == 0 => (format!("PUSH {}", STACK_PAIRS[p]), 1),
+        (_, 5) if p == 0 => (format!("CALL {}", target(word)), 3),
+        (_, 6) => (format!("{} ${:02X}", ALU[y], byte), 2),
+        (_, 7) => (format!("RST {}", target(y as u16 * 8)), 1),
+        _ => (format!("DB ${:02X}", opcode), 1),
+    };
+    (text, length)
+}
+
+fn disassemble_prefixed(opcode: u8) -> String {
+    let y = ((opcode >> 3) & 7) as usize;
+    let register = REGISTERS[(opcode & 7) as usize];
+    match opcode >> 6 {
+        0 => format!("{} {}", ROTATIONS[y], register),
+        1 => format!("BIT {},{}", y, register),
+        2 => format!("RES {},{}", y, register),
+        _ => format!("SET {},{}", y, register),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPU;
+    use crate::memory::cartridge::Cartridge;
+    use crate::model::Model;
+
+    #[test]
+    fn labels_jump_and_memory_operands() {
+        let mut rom = vec![0; 0x8000];
+        let code = [
+            0xC3, 0x50, 0x01, 0xCD, 0x03, 0x40, 0x18, 0xFE, 0xEA, 0x00, 0xC0, 0xCB, 0x7C,
+        ];
+        rom[0x0100..0x0100 + code.len()].copy_from_slice(&code);
+        let cpu = CPU::new(Some(Cartridge { rom }), Model::DMG, None);
+        let symbols = SymbolTable::parse("00:0150 Main\n01:4000 Routine\n00:c000 wBuffer\n");
+
+        let mut address = 0x0100;
+        let mut lines = vec![];
+        while address < 0x0100 + code.len() as u16 {
+            let (text, length) = disassemble(&cpu.bus, address, Some(&symbols));
+            lines.push(text);
+            address += length;
+        }
+        assert_eq!(
+            lines,
+            [
+                "JP Main",
+                "CALL Routine+$3",
+                "JR $0106",
+                "LD (wBuffer),A",
+                "BIT 7,H"
+            ]
+        );
+        assert_eq!(disassemble(&cpu.bus, 0x0100, None).0, "JP $0150");
+    }
+}
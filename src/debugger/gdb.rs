@@ -0,0 +1,623 @@
+use super::disassembler::disassemble;
+use super::symbols::{parse_location, BankedAddress, SymbolTable};
+use super::{WatchKind, WatchpointHit};
+use crate::cpu::{CPU, CYCLES_PER_FRAME, REGISTER_FILE_SIZE};
+use std::collections::HashSet;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+
+// Register numbering used in 'g'/'G'/'p'/'P' packets. The 8 bit registers are one byte each, SP
+// and PC are little endian words.
+const REGISTER_SP: usize = 8;
+const REGISTER_PC: usize = 9;
+const REGISTER_COUNT: usize = 10;
+
+// How many instructions to run between checks for an interrupt (0x03) from the client.
+const INTERRUPT_POLL_INTERVAL: u32 = 4096;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+enum StopReason {
+    Signal(u8),
+    Watchpoint(WatchpointHit),
+}
+
+enum SessionEnd {
+    Detached,
+    Killed,
+}
+
+pub fn serve(
+    cpu: &mut CPU,
+    port: u16,
+    symbols: Option<Arc<SymbolTable>>,
+    initial_breakpoints: &[BankedAddress],
+) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    serve_listener(cpu, listener, symbols, initial_breakpoints)
+}
+
+fn serve_listener(
+    cpu: &mut CPU,
+    listener: TcpListener,
+    symbols: Option<Arc<SymbolTable>>,
+    initial_breakpoints: &[BankedAddress],
+) -> io::Result<()> {
+    cpu.enable_call_stack_tracking();
+    let port = listener.local_addr()?.port();
+    loop {
+        eprintln!("Waiting for a GDB connection on 127.0.0.1:{}", port);
+        let (stream, peer) = listener.accept()?;
+        eprintln!("GDB connected from {}", peer);
+        let mut session = GdbSession::new(cpu, stream, symbols.clone())?;
+        session.breakpoints.extend(initial_breakpoints);
+        match session.run()? {
+            SessionEnd::Detached => eprintln!("GDB detached"),
+            SessionEnd::Killed => std::process::exit(0),
+        }
+    }
+}
+
+struct GdbSession<'a> {
+    cpu: &'a mut CPU,
+    stream: TcpStream,
+    pending: Vec<u8>,
+    no_ack: bool,
+    // Breakpoints only stop in the bank they were set in.
+    breakpoints: HashSet<BankedAddress>,
+    symbols: Option<Arc<SymbolTable>>,
+    last_stop: StopReason,
+    cycles_this_frame: u32,
+}
+
+impl<'a> GdbSession<'a> {
+    fn new(
+        cpu: &'a mut CPU,
+        stream: TcpStream,
+        symbols: Option<Arc<SymbolTable>>,
+    ) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        Ok(GdbSession {
+            cpu,
+            stream,
+            pending: vec![],
+            no_ack: false,
+            breakpoints: HashSet::new(),
+            symbols,
+            last_stop: StopReason::Signal(SIGTRAP),
+            cycles_this_frame: 0,
+        })
+    }
+
+    fn run(&mut self) -> io::Result<SessionEnd> {
+        loop {
+            let packet = match self.read_packet()? {
+                Some(packet) => packet,
+                None => return Ok(SessionEnd::Detached),
+            };
+            let packet = String::from_utf8_lossy(&packet).into_owned();
+            match packet.as_bytes().first() {
+                Some(b'k') => return Ok(SessionEnd::Killed),
+                Some(b'D') => {
+                    self.send_packet("OK")?;
+                    self.clear_debug_state();
+                    return Ok(SessionEnd::Detached);
+                }
+                Some(b'c') | Some(b's') => {
+                    if let Some(address) = parse_hex(&packet[1..]) {
+                        self.set_register(REGISTER_PC, address as u16);
+                    }
+                    let single_step = packet.starts_with('s');
+                    self.last_stop = self.resume(single_step)?;
+                    if !single_step {
+                        eprintln!("Stopped at {}", self.describe(self.cpu.program_counter()));
+                    }
+                    let reply = self.stop_reply();
+                    self.send_packet(&reply)?;
+                }
+                _ => {
+                    let reply = self.handle_query(&packet);
+                    self.send_packet(&reply)?;
+                    if packet == "QStartNoAckMode" {
+                        self.no_ack = true;
+                    }
+                }
+            }
+        }
+    }
+
+    // Unknown packets, including empty or garbled ones, get the empty reply.
+    fn handle_query(&mut self, packet: &str) -> String {
+        let (command, arguments) = match (packet.get(..1), packet.get(1..)) {
+            (Some(command), Some(arguments)) => (command, arguments),
+            _ => return String::new(),
+        };
+        match command {
+            "?" => self.stop_reply(),
+            "g" => encode_hex(&self.cpu.register_bytes()),
+            "G" => match decode_hex(arguments) {
+                Some(bytes) if bytes.len() >= REGISTER_FILE_SIZE => {
+                    let mut registers = [0; REGISTER_FILE_SIZE];
+                    registers.copy_from_slice(&bytes[..REGISTER_FILE_SIZE]);
+                    self.cpu.set_register_bytes(&registers);
+                    "OK".to_string()
+                }
+                _ => "E01".to_string(),
+            },
+            "p" => match parse_hex(arguments) {
+                Some(index) if (index as usize) < REGISTER_COUNT => {
+                    let value = self.get_register(index as usize);
+                    if index as usize >= REGISTER_SP {
+                        encode_hex(&[(value & 0x00FF) as u8, ((value & 0xFF00) >> 8) as u8])
+                    } else {
+                        encode_hex(&[value as u8])
+                    }
+                }
+                _ => "E01".to_string(),
+            },
+            "P" => {
+                let mut parts = arguments.splitn(2, '=');
+                let index = parts.next().and_then(parse_hex);
+                let value = parts.next().and_then(decode_hex);
+                match (index, value) {
+                    (Some(index), Some(value)) if (index as usize) < REGISTER_COUNT => {
+                        let value = value
+                            .iter()
+                            .rev()
+                            .fold(0u16, |word, byte| (word << 8) | *byte as u16);
+                        self.set_register(index as usize, value);
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            "m" => match parse_address_and_length(arguments) {
+                Some((address, length)) => {
+                    let bytes: Vec<u8> = (0..length)
+                        .map(|offset| self.cpu.bus.peek_byte(address.wrapping_add(offset)))
+                        .collect();
+                    encode_hex(&bytes)
+                }
+                None => "E01".to_string(),
+            },
+            "M" => {
+                let mut parts = arguments.splitn(2, ':');
+                let target = parts.next().and_then(parse_address_and_length);
+                let data = parts.next().and_then(decode_hex);
+                match (target, data) {
+                    (Some((address, length)), Some(data)) if data.len() == length as usize => {
+                        for (offset, byte) in data.iter().enumerate() {
+                            self.cpu
+                                .bus
+                                .poke_byte(*byte, address.wrapping_add(offset as u16));
+                        }
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            "Z" | "z" => {
+                let insert = command == "Z";
+                let mut parts = arguments.split(',');
+                let kind = parts.next();
+                let address = parts.next().and_then(parse_hex);
+                let length = parts.next().and_then(parse_hex).unwrap_or(1);
+                let address = match address {
+                    Some(address) => address as u16,
+                    None => return "E01".to_string(),
+                };
+                let watch_kind = match kind {
+                    // GDB only knows plain addresses, which mean the bank mapped there now.
+                    Some("0") | Some("1") => {
+                        let location = self.cpu.bus.banked_address(address);
+                        if insert {
+                            self.breakpoints.insert(location);
+                        } else {
+                            self.breakpoints.remove(&location);
+                        }
+                        return "OK".to_string();
+                    }
+                    Some("2") => WatchKind::Write,
+                    Some("3") => WatchKind::Read,
+                    Some("4") => WatchKind::Access,
+                    _ => return String::new(),
+                };
+                if insert {
+                    self.cpu
+                        .bus
+                        .watchpoints
+                        .insert(watch_kind, address, length as u16);
+                } else {
+                    self.cpu
+                        .bus
+                        .watchpoints
+                        .remove(watch_kind, address, length as u16);
+                }
+                "OK".to_string()
+            }
+            "H" => "OK".to_string(),
+            "q" | "Q" => match packet {
+                _ if packet.starts_with("qSupported") => {
+                    "PacketSize=1000;QStartNoAckMode+".to_string()
+                }
+                _ if packet.starts_with("qRcmd,") => match decode_hex(&packet[6..]) {
+                    Some(command) => {
+                        let command = String::from_utf8_lossy(&command).into_owned();
+                        let output = self.monitor_command(&command);
+                        encode_hex(output.as_bytes())
+                    }
+                    None => "E01".to_string(),
+                },
+                "qAttached" => "1".to_string(),
+                "qC" => "QC1".to_string(),
+                "qfThreadInfo" => "m1".to_string(),
+                "qsThreadInfo" => "l".to_string(),
+                "QStartNoAckMode" => "OK".to_string(),
+                _ => String::new(),
+            },
+            _ => String::new(),
+        }
+    }
+
+    // Commands sent with GDB's "monitor", which take symbols as well as addresses.
+    fn monitor_command(&mut self, command: &str) -> String {
+        let mut words = command.split_whitespace();
+        let verb = words.next().unwrap_or("");
+        let argument = words.next();
+        match (verb, argument) {
+            ("break", Some(location)) | ("delete", Some(location)) => {
+                match parse_location(location, self.symbols.as_deref()) {
+                    Ok(location) => {
+                        if verb == "break" {
+                            self.breakpoints.insert(location);
+                            format!("Breakpoint at {}\n", self.describe_location(location))
+                        } else {
+                            self.breakpoints.remove(&location);
+                            format!(
+                                "Deleted breakpoint at {}\n",
+                                self.describe_location(location)
+                            )
+                        }
+                    }
+                    Err(err) => format!("{}\n", err),
+                }
+            }
+            ("backtrace", None) | ("bt", None) => self.backtrace(),
+            ("disassemble", _) => {
+                let count = words.next().and_then(|count| count.parse().ok());
+                match argument.map(|location| parse_location(location, self.symbols.as_deref())) {
+                    Some(Ok(location)) => self.disassemble(location.address, count.unwrap_or(8)),
+                    Some(Err(err)) => format!("{}\n", err),
+                    None => self.disassemble(self.cpu.program_counter(), count.unwrap_or(8)),
+                }
+            }
+            ("info", Some("breakpoints")) => {
+                let mut breakpoints: Vec<_> = self.breakpoints.iter().copied().collect();
+                breakpoints.sort_unstable();
+                breakpoints
+                    .iter()
+                    .map(|location| format!("{}\n", self.describe_location(*location)))
+                    .collect()
+            }
+            _ => "Supported commands: break <location>, delete <location>, backtrace, \
+                  disassemble [location] [count], info breakpoints\n"
+                .to_string(),
+        }
+    }
+
+    fn disassemble(&self, mut address: u16, count: usize) -> String {
+        let mut output = String::new();
+        for _ in 0..count {
+            let (text, length) = disassemble(&self.cpu.bus, address, self.symbols.as_deref());
+            output += &format!("{:<24} {}\n", self.describe(address), text);
+            address = address.wrapping_add(length);
+        }
+        output
+    }
+
+    fn backtrace(&self) -> String {
+        let mut output = format!("#0  {}\n", self.describe(self.cpu.program_counter()));
+        if let Some(call_stack) = self.cpu.call_stack() {
+            for (depth, frame) in call_stack.frames().enumerate() {
+                output += &format!(
+                    "#{:<2} {} calling {}\n",
+                    depth + 1,
+                    self.describe(frame.call_site),
+                    self.describe(frame.target)
+                );
+            }
+        }
+        output
+    }
+
+    fn describe(&self, address: u16) -> String {
+        self.describe_location(self.cpu.bus.banked_address(address))
+    }
+
+    fn describe_location(&self, location: BankedAddress) -> String {
+        match &self.symbols {
+            Some(symbols) => symbols.describe(location),
+            None => location.to_string(),
+        }
+    }
+
+    fn resume(&mut self, single_step: bool) -> io::Result<StopReason> {
+        self.cpu.bus.watchpoints.take_hit();
+        let mut instructions_until_poll = INTERRUPT_POLL_INTERVAL;
+        loop {
+            let cycles = self.cpu.step_single_instruction();
+            self.cycles_this_frame += cycles as u32;
+            if self.cycles_this_frame >= CYCLES_PER_FRAME {
+                self.cycles_this_frame -= CYCLES_PER_FRAME;
+                self.cpu.end_frame();
+            }
+
+            if let Some(hit) = self.cpu.bus.watchpoints.take_hit() {
+                return Ok(StopReason::Watchpoint(hit));
+            }
+            let location = self.cpu.bus.banked_address(self.cpu.program_counter());
+            if single_step || self.breakpoints.contains(&location) {
+                return Ok(StopReason::Signal(SIGTRAP));
+            }
+
+            instructions_until_poll -= 1;
+            if instructions_until_poll == 0 {
+                instructions_until_poll = INTERRUPT_POLL_INTERVAL;
+                if self.interrupt_requested()? {
+                    return Ok(StopReason::Signal(SIGINT));
+                }
+            }
+        }
+    }
+
+    fn stop_reply(&self) -> String {
+        match self.last_stop {
+            StopReason::Signal(signal) => format!("S{:02x}", signal),
+            StopReason::Watchpoint(hit) => {
+                let kind = match hit.kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Access => "awatch",
+                };
+                format!("T{:02x}{}:{:04x};", SIGTRAP, kind, hit.address)
+            }
+        }
+    }
+
+    fn get_register(&self, index: usize) -> u16 {
+        let registers = self.cpu.register_bytes();
+        match index {
+            REGISTER_SP => (registers[8] as u16) | ((registers[9] as u16) << 8),
+            REGISTER_PC => (registers[10] as u16) | ((registers[11] as u16) << 8),
+            _ => registers[index] as u16,
+        }
+    }
+
+    fn set_register(&mut self, index: usize, value: u16) {
+        let mut registers = self.cpu.register_bytes();
+        match index {
+            REGISTER_SP | REGISTER_PC => {
+                let offset = 8 + (index - REGISTER_SP) * 2;
+                registers[offset] = (value & 0x00FF) as u8;
+                registers[offset + 1] = ((value & 0xFF00) >> 8) as u8;
+            }
+            _ => registers[index] = value as u8,
+        }
+        self.cpu.set_register_bytes(&registers);
+    }
+
+    fn clear_debug_state(&mut self) {
+        self.breakpoints.clear();
+        self.cpu.bus.watchpoints = Default::default();
+    }
+
+    fn interrupt_requested(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut buffer = [0; 64];
+        let result = self.stream.read(&mut buffer);
+        self.stream.set_nonblocking(false)?;
+        match result {
+            Ok(read) => {
+                self.pending.extend_from_slice(&buffer[..read]);
+                let interrupted = self.pending.contains(&0x03);
+                self.pending.retain(|byte| *byte != 0x03);
+                Ok(interrupted)
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn next_byte(&mut self) -> io::Result<Option<u8>> {
+        if !self.pending.is_empty() {
+            return Ok(Some(self.pending.remove(0)));
+        }
+        let mut byte = [0; 1];
+        match self.stream.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    fn read_packet(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            // Skip acknowledgements and stray interrupts until the start of a packet.
+            match self.next_byte()? {
+                Some(b'$') => {}
+                Some(_) => continue,
+                None => return Ok(None),
+            }
+
+            let mut packet = vec![];
+            loop {
+                match self.next_byte()? {
+                    Some(b'#') => break,
+                    Some(byte) => packet.push(byte),
+                    None => return Ok(None),
+                }
+            }
+            let mut checksum = [0; 2];
+            for digit in checksum.iter_mut() {
+                *digit = match self.next_byte()? {
+                    Some(byte) => byte,
+                    None => return Ok(None),
+                };
+            }
+
+            let expected = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|checksum| u8::from_str_radix(checksum, 16).ok());
+            if self.no_ack {
+                return Ok(Some(packet));
+            }
+            if expected == Some(packet_checksum(&packet)) {
+                self.stream.write_all(b"+")?;
+                return Ok(Some(packet));
+            }
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    fn send_packet(&mut self, data: &str) -> io::Result<()> {
+        let framed = format!("${}#{:02x}", data, packet_checksum(data.as_bytes()));
+        loop {
+            self.stream.write_all(framed.as_bytes())?;
+            if self.no_ack {
+                return Ok(());
+            }
+            match self.next_byte()? {
+                Some(b'+') | None => return Ok(()),
+                Some(b'-') => continue,
+                Some(other) => {
+                    // Not an acknowledgement, keep it for the next packet read.
+                    self.pending.insert(0, other);
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+fn packet_checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn parse_hex(text: &str) -> Option<u32> {
+    u32::from_str_radix(text, 16).ok()
+}
+
+fn parse_address_and_length(text: &str) -> Option<(u16, u16)> {
+    let mut parts = text.splitn(2, ',');
+    let address = parts.next().and_then(parse_hex)?;
+    let length = parts.next().and_then(parse_hex)?;
+    Some((address as u16, length as u16))
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    let digits = text.as_bytes();
+    digits
+        .chunks(2)
+        .map(|pair| match pair {
+            [high, low] => u8::from_str_radix(std::str::from_utf8(&[*high, *low]).ok()?, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::cartridge::Cartridge;
+    use crate::model::Model;
+    use std::thread;
+
+    // JP $0150, then at $0150: NOP, NOP, INC A, JR back to the NOPs.
+    fn test_cpu() -> CPU {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0100..0x0104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+        rom[0x0150..0x0155].copy_from_slice(&[0x00, 0x00, 0x3C, 0x18, 0xFB]);
+        CPU::new(Some(Cartridge { rom }), Model::DMG, None)
+    }
+
+    struct Client {
+        stream: TcpStream,
+    }
+
+    impl Client {
+        fn connect() -> Self {
+            let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+            let port = listener.local_addr().unwrap().port();
+            thread::spawn(move || {
+                let mut cpu = test_cpu();
+                serve_listener(&mut cpu, listener, None, &[])
+            });
+            Client {
+                stream: TcpStream::connect(("127.0.0.1", port)).unwrap(),
+            }
+        }
+
+        fn byte(&mut self) -> u8 {
+            let mut byte = [0; 1];
+            self.stream.read_exact(&mut byte).unwrap();
+            byte[0]
+        }
+
+        fn request(&mut self, packet: &str) -> String {
+            let framed = format!("${}#{:02x}", packet, packet_checksum(packet.as_bytes()));
+            self.stream.write_all(framed.as_bytes()).unwrap();
+            assert_eq!(self.byte(), b'+');
+            assert_eq!(self.byte(), b'$');
+            let mut reply = vec![];
+            loop {
+                match self.byte() {
+                    b'#' => break,
+                    byte => reply.push(byte),
+                }
+            }
+            let checksum = [self.byte(), self.byte()];
+            let checksum = u8::from_str_radix(std::str::from_utf8(&checksum).unwrap(), 16);
+            assert_eq!(checksum, Ok(packet_checksum(&reply)));
+            self.stream.write_all(b"+").unwrap();
+            String::from_utf8(reply).unwrap()
+        }
+    }
+
+    #[test]
+    fn scripted_session() {
+        let mut client = Client::connect();
+        assert_eq!(client.request("?"), "S05");
+        let registers = client.request("g");
+        assert_eq!(registers.len(), REGISTER_FILE_SIZE * 2);
+        assert_eq!(&registers[20..], "0001");
+        assert_eq!(client.request("m150,5"), "00003c18fb");
+        assert_eq!(client.request("Z0,152,1"), "OK");
+        assert_eq!(client.request("c"), "S05");
+        assert_eq!(client.request("p9"), "5201");
+        assert_eq!(client.request("c"), "S05");
+        assert_eq!(&client.request("g")[..2], "02");
+        let command = encode_hex(b"info breakpoints");
+        let output = decode_hex(&client.request(&format!("qRcmd,{}", command))).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "00:0152\n");
+        let command = encode_hex(b"disassemble $0152 2");
+        let output = decode_hex(&client.request(&format!("qRcmd,{}", command))).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            format!("{:<24} INC A\n{:<24} JR $0150\n", "00:0152", "00:0153")
+        );
+        assert_eq!(client.request("D"), "OK");
+    }
+
+    #[test]
+    fn malformed_packets_get_the_empty_reply() {
+        let mut client = Client::connect();
+        assert_eq!(client.request(""), "");
+        assert_eq!(client.request("\u{e9}x"), "");
+        assert_eq!(client.request("?"), "S05");
+    }
+}
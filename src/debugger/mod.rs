@@ -0,0 +1,139 @@
+pub mod disassembler;
+pub mod gdb;
+pub mod symbols;
+
+use std::cell::Cell;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct WatchpointHit {
+    pub kind: WatchKind,
+    pub address: u16,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+struct Watchpoint {
+    kind: WatchKind,
+    start: u16,
+    length: u16,
+}
+
+impl Watchpoint {
+    fn contains(&self, address: u16) -> bool {
+        address >= self.start && (address - self.start) < self.length.max(1)
+    }
+}
+
+// The program's data accesses are checked against these from MemoryBus::read_byte/write_byte.
+// Instruction fetches, DMA and the debugger itself go through peek_byte/poke_byte and aren't. The
+// first matching access is latched until the debugger takes it.
+#[derive(Default)]
+pub struct Watchpoints {
+    watches: Vec<Watchpoint>,
+    hit: Cell<Option<WatchpointHit>>,
+}
+
+impl Watchpoints {
+    pub fn insert(&mut self, kind: WatchKind, start: u16, length: u16) {
+        let watchpoint = Watchpoint {
+            kind,
+            start,
+            length,
+        };
+        if !self.watches.contains(&watchpoint) {
+            self.watches.push(watchpoint);
+        }
+    }
+
+    pub fn remove(&mut self, kind: WatchKind, start: u16, length: u16) {
+        let watchpoint = Watchpoint {
+            kind,
+            start,
+            length,
+        };
+        self.watches.retain(|existing| *existing != watchpoint);
+    }
+
+    pub fn on_read(&self, address: u16) {
+        self.check(address, WatchKind::Read);
+    }
+
+    pub fn on_write(&self, address: u16) {
+        self.check(address, WatchKind::Write);
+    }
+
+    pub fn take_hit(&self) -> Option<WatchpointHit> {
+        self.hit.take()
+    }
+
+    fn check(&self, address: u16, access: WatchKind) {
+        if self.watches.is_empty() {
+            return;
+        }
+        let already_hit = self.hit.get().is_some();
+        if already_hit {
+            return;
+        }
+        let matching_watch = self.watches.iter().find(|watch| {
+            (watch.kind == access || watch.kind == WatchKind::Access) && watch.contains(address)
+        });
+        if let Some(watch) = matching_watch {
+            self.hit.set(Some(WatchpointHit {
+                kind: watch.kind,
+                address,
+            }));
+        }
+    }
+}
+
+// Deep enough for any sane program, and bounded for code that never returns.
+const MAX_CALL_DEPTH: usize = 256;
+
+#[derive(Copy, Clone)]
+pub struct CallFrame {
+    pub call_site: u16,
+    pub target: u16,
+    stack_pointer: u16,
+}
+
+// Shadow stack of CALL/RST/interrupt entries. Frames are unwound by stack pointer rather than by
+// counting returns, so code that adjusts SP by hand does not leave stale frames behind.
+#[derive(Default)]
+pub struct CallStack {
+    frames: Vec<CallFrame>,
+}
+
+impl CallStack {
+    pub fn push(&mut self, call_site: u16, target: u16, stack_pointer: u16) {
+        // Any frame at or below the new return address can no longer be live.
+        self.unwind(stack_pointer.saturating_add(1));
+        if self.frames.len() == MAX_CALL_DEPTH {
+            self.frames.remove(0);
+        }
+        self.frames.push(CallFrame {
+            call_site,
+            target,
+            stack_pointer,
+        });
+    }
+
+    // Drops frames whose return address has been popped, given the stack pointer after a return.
+    pub fn unwind(&mut self, stack_pointer: u16) {
+        while let Some(frame) = self.frames.last() {
+            if frame.stack_pointer >= stack_pointer {
+                break;
+            }
+            self.frames.pop();
+        }
+    }
+
+    pub fn frames(&self) -> impl Iterator<Item = &CallFrame> {
+        self.frames.iter().rev()
+    }
+}
@@ -0,0 +1,203 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::Path;
+
+// A banked address as written in RGBDS .sym files, e.g. 01:4000.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub struct BankedAddress {
+    pub bank: u16,
+    pub address: u16,
+}
+
+impl fmt::Display for BankedAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02X}:{:04X}", self.bank, self.address)
+    }
+}
+
+#[derive(Default)]
+pub struct SymbolTable {
+    by_address: BTreeMap<BankedAddress, String>,
+    by_name: HashMap<String, BankedAddress>,
+}
+
+impl SymbolTable {
+    pub fn load_for_rom(rom_path: &Path) -> Option<Self> {
+        let sym_path = rom_path.with_extension("sym");
+        let contents = std::fs::read_to_string(&sym_path).ok()?;
+        let symbols = Self::parse(&contents);
+        eprintln!(
+            "Loaded {} symbols from {}",
+            symbols.by_name.len(),
+            sym_path.display()
+        );
+        Some(symbols)
+    }
+
+    pub fn parse(contents: &str) -> Self {
+        let mut symbols = SymbolTable::default();
+        for line in contents.lines() {
+            let line = match line.find(';') {
+                Some(comment_start) => &line[..comment_start],
+                None => line,
+            };
+            let mut parts = line.split_whitespace();
+            let location = match parts.next() {
+                Some(location) => location,
+                None => continue,
+            };
+            let name = match parts.next() {
+                Some(name) => name,
+                None => continue,
+            };
+            if let Some(location) = parse_banked_address(location) {
+                symbols.insert(location, name);
+            }
+        }
+        symbols
+    }
+
+    fn insert(&mut self, location: BankedAddress, name: &str) {
+        // Keep the first label at an address, RGBDS lists the enclosing global label first.
+        self.by_address
+            .entry(location)
+            .or_insert_with(|| name.to_string());
+        self.by_name.insert(name.to_string(), location);
+    }
+
+    pub fn resolve(&self, name: &str) -> Option<BankedAddress> {
+        self.by_name.get(name).copied()
+    }
+
+    // Finds the closest label at or before the address, within the same bank and memory region.
+    pub fn lookup(&self, location: BankedAddress) -> Option<(&str, u16)> {
+        let region_start = BankedAddress {
+            bank: location.bank,
+            address: region_start(location.address),
+        };
+        self.by_address
+            .range(region_start..=location)
+            .next_back()
+            .map(|(label_location, name)| {
+                (name.as_str(), location.address - label_location.address)
+            })
+    }
+
+    pub fn describe(&self, location: BankedAddress) -> String {
+        match self.lookup(location) {
+            Some((name, 0)) => format!("{} ({})", name, location),
+            Some((name, offset)) => format!("{}+${:X} ({})", name, offset, location),
+            None => location.to_string(),
+        }
+    }
+}
+
+fn region_start(address: u16) -> u16 {
+    match address {
+        0x0000..=0x3FFF => 0x0000,
+        0x4000..=0x7FFF => 0x4000,
+        0x8000..=0x9FFF => 0x8000,
+        0xA000..=0xBFFF => 0xA000,
+        0xC000..=0xCFFF => 0xC000,
+        0xD000..=0xDFFF => 0xD000,
+        0xE000..=0xFDFF => 0xE000,
+        0xFE00..=0xFE9F => 0xFE00,
+        0xFEA0..=0xFF7F => 0xFEA0,
+        0xFF80..=0xFFFF => 0xFF80,
+    }
+}
+
+fn parse_banked_address(text: &str) -> Option<BankedAddress> {
+    let mut parts = text.splitn(2, ':');
+    let bank = u16::from_str_radix(parts.next()?, 16).ok()?;
+    let address = u16::from_str_radix(parts.next()?, 16).ok()?;
+    Some(BankedAddress { bank, address })
+}
+
+// Parses a breakpoint location: a label, a banked address (01:4000) or a plain address, written
+// either as $0150 or 0x0150.
+pub fn parse_location(text: &str, symbols: Option<&SymbolTable>) -> Result<BankedAddress, String> {
+    let text = text.trim();
+    if let Some(location) = symbols.and_then(|symbols| symbols.resolve(text)) {
+        return Ok(location);
+    }
+    if let Some(location) = parse_banked_address(text) {
+        return Ok(location);
+    }
+    let digits = text
+        .strip_prefix('$')
+        .or_else(|| text.strip_prefix("0x"))
+        .unwrap_or(text);
+    match u16::from_str_radix(digits, 16) {
+        Ok(address) => Ok(BankedAddress {
+            bank: default_bank(address),
+            address,
+        }),
+        Err(_) => Err(format!("Unknown symbol or address: {}", text)),
+    }
+}
+
+// The bank mapped at power on. Without a memory bank controller only bank 1 can be mapped at
+// 0x4000-0x7FFF.
+fn default_bank(address: u16) -> u16 {
+    match address {
+        0x4000..=0x7FFF | 0xD000..=0xDFFF => 1,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYMBOLS: &str = "; File generated by rgblink\n\
+                           00:0150 Main\n\
+                           00:0150 Main.loop\n\
+                           01:4000 Routine ; comment\n\
+                           00:ff80 hDMA\n";
+
+    #[test]
+    fn parses_banked_and_unbanked_entries() {
+        let symbols = SymbolTable::parse(SYMBOLS);
+        let main = BankedAddress {
+            bank: 0,
+            address: 0x0150,
+        };
+        let routine = BankedAddress {
+            bank: 1,
+            address: 0x4000,
+        };
+        assert_eq!(symbols.resolve("Main"), Some(main));
+        assert_eq!(symbols.resolve("Routine"), Some(routine));
+        assert_eq!(symbols.lookup(main), Some(("Main", 0)));
+        assert_eq!(
+            symbols.lookup(BankedAddress {
+                bank: 1,
+                address: 0x4010,
+            }),
+            Some(("Routine", 0x10))
+        );
+        assert_eq!(
+            symbols.lookup(BankedAddress {
+                bank: 2,
+                address: 0x4010,
+            }),
+            None
+        );
+        assert_eq!(symbols.describe(routine), "Routine (01:4000)");
+    }
+
+    #[test]
+    fn parses_locations() {
+        let symbols = SymbolTable::parse(SYMBOLS);
+        let location = |text| parse_location(text, Some(&symbols)).map(|l| l.to_string());
+        assert_eq!(location("Routine"), Ok("01:4000".to_string()));
+        assert_eq!(location("hDMA"), Ok("00:FF80".to_string()));
+        assert_eq!(location("02:4123"), Ok("02:4123".to_string()));
+        assert_eq!(location("$0150"), Ok("00:0150".to_string()));
+        assert_eq!(location("0x4000"), Ok("01:4000".to_string()));
+        assert_eq!(location("d000"), Ok("01:D000".to_string()));
+        assert!(location("Missing").is_err());
+        assert!(parse_location("Routine", None).is_err());
+    }
+}
@@ -0,0 +1,319 @@
+pub mod speed;
+
+use crate::apu::time_stretch::TimeStretch;
+use crate::cpu::CPU;
+use crate::debugger::symbols::BankedAddress;
+use crate::input::Joypads;
+use crate::snapshot::rewind::Rewind;
+use speed::Speed;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// At unlimited speed each buffer runs the emulation for this much of the time it lasts, leaving
+// the rest for the frontend.
+const UNLIMITED_TIME_SHARE: f64 = 0.75;
+
+// Sent from the frontend, and handled by the emulation thread before it fills the next buffer.
+pub enum Command {
+    // Takes effect at the start of the next frame.
+    SetJoypads(Joypads),
+    Pause,
+    Resume,
+    // Pauses if running and resumes if paused. The frontend's view of whether it is paused can be
+    // behind, so the emulation thread decides.
+    TogglePause,
+    // Pauses if running, then runs one more frame.
+    AdvanceFrame,
+    Reset,
+    PowerCycle,
+    SaveState,
+    LoadState(Vec<u8>),
+    ReadMemory { address: u16, length: u16 },
+    AddBreakpoint(BankedAddress),
+}
+
+// Sent from the emulation thread as things happen, for the frontend to pick up when it's ready.
+pub enum Event {
+    // The frame that has just started. Frames run within the same buffer only send the last.
+    FrameReady(u64),
+    // Another buffer of sound has been handed to the audio device.
+    AudioReady,
+    // Emulation pauses before running the instruction at the address.
+    BreakpointHit(BankedAddress),
+    // Emulation has paused or resumed.
+    Paused(bool),
+    StateSaved(Vec<u8>),
+    StateLoaded(Result<(), String>),
+    Memory { address: u16, bytes: Vec<u8> },
+}
+
+// Settings the frontend holds down or changes often, read before every buffer rather than sent
+// as commands. Pausing goes through commands, and Event::Paused reports when it has happened.
+pub struct PlaybackControls {
+    paused: AtomicBool,
+    // Runs at the turbo speed rather than the normal one while set.
+    pub fast_forward: AtomicBool,
+    pub rewinding: AtomicBool,
+    speed: AtomicU32,
+    turbo_speed: AtomicU32,
+}
+
+impl PlaybackControls {
+    pub fn new(speed: Speed, turbo_speed: Speed) -> Self {
+        PlaybackControls {
+            paused: AtomicBool::new(false),
+            fast_forward: AtomicBool::new(false),
+            rewinding: AtomicBool::new(false),
+            speed: AtomicU32::new(speed.to_bits()),
+            turbo_speed: AtomicU32::new(turbo_speed.to_bits()),
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    pub fn speed(&self) -> Speed {
+        Speed::from_bits(self.speed.load(Ordering::Relaxed))
+    }
+
+    pub fn set_speed(&self, speed: Speed) {
+        self.speed.store(speed.to_bits(), Ordering::Relaxed);
+    }
+
+    fn current_speed(&self) -> Speed {
+        match self.fast_forward.load(Ordering::Relaxed) {
+            true => Speed::from_bits(self.turbo_speed.load(Ordering::Relaxed)),
+            false => self.speed(),
+        }
+    }
+}
+
+// The emulation thread's side, which runs the CPU as the audio device asks for sound. The CPU
+// stays shared so that the debugger can take it over instead.
+pub struct Emulation {
+    cpu: Arc<Mutex<CPU>>,
+    controls: Arc<PlaybackControls>,
+    commands: Receiver<Command>,
+    events: Sender<Event>,
+    rewind: Option<Rewind>,
+    time_stretch: TimeStretch,
+    breakpoints: HashSet<BankedAddress>,
+    // Frames to run while paused.
+    frames_to_advance: u32,
+    sample_rate: u32,
+}
+
+impl Emulation {
+    pub fn new(
+        cpu: Arc<Mutex<CPU>>,
+        controls: Arc<PlaybackControls>,
+        rewind: Option<Rewind>,
+    ) -> (Self, Sender<Command>, Receiver<Event>) {
+        let (command_sender, commands) = mpsc::channel();
+        let (events, event_receiver) = mpsc::channel();
+        let emulation = Emulation {
+            cpu,
+            controls,
+            commands,
+            events,
+            rewind,
+            time_stretch: TimeStretch::default(),
+            breakpoints: HashSet::new(),
+            frames_to_advance: 0,
+            sample_rate: 0,
+        };
+        (emulation, command_sender, event_receiver)
+    }
+
+    pub fn initialize_audio(&mut self, sample_rate: u32, clock_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.cpu
+            .lock()
+            .unwrap()
+            .bus
+            .apu
+            .initialize_buffers(sample_rate, clock_rate);
+    }
+
+    // Fills the buffer with interleaved stereo samples, running the CPU for as long as it takes.
+    pub fn fill_buffer(&mut self, data: &mut [f32]) {
+        let cpu = Arc::clone(&self.cpu);
+        let mut cpu = cpu.lock().unwrap();
+        let frame = cpu.frame();
+        while let Ok(command) = self.commands.try_recv() {
+            self.handle_command(&mut cpu, command);
+        }
+
+        if self.controls.is_paused() {
+            // Frames advanced while paused are run whole and left silent.
+            if self.frames_to_advance > 0 {
+                self.frames_to_advance -= 1;
+                let cycles = self.run_frame(&mut cpu);
+                cpu.bus.apu.gather_samples();
+                if let Some(rewind) = &mut self.rewind {
+                    rewind.record(&cpu, cycles);
+                }
+            }
+            data.iter_mut().for_each(|sample| *sample = 0.0);
+        } else if self.controls.rewinding.load(Ordering::Relaxed) {
+            // Rewinding steps back one snapshot per buffer, with the sound muted.
+            if let Some(rewind) = &mut self.rewind {
+                rewind.step_back(&mut cpu);
+            }
+            data.iter_mut().for_each(|sample| *sample = 0.0);
+        } else {
+            let cycles = match self.controls.current_speed() {
+                Speed::Unlimited => self.run_unlimited(&mut cpu, data),
+                Speed::Multiplier(speed) => self.run_stretched(&mut cpu, data, speed),
+            };
+            if let Some(rewind) = &mut self.rewind {
+                rewind.record(&cpu, cycles);
+            }
+        }
+
+        if cpu.frame() != frame {
+            self.send(Event::FrameReady(cpu.frame()));
+        }
+        self.send(Event::AudioReady);
+    }
+
+    fn handle_command(&mut self, cpu: &mut CPU, command: Command) {
+        match command {
+            Command::SetJoypads(joypads) => cpu.bus.input.set_next_joypads(joypads),
+            Command::Pause => self.set_paused(true),
+            Command::Resume => {
+                self.set_paused(false);
+                self.frames_to_advance = 0;
+            }
+            Command::TogglePause => self.set_paused(!self.controls.is_paused()),
+            Command::AdvanceFrame => {
+                self.set_paused(true);
+                self.frames_to_advance += 1;
+            }
+            Command::Reset => {
+                cpu.reset();
+                self.time_stretch.clear();
+            }
+            Command::PowerCycle => {
+                cpu.power_cycle();
+                self.time_stretch.clear();
+            }
+            Command::SaveState => self.send(Event::StateSaved(cpu.snapshot())),
+            Command::LoadState(state) => {
+                let result = cpu.restore(&state);
+                if result.is_ok() {
+                    self.time_stretch.clear();
+                }
+                self.send(Event::StateLoaded(result));
+            }
+            Command::ReadMemory { address, length } => {
+                let bytes = (0..length)
+                    .map(|offset| cpu.bus.peek_byte(address.wrapping_add(offset)))
+                    .collect();
+                self.send(Event::Memory { address, bytes });
+            }
+            Command::AddBreakpoint(address) => {
+                self.breakpoints.insert(address);
+            }
+        }
+    }
+
+    // Runs buffers' worth of emulation until the time is up and plays only the last, as stretching
+    // the sound that much would make it unrecognisable.
+    fn run_unlimited(&mut self, cpu: &mut CPU, data: &mut [f32]) -> u32 {
+        let samples_needed = data.len() / 2;
+        let buffer_time = samples_needed as f64 / self.sample_rate as f64;
+        let deadline = Instant::now() + Duration::from_secs_f64(buffer_time * UNLIMITED_TIME_SHARE);
+        let mut cycles = 0;
+        let mut samples;
+        loop {
+            cycles += self.run_for_samples(cpu, samples_needed as u32);
+            samples = cpu.bus.apu.gather_samples();
+            if Instant::now() >= deadline || self.controls.is_paused() {
+                break;
+            }
+        }
+        self.time_stretch.clear();
+        let flattened_samples = samples.interleave();
+        let length = flattened_samples.len().min(data.len());
+        data[..length].copy_from_slice(&flattened_samples[..length]);
+        data[length..].iter_mut().for_each(|sample| *sample = 0.0);
+        cycles
+    }
+
+    fn run_stretched(&mut self, cpu: &mut CPU, data: &mut [f32], speed: f32) -> u32 {
+        let samples_needed = data.len() / 2;
+        let mut cycles = 0;
+        while self.time_stretch.output_available() < samples_needed {
+            let input_needed = self.time_stretch.input_needed();
+            if input_needed == 0 {
+                self.time_stretch.add_grain(speed as f64);
+                continue;
+            }
+            cycles += self.run_for_samples(cpu, input_needed as u32);
+            let samples = cpu.bus.apu.gather_samples();
+            if samples.length() == 0 || self.controls.is_paused() {
+                break;
+            }
+            self.time_stretch.push(samples);
+        }
+        self.time_stretch.take_output(data);
+        cycles
+    }
+
+    // Returns the cycles that were run, which stop short at a breakpoint.
+    fn run_for_samples(&mut self, cpu: &mut CPU, samples_needed: u32) -> u32 {
+        let cycles_to_run = cpu
+            .bus
+            .apu
+            .cycles_needed_to_generate_samples(samples_needed);
+        let mut cycles_ran = 0;
+        while cycles_ran < cycles_to_run {
+            cycles_ran += cpu.step_single_instruction() as u32;
+            if self.check_breakpoint(cpu) {
+                break;
+            }
+        }
+        cpu.end_frame();
+        cycles_ran
+    }
+
+    fn run_frame(&mut self, cpu: &mut CPU) -> u32 {
+        let frame = cpu.frame();
+        let mut cycles = 0;
+        while cpu.frame() == frame {
+            cycles += cpu.step_single_instruction() as u32;
+            if self.check_breakpoint(cpu) {
+                break;
+            }
+        }
+        cpu.end_frame();
+        cycles
+    }
+
+    fn check_breakpoint(&mut self, cpu: &CPU) -> bool {
+        let address = cpu.bus.banked_address(cpu.program_counter());
+        if !self.breakpoints.contains(&address) {
+            return false;
+        }
+        self.set_paused(true);
+        self.frames_to_advance = 0;
+        self.send(Event::BreakpointHit(address));
+        true
+    }
+
+    fn set_paused(&self, paused: bool) {
+        if self.controls.paused.swap(paused, Ordering::Relaxed) != paused {
+            self.send(Event::Paused(paused));
+        }
+    }
+
+    // The frontend may have gone away while the audio device is still asking for sound.
+    fn send(&self, event: Event) {
+        let _ = self.events.send(event);
+    }
+}
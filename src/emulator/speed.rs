@@ -0,0 +1,76 @@
+use std::fmt;
+use std::str::FromStr;
+
+const MIN_SPEED: f32 = 0.25;
+const MAX_SPEED: f32 = 8.0;
+// The speeds stepped through by the speed up and down hotkeys.
+const SPEED_STEPS: [f32; 8] = [0.25, 0.5, 0.75, 1.0, 1.5, 2.0, 4.0, 8.0];
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Speed {
+    Multiplier(f32),
+    // As fast as the host can run, playing only the sound of the latest buffer.
+    Unlimited,
+}
+
+impl FromStr for Speed {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "unlimited" {
+            return Ok(Speed::Unlimited);
+        }
+        match s.trim_end_matches('x').parse::<f32>() {
+            Ok(speed) if (MIN_SPEED..=MAX_SPEED).contains(&speed) => Ok(Speed::Multiplier(speed)),
+            _ => Err(format!(
+                "Speed must be from {}x to {}x, or unlimited",
+                MIN_SPEED, MAX_SPEED
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Speed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Speed::Multiplier(speed) => write!(f, "{}x", speed),
+            Speed::Unlimited => write!(f, "unlimited"),
+        }
+    }
+}
+
+impl Speed {
+    pub fn faster(self) -> Speed {
+        match self {
+            Speed::Multiplier(speed) => SPEED_STEPS
+                .iter()
+                .find(|&&step| step > speed)
+                .map_or(Speed::Unlimited, |&step| Speed::Multiplier(step)),
+            Speed::Unlimited => Speed::Unlimited,
+        }
+    }
+
+    pub fn slower(self) -> Speed {
+        let speed = match self {
+            Speed::Multiplier(speed) => speed,
+            Speed::Unlimited => f32::INFINITY,
+        };
+        let step = SPEED_STEPS.iter().rev().find(|&&step| step < speed);
+        Speed::Multiplier(*step.unwrap_or(&MIN_SPEED))
+    }
+
+    // Unlimited is stored as zero, which isn't a valid multiplier.
+    pub fn to_bits(self) -> u32 {
+        match self {
+            Speed::Multiplier(speed) => speed.to_bits(),
+            Speed::Unlimited => 0,
+        }
+    }
+
+    pub fn from_bits(bits: u32) -> Speed {
+        match bits {
+            0 => Speed::Unlimited,
+            bits => Speed::Multiplier(f32::from_bits(bits)),
+        }
+    }
+}
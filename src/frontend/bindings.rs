@@ -0,0 +1,369 @@
+use crate::input::{JoypadInput, Joypads};
+use minifb::Key;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Button {
+    A,
+    B,
+    Select,
+    Start,
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl FromStr for Button {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "a" => Ok(Button::A),
+            "b" => Ok(Button::B),
+            "select" => Ok(Button::Select),
+            "start" => Ok(Button::Start),
+            "up" => Ok(Button::Up),
+            "down" => Ok(Button::Down),
+            "left" => Ok(Button::Left),
+            "right" => Ok(Button::Right),
+            _ => Err(format!("Unknown button {}", s)),
+        }
+    }
+}
+
+impl Button {
+    fn press(self, joypad: &mut JoypadInput) {
+        match self {
+            Button::A => joypad.a = true,
+            Button::B => joypad.b = true,
+            Button::Select => joypad.select = true,
+            Button::Start => joypad.start = true,
+            Button::Up => joypad.up = true,
+            Button::Down => joypad.down = true,
+            Button::Left => joypad.left = true,
+            Button::Right => joypad.right = true,
+        }
+    }
+}
+
+// Emulator actions, which are handled by the frontend and never reach the game.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Hotkey {
+    Pause,
+    // Runs one frame and stays paused.
+    FrameAdvance,
+    // Reset keeps the RAM, while power cycling starts again from nothing.
+    Reset,
+    PowerCycle,
+    // Fast-forward and rewind are held rather than toggled.
+    FastForward,
+    Rewind,
+    SpeedUp,
+    SpeedDown,
+    SaveState,
+    LoadState,
+    Screenshot,
+    CyclePalette,
+}
+
+impl FromStr for Hotkey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pause" => Ok(Hotkey::Pause),
+            "frame_advance" => Ok(Hotkey::FrameAdvance),
+            "reset" => Ok(Hotkey::Reset),
+            "power_cycle" => Ok(Hotkey::PowerCycle),
+            "fast_forward" => Ok(Hotkey::FastForward),
+            "rewind" => Ok(Hotkey::Rewind),
+            "speed_up" => Ok(Hotkey::SpeedUp),
+            "speed_down" => Ok(Hotkey::SpeedDown),
+            "save_state" => Ok(Hotkey::SaveState),
+            "load_state" => Ok(Hotkey::LoadState),
+            "screenshot" => Ok(Hotkey::Screenshot),
+            "cycle_palette" => Ok(Hotkey::CyclePalette),
+            _ => Err(format!("Unknown hotkey {}", s)),
+        }
+    }
+}
+
+const DEFAULT_BUTTONS: [(Key, Button); 8] = [
+    (Key::X, Button::A),
+    (Key::Z, Button::B),
+    (Key::Space, Button::Select),
+    (Key::Enter, Button::Start),
+    (Key::Up, Button::Up),
+    (Key::Down, Button::Down),
+    (Key::Left, Button::Left),
+    (Key::Right, Button::Right),
+];
+
+const DEFAULT_HOTKEYS: [(Key, Hotkey); 12] = [
+    (Key::Escape, Hotkey::Pause),
+    (Key::F, Hotkey::FrameAdvance),
+    (Key::R, Hotkey::Reset),
+    (Key::F1, Hotkey::PowerCycle),
+    (Key::Tab, Hotkey::FastForward),
+    (Key::Backspace, Hotkey::Rewind),
+    (Key::Equal, Hotkey::SpeedUp),
+    (Key::Minus, Hotkey::SpeedDown),
+    (Key::F5, Hotkey::SaveState),
+    (Key::F9, Hotkey::LoadState),
+    (Key::F12, Hotkey::Screenshot),
+    (Key::P, Hotkey::CyclePalette),
+];
+
+pub struct KeyBindings {
+    // The joypad port each key is for, as well as its button.
+    buttons: Vec<(Key, usize, Button)>,
+    hotkeys: Vec<(Key, Hotkey)>,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        KeyBindings {
+            buttons: DEFAULT_BUTTONS
+                .iter()
+                .map(|&(key, button)| (key, 0, button))
+                .collect(),
+            hotkeys: DEFAULT_HOTKEYS.to_vec(),
+        }
+    }
+}
+
+// A bindings file lists the keys for each button or hotkey, for example:
+//
+// [buttons]
+// a = ["X", "K"]
+// b = ["Z", "J"]
+//
+// [buttons.p2]
+// a = ["Key2"]
+// start = ["Key1"]
+//
+// [hotkeys]
+// fast_forward = ["Tab", "Backquote"]
+//
+// The buttons table is for the first joypad, with p2 to p4 for the others on an SGB multitap. Key
+// names are those of minifb's Key, ignoring case. Anything not listed keeps its default keys.
+#[derive(Deserialize)]
+struct BindingsFile {
+    #[serde(default)]
+    buttons: ButtonsTable,
+    #[serde(default)]
+    hotkeys: HashMap<String, Vec<String>>,
+}
+
+#[derive(Deserialize, Default)]
+struct ButtonsTable {
+    #[serde(default)]
+    p2: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    p3: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    p4: HashMap<String, Vec<String>>,
+    #[serde(flatten)]
+    p1: HashMap<String, Vec<String>>,
+}
+
+impl KeyBindings {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|err| format!("Could not read {}: {}", path.display(), err))?;
+        Self::parse(&contents)
+            .map_err(|err| format!("Invalid key bindings {}: {}", path.display(), err))
+    }
+
+    fn parse(contents: &str) -> Result<Self, String> {
+        let file: BindingsFile = toml::from_str(contents).map_err(|err| err.to_string())?;
+        let mut bindings = KeyBindings::default();
+        let ports = [
+            &file.buttons.p1,
+            &file.buttons.p2,
+            &file.buttons.p3,
+            &file.buttons.p4,
+        ];
+        for (port, buttons) in ports.iter().enumerate() {
+            for (name, keys) in buttons.iter() {
+                let button: Button = name.parse()?;
+                bindings
+                    .buttons
+                    .retain(|&(_, bound_port, bound)| bound_port != port || bound != button);
+                for key in keys {
+                    bindings.buttons.push((parse_key(key)?, port, button));
+                }
+            }
+        }
+        for (name, keys) in &file.hotkeys {
+            let hotkey: Hotkey = name.parse()?;
+            bindings.hotkeys.retain(|&(_, bound)| bound != hotkey);
+            for key in keys {
+                bindings.hotkeys.push((parse_key(key)?, hotkey));
+            }
+        }
+
+        // A key can't be both a button and a hotkey, or the hotkey would leak into the game.
+        for &(key, hotkey) in &bindings.hotkeys {
+            if let Some((_, _, button)) =
+                bindings.buttons.iter().find(|(bound, _, _)| *bound == key)
+            {
+                return Err(format!(
+                    "{:?} is bound to both {:?} and {:?}",
+                    key, button, hotkey
+                ));
+            }
+            if bindings
+                .hotkeys
+                .iter()
+                .any(|&(bound, other)| bound == key && other != hotkey)
+            {
+                return Err(format!("{:?} is bound to more than one hotkey", key));
+            }
+        }
+        Ok(bindings)
+    }
+
+    pub fn joypads(&self, keys: &[Key]) -> Joypads {
+        let mut joypads = Joypads::default();
+        for &(key, port, button) in &self.buttons {
+            if keys.contains(&key) {
+                button.press(&mut joypads[port]);
+            }
+        }
+        joypads
+    }
+
+    pub fn hotkey(&self, key: Key) -> Option<Hotkey> {
+        self.hotkeys
+            .iter()
+            .find(|&&(bound, _)| bound == key)
+            .map(|&(_, hotkey)| hotkey)
+    }
+
+    pub fn is_held(&self, hotkey: Hotkey, keys: &[Key]) -> bool {
+        self.hotkeys
+            .iter()
+            .any(|&(key, bound)| bound == hotkey && keys.contains(&key))
+    }
+}
+
+const KEYS: [Key; 106] = [
+    Key::Key0,
+    Key::Key1,
+    Key::Key2,
+    Key::Key3,
+    Key::Key4,
+    Key::Key5,
+    Key::Key6,
+    Key::Key7,
+    Key::Key8,
+    Key::Key9,
+    Key::A,
+    Key::B,
+    Key::C,
+    Key::D,
+    Key::E,
+    Key::F,
+    Key::G,
+    Key::H,
+    Key::I,
+    Key::J,
+    Key::K,
+    Key::L,
+    Key::M,
+    Key::N,
+    Key::O,
+    Key::P,
+    Key::Q,
+    Key::R,
+    Key::S,
+    Key::T,
+    Key::U,
+    Key::V,
+    Key::W,
+    Key::X,
+    Key::Y,
+    Key::Z,
+    Key::F1,
+    Key::F2,
+    Key::F3,
+    Key::F4,
+    Key::F5,
+    Key::F6,
+    Key::F7,
+    Key::F8,
+    Key::F9,
+    Key::F10,
+    Key::F11,
+    Key::F12,
+    Key::F13,
+    Key::F14,
+    Key::F15,
+    Key::Down,
+    Key::Left,
+    Key::Right,
+    Key::Up,
+    Key::Apostrophe,
+    Key::Backquote,
+    Key::Backslash,
+    Key::Comma,
+    Key::Equal,
+    Key::LeftBracket,
+    Key::Minus,
+    Key::Period,
+    Key::RightBracket,
+    Key::Semicolon,
+    Key::Slash,
+    Key::Backspace,
+    Key::Delete,
+    Key::End,
+    Key::Enter,
+    Key::Escape,
+    Key::Home,
+    Key::Insert,
+    Key::Menu,
+    Key::PageDown,
+    Key::PageUp,
+    Key::Pause,
+    Key::Space,
+    Key::Tab,
+    Key::NumLock,
+    Key::CapsLock,
+    Key::ScrollLock,
+    Key::LeftShift,
+    Key::RightShift,
+    Key::LeftCtrl,
+    Key::RightCtrl,
+    Key::NumPad0,
+    Key::NumPad1,
+    Key::NumPad2,
+    Key::NumPad3,
+    Key::NumPad4,
+    Key::NumPad5,
+    Key::NumPad6,
+    Key::NumPad7,
+    Key::NumPad8,
+    Key::NumPad9,
+    Key::NumPadDot,
+    Key::NumPadSlash,
+    Key::NumPadAsterisk,
+    Key::NumPadMinus,
+    Key::NumPadPlus,
+    Key::NumPadEnter,
+    Key::LeftAlt,
+    Key::RightAlt,
+    Key::LeftSuper,
+    Key::RightSuper,
+];
+
+fn parse_key(name: &str) -> Result<Key, String> {
+    KEYS.iter()
+        .copied()
+        .find(|key| format!("{:?}", key).eq_ignore_ascii_case(name))
+        .ok_or_else(|| format!("Unknown key {}", name))
+}
@@ -0,0 +1,34 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Saves the displayed frame as a PNG in the current directory, returning its path.
+pub fn save(framebuffer: &[u32], width: usize, height: usize) -> Result<PathBuf, String> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis())
+        .unwrap_or(0);
+    let path = PathBuf::from(format!("screenshot-{}.png", timestamp));
+    save_png(&path, framebuffer, width, height)
+        .map_err(|err| format!("Could not save screenshot to {}: {}", path.display(), err))?;
+    Ok(path)
+}
+
+fn save_png(
+    path: &Path,
+    framebuffer: &[u32],
+    width: usize,
+    height: usize,
+) -> Result<(), png::EncodingError> {
+    let file = File::create(path)?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width as u32, height as u32);
+    encoder.set_color(png::ColorType::RGB);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    let pixels: Vec<u8> = framebuffer
+        .iter()
+        .flat_map(|colour| colour.to_be_bytes()[1..].to_vec())
+        .collect();
+    writer.write_image_data(&pixels)
+}
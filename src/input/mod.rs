@@ -1,6 +1,12 @@
+mod sgb_packets;
+
 use crate::cpu::interrupts::{Interrupt, InterruptsToSet};
+use crate::model::Model;
+use crate::snapshot::{Snapshot, StateReader, StateWriter};
+use sgb_packets::PacketReceiver;
+use std::cell::Cell;
+use std::collections::VecDeque;
 use std::ops::Not;
-use std::sync::{Arc, Mutex};
 
 #[derive(Copy, Clone, Eq, PartialEq)]
 pub struct JoypadInput {
@@ -29,21 +35,80 @@ impl Default for JoypadInput {
     }
 }
 
+// One bit per button, set while pressed, in the order P1 reports them: A, B, Select and Start,
+// then Right, Left, Up and Down.
+impl From<&JoypadInput> for u8 {
+    fn from(joypad: &JoypadInput) -> u8 {
+        (joypad.a as u8)
+            | (joypad.b as u8) << 1
+            | (joypad.select as u8) << 2
+            | (joypad.start as u8) << 3
+            | (joypad.right as u8) << 4
+            | (joypad.left as u8) << 5
+            | (joypad.up as u8) << 6
+            | (joypad.down as u8) << 7
+    }
+}
+
+impl From<u8> for JoypadInput {
+    fn from(value: u8) -> Self {
+        JoypadInput {
+            a: (value & 1) != 0,
+            b: (value & (1 << 1)) != 0,
+            select: (value & (1 << 2)) != 0,
+            start: (value & (1 << 3)) != 0,
+            right: (value & (1 << 4)) != 0,
+            left: (value & (1 << 5)) != 0,
+            up: (value & (1 << 6)) != 0,
+            down: (value & (1 << 7)) != 0,
+        }
+    }
+}
+
+// The SGB can take up to four joypads through a multitap. Only the first is used otherwise.
+pub const JOYPAD_PORTS: usize = 4;
+
+pub type Joypads = [JoypadInput; JOYPAD_PORTS];
+
+const MLT_REQ: u8 = 0x11;
+
 pub struct InputState {
     pub select_buttons: bool,
     pub select_directions: bool,
-    pub current_joypad: JoypadInput,
-    pub next_joypad: Arc<Mutex<JoypadInput>>,
+    pub current_joypads: Joypads,
+    next_joypads: Joypads,
+    sgb_packets: Option<PacketReceiver>,
+    sgb_commands: VecDeque<Vec<u8>>,
+    players: usize,
+    player: usize,
+    // Whether the game has read the joypad since the last frame, to find lag frames.
+    polled: Cell<bool>,
 }
 
 impl InputState {
-    pub fn swap_to_next_joypad_state(&mut self) -> InterruptsToSet {
-        let next_joypad_state_mutex = self.next_joypad.lock().unwrap();
-        let next_joypad_state = *next_joypad_state_mutex;
-        drop(next_joypad_state_mutex);
+    pub fn new(model: Model) -> Self {
+        let sgb_packets = match model {
+            Model::SGB => Some(PacketReceiver::default()),
+            _ => None,
+        };
+        InputState {
+            sgb_packets,
+            ..Default::default()
+        }
+    }
 
-        let fire_interrupt = self.current_joypad != next_joypad_state;
-        self.current_joypad = next_joypad_state;
+    // The joypads the frontend has set, which take effect at the start of the next frame.
+    pub fn next_joypads(&self) -> Joypads {
+        self.next_joypads
+    }
+
+    pub fn set_next_joypads(&mut self, joypads: Joypads) {
+        self.next_joypads = joypads;
+    }
+
+    pub fn swap_to_next_joypad_state(&mut self, next_joypads: Joypads) -> InterruptsToSet {
+        let fire_interrupt = self.current_joypads != next_joypads;
+        self.current_joypads = next_joypads;
 
         let mut interrupts = InterruptsToSet::default();
         if fire_interrupt {
@@ -52,51 +117,96 @@ impl InputState {
         interrupts
     }
 
-    pub fn step(&mut self) -> InterruptsToSet {
-        self.swap_to_next_joypad_state()
+    pub fn take_polled(&self) -> bool {
+        self.polled.replace(false)
     }
 
     pub fn supports_io_register(&self, address: usize) -> bool {
         address == 0xFF00
     }
 
+    // The joypad the game sees, which only changes when SGB multiplayer is on.
+    pub fn current_joypad(&self) -> &JoypadInput {
+        &self.current_joypads[self.player]
+    }
+
+    // Called when the program reads the joypad register, rather than the debugger.
+    pub fn on_read(&self) {
+        if self.select_buttons || self.select_directions {
+            self.polled.set(true);
+        }
+    }
+
     pub fn read_io_register(&self, _address: usize) -> u8 {
+        let joypad = self.current_joypad();
         let mut value = 0x00_u8;
         if self.select_buttons {
             value = value | (1 << 5);
-            if self.current_joypad.start {
+            if joypad.start {
                 value = value | (1 << 3);
             }
-            if self.current_joypad.select {
+            if joypad.select {
                 value = value | (1 << 2);
             }
-            if self.current_joypad.b {
+            if joypad.b {
                 value = value | (1 << 1);
             }
-            if self.current_joypad.a {
+            if joypad.a {
                 value = value | (1 << 0);
             }
         } else if self.select_directions {
             value = value & !(1 << 4);
-            if self.current_joypad.down {
+            if joypad.down {
                 value = value | (1 << 3);
             }
-            if self.current_joypad.up {
+            if joypad.up {
                 value = value | (1 << 2);
             }
-            if self.current_joypad.left {
+            if joypad.left {
                 value = value | (1 << 1);
             }
-            if self.current_joypad.right {
+            if joypad.right {
                 value = value | (1 << 0);
             }
+        } else {
+            // With neither selected the SGB reports the current joypad, from 0xF for the first
+            // down to 0xC for the fourth.
+            value = self.player as u8;
         }
         value.not()
     }
 
     pub fn write_io_register(&mut self, value: u8, _address: usize) {
+        let buttons_released = self.select_buttons && (value & (1 << 5)) != 0;
         self.select_buttons = (value.not() & (1 << 5)) != 0;
         self.select_directions = (value.not() & (1 << 4)) != 0;
+        if let Some(sgb_packets) = &mut self.sgb_packets {
+            // The SGB moves on to the next joypad when P15 goes high, except while it is being
+            // pulsed to send a packet.
+            if buttons_released && !sgb_packets.is_receiving() {
+                self.player = (self.player + 1) % self.players;
+            }
+            match sgb_packets.write(value) {
+                Some(command) if command[0] >> 3 == MLT_REQ => self.request_players(command[1]),
+                Some(command) => self.sgb_commands.push_back(command),
+                None => {}
+            }
+        }
+    }
+
+    // MLT_REQ asks for one, two or four joypads and starts again from the first.
+    fn request_players(&mut self, request: u8) {
+        self.players = match request & 0b11 {
+            1 => 2,
+            3 => 4,
+            _ => 1,
+        };
+        self.player = 0;
+    }
+
+    // The next complete command sent to the Super Game Boy, with all of its packets.
+    pub fn take_sgb_command(&mut self) -> Option<Vec<u8>> {
+        self.sgb_commands.pop_front()
     }
 }
 
@@ -105,8 +215,43 @@ impl Default for InputState {
         Self {
             select_buttons: false,
             select_directions: false,
-            current_joypad: Default::default(),
-            next_joypad: Arc::new(Mutex::new(Default::default())),
+            current_joypads: Default::default(),
+            next_joypads: Default::default(),
+            sgb_packets: None,
+            sgb_commands: VecDeque::new(),
+            players: 1,
+            player: 0,
+            polled: Cell::new(false),
+        }
+    }
+}
+
+// The next joypad state is left alone, as it comes from the frontend. SGB commands are handled as
+// soon as they are received, so none are ever waiting.
+impl Snapshot for InputState {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.select_buttons);
+        state.bool(self.select_directions);
+        for joypad in self.current_joypads.iter() {
+            state.u8(u8::from(joypad));
+        }
+        if let Some(sgb_packets) = &self.sgb_packets {
+            sgb_packets.save_state(state);
+        }
+        state.usize(self.players);
+        state.usize(self.player);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) {
+        self.select_buttons = state.bool();
+        self.select_directions = state.bool();
+        for joypad in self.current_joypads.iter_mut() {
+            *joypad = JoypadInput::from(state.u8());
+        }
+        if let Some(sgb_packets) = &mut self.sgb_packets {
+            sgb_packets.load_state(state);
         }
+        self.players = state.usize().clamp(1, JOYPAD_PORTS);
+        self.player = state.usize() % self.players;
     }
 }
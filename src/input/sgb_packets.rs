@@ -0,0 +1,92 @@
+use crate::snapshot::{Snapshot, StateReader, StateWriter};
+
+const PACKET_SIZE: usize = 16;
+const PACKET_BITS: usize = PACKET_SIZE * 8;
+
+const P15_LOW: u8 = 0x10;
+const BOTH_HIGH: u8 = 0x30;
+
+// Decodes the packets a game sends to the Super Game Boy by pulsing P14 and P15. Pulling both low
+// starts a packet, then each of the 128 bits is a pulse on P14 for a 0 or P15 for a 1, least
+// significant bit first, with both lines released in between. A final 0 bit ends the packet.
+//
+// The low three bits of a command's first byte give how many packets it spans.
+#[derive(Default)]
+pub struct PacketReceiver {
+    receiving: bool,
+    released: bool,
+    packet: [u8; PACKET_SIZE],
+    bits_received: usize,
+    command: Vec<u8>,
+    packets_remaining: usize,
+}
+
+impl PacketReceiver {
+    // Returns the command once all of its packets have been received.
+    pub fn write(&mut self, value: u8) -> Option<Vec<u8>> {
+        match value & BOTH_HIGH {
+            0 => {
+                self.receiving = true;
+                self.released = false;
+                self.packet = [0; PACKET_SIZE];
+                self.bits_received = 0;
+            }
+            BOTH_HIGH => self.released = true,
+            lines if self.receiving && self.released => {
+                self.released = false;
+                return self.receive_bit(lines == P15_LOW);
+            }
+            _ => {}
+        }
+        None
+    }
+
+    pub fn is_receiving(&self) -> bool {
+        self.receiving
+    }
+
+    fn receive_bit(&mut self, bit: bool) -> Option<Vec<u8>> {
+        if self.bits_received < PACKET_BITS {
+            if bit {
+                self.packet[self.bits_received / 8] |= 1 << (self.bits_received % 8);
+            }
+            self.bits_received += 1;
+            return None;
+        }
+
+        self.receiving = false;
+        // The stop bit has to be a 0.
+        if bit {
+            return None;
+        }
+        if self.command.is_empty() {
+            self.packets_remaining = ((self.packet[0] & 0b111) as usize).max(1);
+        }
+        self.command.extend_from_slice(&self.packet);
+        self.packets_remaining -= 1;
+        match self.packets_remaining {
+            0 => Some(std::mem::take(&mut self.command)),
+            _ => None,
+        }
+    }
+}
+
+impl Snapshot for PacketReceiver {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.receiving);
+        state.bool(self.released);
+        state.bytes(&self.packet);
+        state.usize(self.bits_received);
+        state.vec(&self.command);
+        state.usize(self.packets_remaining);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) {
+        self.receiving = state.bool();
+        self.released = state.bool();
+        state.bytes(&mut self.packet);
+        self.bits_received = state.usize().min(PACKET_BITS);
+        self.command = state.vec();
+        self.packets_remaining = state.usize();
+    }
+}
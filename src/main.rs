@@ -1,87 +1,414 @@
 mod apu;
 mod cpu;
+mod debugger;
+mod emulator;
+mod frontend;
 mod input;
 mod memory;
+mod model;
+mod movie;
 mod ppu;
+mod serial;
+mod snapshot;
 mod utils;
 
+// The running emulator as the frontend sees it. The CPU runs on the audio thread, which picks up
+// commands between buffers and sends back events.
 struct DMG01 {
-    cpu: cpu::CPU,
+    cpu: Arc<Mutex<cpu::CPU>>,
+    controls: Arc<PlaybackControls>,
+    commands: Sender<Command>,
+    events: Receiver<Event>,
 }
 
+use memory::boot_rom::BootRom;
 use memory::cartridge::Cartridge;
+use model::Model;
 
 impl DMG01 {
-    fn new(cart: Option<Cartridge>) -> DMG01 {
-        DMG01 {
-            cpu: cpu::CPU::new(cart),
-        }
+    fn new(
+        cpu: cpu::CPU,
+        controls: PlaybackControls,
+        rewind: Option<Rewind>,
+    ) -> (DMG01, Emulation) {
+        let cpu = Arc::new(Mutex::new(cpu));
+        let controls = Arc::new(controls);
+        let (emulation, commands, events) =
+            Emulation::new(Arc::clone(&cpu), Arc::clone(&controls), rewind);
+        let gameboy = DMG01 {
+            cpu,
+            controls,
+            commands,
+            events,
+        };
+        (gameboy, emulation)
+    }
+
+    // Commands are dropped when nothing is running the emulation, as under the debugger.
+    fn send(&self, command: Command) {
+        let _ = self.commands.send(command);
+    }
+
+    fn pause(&self) {
+        self.send(Command::Pause);
+    }
+
+    fn resume(&self) {
+        self.send(Command::Resume);
+    }
+
+    fn toggle_pause(&self) {
+        self.send(Command::TogglePause);
+    }
+
+    fn advance_frame(&self) {
+        self.send(Command::AdvanceFrame);
+    }
+
+    // Runs the boot ROM again with the cartridge and RAM kept.
+    fn reset(&self) {
+        self.send(Command::Reset);
+    }
+
+    fn power_cycle(&self) {
+        self.send(Command::PowerCycle);
     }
 }
 
-use crate::input::JoypadInput;
-use minifb::Key;
-use std::sync::Arc;
+use crate::cpu::trace::{TraceCondition, Tracer};
+use crate::debugger::symbols::{parse_location, SymbolTable};
+use crate::emulator::speed::Speed;
+use crate::emulator::{Command, Emulation, Event, PlaybackControls};
+use crate::frontend::bindings::{Hotkey, KeyBindings};
+use crate::frontend::screenshot;
+use crate::input::Joypads;
+use crate::model::ModelSelection;
+use crate::movie::{Movie, MovieSession};
+use crate::ppu::colour_schemes::{self, ColourScheme};
+use crate::ppu::compat_palettes::Colourisation;
+use crate::serial::SerialConfig;
+use crate::snapshot::rewind::Rewind;
+use minifb::KeyRepeat;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex};
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
 struct Cli {
     #[structopt(parse(from_os_str), long)]
     rom: Option<std::path::PathBuf>,
+    #[structopt(parse(from_os_str), long)]
+    boot_rom: Option<std::path::PathBuf>,
+    #[structopt(long, conflicts_with = "boot-rom")]
+    skip_boot: bool,
+    #[structopt(parse(from_os_str), long)]
+    trace: Option<std::path::PathBuf>,
+    #[structopt(long, default_value = "boot")]
+    trace_start: TraceCondition,
+    #[structopt(long)]
+    trace_stop: Option<TraceCondition>,
+    #[structopt(long)]
+    trace_labels: bool,
+    #[structopt(long)]
+    gdb: Option<u16>,
+    #[structopt(long = "break")]
+    breakpoints: Vec<String>,
+    #[structopt(long, default_value = "disconnected")]
+    serial: SerialConfig,
+    // auto picks the model from the cartridge header.
+    #[structopt(long, default_value = "auto")]
+    model: ModelSelection,
+    #[structopt(long)]
+    colourise: Option<Colourisation>,
+    // Draws pixel by pixel during pixel transfer, for games that change registers mid-line.
+    #[structopt(long)]
+    pixel_fifo: bool,
+    // A preset (grey, green, pocket, light, high-contrast) or the path of a palette file.
+    #[structopt(long, default_value = "grey")]
+    palette: ColourScheme,
+    // A TOML file of key bindings for the buttons and hotkeys.
+    #[structopt(parse(from_os_str), long)]
+    bindings: Option<std::path::PathBuf>,
+    // A multiplier from 0.25 to 8, or unlimited.
+    #[structopt(long, default_value = "1")]
+    speed: Speed,
+    // The speed while the fast-forward hotkey is held.
+    #[structopt(long, default_value = "4")]
+    turbo_speed: Speed,
+    // How many frames apart rewind snapshots are taken.
+    #[structopt(long, default_value = "1")]
+    rewind_interval: u32,
+    // The memory rewind snapshots can use, in MiB. 0 turns rewinding off.
+    #[structopt(long, default_value = "32")]
+    rewind_budget: usize,
+    // Records the input from power on into a movie file.
+    #[structopt(parse(from_os_str), long, conflicts_with = "play-movie")]
+    record_movie: Option<std::path::PathBuf>,
+    // Our own movies, BizHawk .bk2 or VBA .vbm movies.
+    #[structopt(parse(from_os_str), long)]
+    play_movie: Option<std::path::PathBuf>,
+    // Plays the movie without a window or sound, exiting with an error if it desyncs.
+    #[structopt(long, requires = "play-movie")]
+    headless: bool,
+    // Pauses while the window isn't focused, and resumes when it is again.
+    #[structopt(long)]
+    pause_in_background: bool,
 }
 
 fn main() {
     let args = Cli::from_args();
 
     use std::fs;
+    let symbols = args
+        .rom
+        .as_ref()
+        .and_then(|rom_path| SymbolTable::load_for_rom(rom_path))
+        .map(Arc::new);
+    // Save states sit next to the ROM.
+    let state_path = args
+        .rom
+        .as_ref()
+        .map(|rom_path| rom_path.with_extension("state"));
     let cart = args.rom.map(|rom_path| Cartridge {
             rom: fs::read(rom_path).expect("Could not open rom file!"),
         });
 
+    let rom = cart
+        .as_ref()
+        .map(|cart| cart.rom.clone())
+        .unwrap_or_default();
+    let movie = args
+        .play_movie
+        .as_ref()
+        .map(|path| Movie::open(path, &rom).unwrap_or_else(|err| panic!("{}", err)));
+    // A movie plays back on the model it was recorded with.
+    let model = match &movie {
+        Some(movie) => movie.model,
+        None => args.model.resolve(cart.as_ref()),
+    };
+    let cgb_game = matches!(&cart, Some(cart) if cart.supports_cgb());
+    let boot_rom = match (&args.boot_rom, args.skip_boot) {
+        (_, true) => None,
+        (Some(path), false) => Some(BootRom::load(path).unwrap_or_else(|err| panic!("{}", err))),
+        (None, false) => BootRom::built_in(model),
+    };
+    let mut cpu = cpu::CPU::new(cart, model, boot_rom);
+    if args.pixel_fifo {
+        cpu.bus.ppu.use_pixel_fifo();
+    }
+    if let Some(movie) = movie {
+        if let Some(start_state) = movie.start_state() {
+            cpu.restore(start_state)
+                .unwrap_or_else(|err| panic!("{}", err));
+        }
+        cpu.attach_movie(MovieSession::play(movie));
+    } else if let Some(path) = args.record_movie {
+        let movie = Movie::start(&cpu, model, &rom);
+        cpu.attach_movie(MovieSession::record(movie, path));
+    }
+    if args.headless {
+        match movie::play_headless(&mut cpu) {
+            Ok(frames) => println!("Played {} frames in sync", frames),
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    let bindings = match &args.bindings {
+        Some(path) => KeyBindings::load(path).unwrap_or_else(|err| panic!("{}", err)),
+        None => KeyBindings::default(),
+    };
+
     use minifb::{Window, WindowOptions};
-    use ppu::{LCD_HEIGHT, LCD_WIDTH};
-    let mut window = match Window::new(
-        "DMG-01",
-        LCD_WIDTH as usize * 3,
-        LCD_HEIGHT as usize * 3,
-        WindowOptions::default(),
-    ) {
+    let (width, height) = cpu.bus.ppu.output_size();
+    let mut window = match Window::new("DMG-01", width * 3, height * 3, WindowOptions::default()) {
         Ok(win) => win,
         Err(_) => panic!("Could not create window!"),
     };
     window.limit_update_rate(Some(std::time::Duration::from_millis(16)));
 
-    let gameboy = DMG01::new(cart);
-    let displayable_framebuffer = Arc::clone(&gameboy.cpu.bus.ppu.displayable_framebuffer);
-    let joypad_buffer = Arc::clone(&gameboy.cpu.bus.input.next_joypad);
-    let _audio_player = apu::cpal_audio_output::CpalAudioLoop::new(gameboy.cpu).ok();
+    let mut colour_schemes = colour_schemes::PRESETS.to_vec();
+    let mut colour_scheme_index = match &args.palette {
+        ColourScheme::File(_) => {
+            colour_schemes.push(args.palette.clone());
+            colour_schemes.len() - 1
+        }
+        scheme => colour_schemes
+            .iter()
+            .position(|preset| std::mem::discriminant(preset) == std::mem::discriminant(scheme))
+            .unwrap(),
+    };
+    // Like the CGB boot ROM, colour models pick compatibility palettes for DMG games by default.
+    let colourisation = match (args.colourise, model.is_colour() && !cgb_game) {
+        (Some(colourisation), _) => Some(colourisation),
+        (None, true) => Some(Colourisation::Auto),
+        (None, false) => None,
+    };
+    match (model, colourisation) {
+        (Model::SGB, _) => {}
+        (_, Some(colourisation)) => cpu.bus.colourise(colourisation),
+        _ => {
+            let colours = args.palette.load().unwrap_or_else(|err| panic!("{}", err));
+            cpu.bus.ppu.set_dmg_colours(colours);
+        }
+    }
+    cpu.bus.serial.connect(args.serial.into_endpoint());
+    if let Some(trace_path) = args.trace {
+        let mut tracer = Tracer::new(&trace_path, args.trace_start, args.trace_stop)
+            .expect("Could not create trace file!");
+        if let (true, Some(symbols)) = (args.trace_labels, &symbols) {
+            tracer = tracer.with_symbols(Arc::clone(symbols));
+        }
+        cpu.attach_tracer(tracer);
+    }
+    let mut displayable_framebuffer = cpu.bus.ppu.take_displayable_framebuffer().unwrap();
+    let next_dmg_colours = Arc::clone(&cpu.bus.ppu.next_dmg_colours);
+    let rewind = match args.rewind_budget {
+        0 => None,
+        budget => Some(Rewind::new(args.rewind_interval, budget * 1024 * 1024)),
+    };
+    let (gameboy, emulation) = DMG01::new(
+        cpu,
+        PlaybackControls::new(args.speed, args.turbo_speed),
+        rewind,
+    );
+    let controls = &gameboy.controls;
+    let breakpoints: Vec<_> = args
+        .breakpoints
+        .iter()
+        .map(|location| {
+            parse_location(location, symbols.as_deref()).unwrap_or_else(|err| panic!("{}", err))
+        })
+        .collect();
+    let _audio_player = match args.gdb {
+        Some(port) => {
+            // The debugger runs the CPU itself, so commands go nowhere.
+            let cpu = Arc::clone(&gameboy.cpu);
+            std::thread::spawn(move || {
+                let mut cpu = cpu.lock().unwrap();
+                debugger::gdb::serve(&mut cpu, port, symbols, &breakpoints)
+                    .expect("GDB server failed!");
+            });
+            None
+        }
+        None => {
+            for breakpoint in &breakpoints {
+                gameboy.send(Command::AddBreakpoint(*breakpoint));
+            }
+            apu::cpal_audio_output::CpalAudioLoop::new(emulation).ok()
+        }
+    };
 
+    let mut joypads = Joypads::default();
+    let mut frame = 0;
+    let mut title = String::new();
+    let mut paused = false;
+    let mut paused_in_background = false;
     while window.is_open() {
-        let mut joypad_state = JoypadInput::default();
-        if let Some(keys) = window.get_keys() {
-            for key in keys {
-                match key {
-                    Key::Enter => joypad_state.start = true,
-                    Key::Space => joypad_state.select = true,
-                    Key::X => joypad_state.a = true,
-                    Key::Z => joypad_state.b = true,
-                    Key::Up => joypad_state.up = true,
-                    Key::Down => joypad_state.down = true,
-                    Key::Left => joypad_state.left = true,
-                    Key::Right => joypad_state.right = true,
-                    _ => {}
+        if args.pause_in_background {
+            let active = window.is_active();
+            if !active && !paused_in_background && !paused {
+                gameboy.pause();
+                paused_in_background = true;
+            } else if active && paused_in_background {
+                gameboy.resume();
+                paused_in_background = false;
+            }
+        }
+
+        let keys = window.get_keys().unwrap_or_default();
+        let pressed_buttons = bindings.joypads(&keys);
+        if pressed_buttons != joypads {
+            joypads = pressed_buttons;
+            gameboy.send(Command::SetJoypads(joypads));
+        }
+        controls.fast_forward.store(
+            bindings.is_held(Hotkey::FastForward, &keys),
+            Ordering::Relaxed,
+        );
+        controls
+            .rewinding
+            .store(bindings.is_held(Hotkey::Rewind, &keys), Ordering::Relaxed);
+
+        let framebuffer = displayable_framebuffer.read();
+        let pressed = window.get_keys_pressed(KeyRepeat::No).unwrap_or_default();
+        for hotkey in pressed.into_iter().filter_map(|key| bindings.hotkey(key)) {
+            match hotkey {
+                Hotkey::Pause => gameboy.toggle_pause(),
+                Hotkey::FrameAdvance => gameboy.advance_frame(),
+                Hotkey::Reset => gameboy.reset(),
+                Hotkey::PowerCycle => gameboy.power_cycle(),
+                Hotkey::SaveState => gameboy.send(Command::SaveState),
+                Hotkey::LoadState => match state_path.as_ref().map(fs::read) {
+                    Some(Ok(state)) => gameboy.send(Command::LoadState(state)),
+                    Some(Err(err)) => eprintln!("Could not read save state: {}", err),
+                    None => eprintln!("There's no ROM to load a save state for"),
+                },
+                Hotkey::Screenshot => match screenshot::save(framebuffer, width, height) {
+                    Ok(path) => eprintln!("Saved screenshot {}", path.display()),
+                    Err(err) => eprintln!("{}", err),
+                },
+                Hotkey::CyclePalette => {
+                    colour_scheme_index = (colour_scheme_index + 1) % colour_schemes.len();
+                    match colour_schemes[colour_scheme_index].load() {
+                        Ok(colours) => *next_dmg_colours.lock().unwrap() = Some(colours),
+                        Err(err) => eprintln!("{}", err),
+                    }
+                }
+                Hotkey::SpeedUp | Hotkey::SpeedDown => {
+                    let speed = match hotkey {
+                        Hotkey::SpeedUp => controls.speed().faster(),
+                        _ => controls.speed().slower(),
+                    };
+                    controls.set_speed(speed);
+                    eprintln!("Speed {}", speed);
                 }
+                Hotkey::FastForward | Hotkey::Rewind => {}
             }
         }
-        *joypad_buffer.lock().unwrap() = joypad_state;
 
-        let framebuffer = displayable_framebuffer.lock().unwrap().clone();
+        for event in gameboy.events.try_iter() {
+            match event {
+                Event::FrameReady(next_frame) => frame = next_frame,
+                Event::AudioReady => {}
+                Event::Paused(now_paused) => paused = now_paused,
+                Event::BreakpointHit(location) => {
+                    eprintln!("Breakpoint hit at {}", location);
+                    gameboy.send(Command::ReadMemory {
+                        address: location.address,
+                        length: 8,
+                    });
+                }
+                Event::Memory { address, bytes } => {
+                    let bytes: Vec<_> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+                    eprintln!("{:04X}: {}", address, bytes.join(" "));
+                }
+                Event::StateSaved(state) => match &state_path {
+                    Some(path) => match fs::write(path, state) {
+                        Ok(()) => eprintln!("Saved state to {}", path.display()),
+                        Err(err) => eprintln!("Could not write {}: {}", path.display(), err),
+                    },
+                    None => eprintln!("There's no ROM to save the state for"),
+                },
+                Event::StateLoaded(Ok(())) => eprintln!("Loaded state"),
+                Event::StateLoaded(Err(err)) => eprintln!("{}", err),
+            }
+        }
+
+        let next_title = match paused {
+            true => format!("DMG-01 (paused at frame {})", frame),
+            false => "DMG-01".to_string(),
+        };
+        if next_title != title {
+            window.set_title(&next_title);
+            title = next_title;
+        }
+
         window
-            .update_with_buffer(
-                framebuffer.as_slice(),
-                LCD_WIDTH as usize,
-                LCD_HEIGHT as usize,
-            )
+            .update_with_buffer(framebuffer, width, height)
             .unwrap();
     }
 }
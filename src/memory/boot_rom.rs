@@ -0,0 +1,169 @@
+use crate::model::Model;
+use std::path::Path;
+
+const BOOT_ROM_SIZE: usize = 0x100;
+// The CGB boot ROM is mapped at 0x0000-0x00FF and 0x0200-0x08FF, leaving the cartridge header
+// visible in between.
+const CGB_BOOT_ROM_SIZE: usize = 0x900;
+const CARTRIDGE_HEADER_BEGIN: usize = 0x100;
+const CARTRIDGE_HEADER_END: usize = 0x1FF;
+
+const DMG_BOOT_ROM: [u8; BOOT_ROM_SIZE] = [
+    0x31, 0xFE, 0xFF, 0xAF, 0x21, 0xFF, 0x9F, 0x32, 0xCB, 0x7C, 0x20, 0xFB, 0x21, 0x26, 0xFF, 0x0E,
+    0x11, 0x3E, 0x80, 0x32, 0xE2, 0x0C, 0x3E, 0xF3, 0xE2, 0x32, 0x3E, 0x77, 0x77, 0x3E, 0xFC, 0xE0,
+    0x47, 0x11, 0x04, 0x01, 0x21, 0x10, 0x80, 0x1A, 0xCD, 0x95, 0x00, 0xCD, 0x96, 0x00, 0x13, 0x7B,
+    0xFE, 0x34, 0x20, 0xF3, 0x11, 0xD8, 0x00, 0x06, 0x08, 0x1A, 0x13, 0x22, 0x23, 0x05, 0x20, 0xF9,
+    0x3E, 0x19, 0xEA, 0x10, 0x99, 0x21, 0x2F, 0x99, 0x0E, 0x0C, 0x3D, 0x28, 0x08, 0x32, 0x0D, 0x20,
+    0xF9, 0x2E, 0x0F, 0x18, 0xF3, 0x67, 0x3E, 0x64, 0x57, 0xE0, 0x42, 0x3E, 0x91, 0xE0, 0x40, 0x04,
+    0x1E, 0x02, 0x0E, 0x0C, 0xF0, 0x44, 0xFE, 0x90, 0x20, 0xFA, 0x0D, 0x20, 0xF7, 0x1D, 0x20, 0xF2,
+    0x0E, 0x13, 0x24, 0x7C, 0x1E, 0x83, 0xFE, 0x62, 0x28, 0x06, 0x1E, 0xC1, 0xFE, 0x64, 0x20, 0x06,
+    0x7B, 0xE2, 0x0C, 0x3E, 0x87, 0xE2, 0xF0, 0x42, 0x90, 0xE0, 0x42, 0x15, 0x20, 0xD2, 0x05, 0x20,
+    0x4F, 0x16, 0x20, 0x18, 0xCB, 0x4F, 0x06, 0x04, 0xC5, 0xCB, 0x11, 0x17, 0xC1, 0xCB, 0x11, 0x17,
+    0x05, 0x20, 0xF5, 0x22, 0x23, 0x22, 0x23, 0xC9, 0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B,
+    0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D, 0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E,
+    0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99, 0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC,
+    0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E, 0x3C, 0x42, 0xB9, 0xA5, 0xB9, 0xA5, 0x42, 0x3C,
+    0x21, 0x04, 0x01, 0x11, 0xA8, 0x00, 0x1A, 0x13, 0xBE, 0x20, 0xFE, 0x23, 0x7D, 0xFE, 0x34, 0x20,
+    0xF5, 0x06, 0x19, 0x78, 0x86, 0x23, 0x05, 0x20, 0xFB, 0x86, 0x20, 0xFE, 0x3E, 0x01, 0xE0, 0x50,
+];
+
+#[derive(Default)]
+pub struct BootRom {
+    data: Vec<u8>,
+}
+
+impl BootRom {
+    // Only the DMG boot ROM is built in. Games tell the models apart by the registers their boot
+    // ROMs leave behind, so the other models start from their post-boot state instead.
+    pub fn built_in(model: Model) -> Option<Self> {
+        match model {
+            Model::DMG => Some(BootRom {
+                data: DMG_BOOT_ROM.to_vec(),
+            }),
+            _ => None,
+        }
+    }
+
+    // Accepts the 256 byte DMG0, DMG, MGB and SGB boot ROMs, and the 2304 byte CGB boot ROM.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let data = std::fs::read(path)
+            .map_err(|err| format!("Could not read boot ROM {}: {}", path.display(), err))?;
+        match data.len() {
+            BOOT_ROM_SIZE | CGB_BOOT_ROM_SIZE => Ok(BootRom { data }),
+            size => Err(format!(
+                "Boot ROM {} is {} bytes, expected {} or {}",
+                path.display(),
+                size,
+                BOOT_ROM_SIZE,
+                CGB_BOOT_ROM_SIZE
+            )),
+        }
+    }
+
+    pub fn maps(&self, address: usize) -> bool {
+        address < self.data.len()
+            && !(CARTRIDGE_HEADER_BEGIN..=CARTRIDGE_HEADER_END).contains(&address)
+    }
+
+    pub fn read(&self, address: usize) -> u8 {
+        self.data[address]
+    }
+}
+
+// The IO registers each boot ROM leaves behind. DMA is left alone as writing it would start a
+// transfer, and the SGB's P1 as writing it would look like the start of an SGB packet.
+const POST_BOOT_IO: [(usize, u8); 37] = [
+    (0xFF00, 0xCF),
+    (0xFF01, 0x00),
+    (0xFF02, 0x7E),
+    (0xFF05, 0x00),
+    (0xFF06, 0x00),
+    (0xFF07, 0xF8),
+    (0xFF0F, 0xE1),
+    (0xFF10, 0x80),
+    (0xFF11, 0xBF),
+    (0xFF12, 0xF3),
+    (0xFF13, 0xFF),
+    (0xFF14, 0xBF),
+    (0xFF16, 0x3F),
+    (0xFF17, 0x00),
+    (0xFF18, 0xFF),
+    (0xFF19, 0xBF),
+    (0xFF1A, 0x7F),
+    (0xFF1B, 0xFF),
+    (0xFF1C, 0x9F),
+    (0xFF1D, 0xFF),
+    (0xFF1E, 0xBF),
+    (0xFF20, 0xFF),
+    (0xFF21, 0x00),
+    (0xFF22, 0x00),
+    (0xFF23, 0xBF),
+    (0xFF24, 0x77),
+    (0xFF25, 0xF3),
+    (0xFF26, 0xF1),
+    (0xFF40, 0x91),
+    (0xFF41, 0x85),
+    (0xFF42, 0x00),
+    (0xFF43, 0x00),
+    (0xFF45, 0x00),
+    (0xFF47, 0xFC),
+    (0xFF4A, 0x00),
+    (0xFF4B, 0x00),
+    (0xFFFF, 0x00),
+];
+
+const TRIGGER: u8 = 0x80;
+
+pub fn post_boot_io(model: Model) -> Vec<(usize, u8)> {
+    POST_BOOT_IO
+        .iter()
+        .filter(|(address, _)| !(model == Model::SGB && *address == 0xFF00))
+        .map(|&(address, value)| match (model, address) {
+            // Triggering the channels again would replay the end of the boot chime.
+            (_, 0xFF14) | (_, 0xFF19) | (_, 0xFF1E) | (_, 0xFF23) => (address, value & !TRIGGER),
+            (Model::SGB, 0xFF26) => (address, 0xF0),
+            (Model::CGB, 0xFF02) | (Model::AGB, 0xFF02) => (address, 0x7F),
+            _ => (address, value),
+        })
+        .collect()
+}
+
+// How far DIV has counted by the time the game starts. The SGB and CGB boot ROMs take a different
+// time depending on the cartridge, so they are left at 0.
+pub fn post_boot_divider(model: Model) -> u8 {
+    match model {
+        Model::DMG0 => 0x18,
+        Model::DMG | Model::MGB => 0xAB,
+        Model::SGB | Model::CGB | Model::AGB => 0x00,
+    }
+}
+
+const REGISTERED_MARK: [u8; 8] = [0x3C, 0x42, 0xB9, 0xA5, 0xB9, 0xA5, 0x42, 0x3C];
+
+// The boot ROM draws the header logo at twice its size, turning each nibble into two rows of a
+// tile with every pixel doubled, then adds the registered mark. Only the first bit plane is set.
+pub fn logo_tiles(logo: &[u8]) -> Vec<u8> {
+    let mut tiles = Vec::with_capacity(logo.len() * 8 + REGISTERED_MARK.len() * 2);
+    for &byte in logo {
+        for &nibble in [byte >> 4, byte & 0x0F].iter() {
+            let row = (0..4).fold(0, |row, bit| {
+                let pixel = (nibble >> (3 - bit)) & 1;
+                (row << 2) | (pixel * 0b11)
+            });
+            tiles.extend_from_slice(&[row, 0, row, 0]);
+        }
+    }
+    for &row in REGISTERED_MARK.iter() {
+        tiles.extend_from_slice(&[row, 0]);
+    }
+    tiles
+}
+
+// The logo takes up two rows of 12 tiles in the middle of the BG map, followed by the mark.
+pub fn logo_map() -> Vec<(usize, u8)> {
+    let top = (0x9904..=0x990F).zip(0x01..=0x0C);
+    let bottom = (0x9924..=0x992F).zip(0x0D..=0x18);
+    top.chain(bottom)
+        .chain(std::iter::once((0x9910, 0x19)))
+        .collect()
+}
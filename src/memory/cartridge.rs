@@ -1,3 +1,24 @@
 pub struct Cartridge {
     pub rom: Vec<u8>,
 }
+
+const CGB_FLAG_ADDRESS: usize = 0x0143;
+const SGB_FLAG_ADDRESS: usize = 0x0146;
+const OLD_LICENSEE_CODE_ADDRESS: usize = 0x014B;
+
+impl Cartridge {
+    // Both CGB enhanced (0x80) and CGB only (0xC0) carts set the top bit.
+    pub fn supports_cgb(&self) -> bool {
+        match self.rom.get(CGB_FLAG_ADDRESS) {
+            Some(flag) => (flag & 0x80) != 0,
+            None => false,
+        }
+    }
+
+    // Carts using SGB features set the SGB flag to 0x03, which the SGB only honours alongside an
+    // old licensee code of 0x33.
+    pub fn supports_sgb(&self) -> bool {
+        self.rom.get(SGB_FLAG_ADDRESS) == Some(&0x03)
+            && self.rom.get(OLD_LICENSEE_CODE_ADDRESS) == Some(&0x33)
+    }
+}
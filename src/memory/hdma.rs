@@ -0,0 +1,159 @@
+use crate::snapshot::{Snapshot, StateReader, StateWriter};
+
+const BLOCK_SIZE: u16 = 0x10;
+// Each block takes 8 microseconds, which is twice as many CPU cycles in double speed mode.
+const CYCLES_PER_BLOCK: u32 = 32;
+const STALL_CYCLES_PER_STEP: u32 = 4;
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum TransferMode {
+    Idle,
+    General,
+    HBlank,
+}
+
+// HDMA1-HDMA5 (0xFF51-0xFF55), which copy 16 byte blocks into VRAM. General purpose transfers copy
+// everything at once, H-Blank transfers copy one block each time the PPU enters H-Blank. The CPU
+// is stalled while a block is copied.
+pub struct Hdma {
+    source: u16,
+    destination: u16,
+    blocks_remaining: u8,
+    mode: TransferMode,
+    stall_cycles: u32,
+}
+
+pub struct HdmaBlock {
+    pub source: u16,
+    pub destination: u16,
+}
+
+impl Default for Hdma {
+    fn default() -> Self {
+        Self {
+            source: 0,
+            destination: 0x8000,
+            blocks_remaining: 0,
+            mode: TransferMode::Idle,
+            stall_cycles: 0,
+        }
+    }
+}
+
+impl Hdma {
+    pub fn next_general_block(&mut self, double_speed: bool) -> Option<HdmaBlock> {
+        match self.mode {
+            TransferMode::General => Some(self.next_block(double_speed)),
+            _ => None,
+        }
+    }
+
+    pub fn next_hblank_block(&mut self, double_speed: bool) -> Option<HdmaBlock> {
+        match self.mode {
+            TransferMode::HBlank => Some(self.next_block(double_speed)),
+            _ => None,
+        }
+    }
+
+    fn next_block(&mut self, double_speed: bool) -> HdmaBlock {
+        let block = HdmaBlock {
+            source: self.source,
+            destination: self.destination,
+        };
+        self.source = self.source.wrapping_add(BLOCK_SIZE);
+        self.destination = 0x8000 | (self.destination.wrapping_add(BLOCK_SIZE) & 0x1FF0);
+        self.blocks_remaining -= 1;
+        if self.blocks_remaining == 0 {
+            self.mode = TransferMode::Idle;
+        }
+        self.stall_cycles += if double_speed {
+            CYCLES_PER_BLOCK * 2
+        } else {
+            CYCLES_PER_BLOCK
+        };
+        block
+    }
+
+    // While a block is being copied the CPU does nothing, a few cycles at a time so the rest of the
+    // hardware keeps running.
+    pub fn take_stall_cycles(&mut self) -> Option<u8> {
+        if self.stall_cycles == 0 {
+            return None;
+        }
+        let cycles = self.stall_cycles.min(STALL_CYCLES_PER_STEP);
+        self.stall_cycles -= cycles;
+        Some(cycles as u8)
+    }
+
+    pub fn supports_io_register(&self, address: usize) -> bool {
+        matches!(address, 0xFF51..=0xFF55)
+    }
+
+    pub fn read_io_register(&self, address: usize) -> u8 {
+        match address {
+            0xFF51..=0xFF54 => 0xFF,
+            // Bit 7 is clear while an H-Blank transfer is running. Once finished this reads 0xFF,
+            // and after cancelling it holds the blocks that were left.
+            0xFF55 => {
+                let length = self.blocks_remaining.wrapping_sub(1) & 0x7F;
+                match self.mode {
+                    TransferMode::HBlank => length,
+                    _ => 0x80 | length,
+                }
+            }
+            _ => panic!("Reading from unknown HDMA register!"),
+        }
+    }
+
+    pub fn write_io_register(&mut self, value: u8, address: usize) {
+        match address {
+            0xFF51 => self.source = (self.source & 0x00FF) | ((value as u16) << 8),
+            0xFF52 => self.source = (self.source & 0xFF00) | (value & 0xF0) as u16,
+            0xFF53 => {
+                self.destination =
+                    0x8000 | (self.destination & 0x00FF) | ((value & 0x1F) as u16) << 8
+            }
+            0xFF54 => self.destination = (self.destination & 0xFF00) | (value & 0xF0) as u16,
+            0xFF55 => {
+                let hblank = (value & 0x80) != 0;
+                if self.mode == TransferMode::HBlank && !hblank {
+                    self.mode = TransferMode::Idle;
+                    return;
+                }
+                self.blocks_remaining = (value & 0x7F) + 1;
+                self.mode = if hblank {
+                    TransferMode::HBlank
+                } else {
+                    TransferMode::General
+                };
+            }
+            _ => panic!("Writing to unknown HDMA register!"),
+        }
+    }
+}
+
+impl Snapshot for Hdma {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u16(self.source);
+        state.u16(self.destination);
+        state.u8(self.blocks_remaining);
+        state.u8(match self.mode {
+            TransferMode::Idle => 0,
+            TransferMode::General => 1,
+            TransferMode::HBlank => 2,
+        });
+        state.u32(self.stall_cycles);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) {
+        self.source = state.u16();
+        self.destination = state.u16();
+        self.blocks_remaining = state.u8();
+        self.mode = match state.u8() {
+            1 => TransferMode::General,
+            2 => TransferMode::HBlank,
+            _ => TransferMode::Idle,
+        };
+        self.stall_cycles = state.u32();
+    }
+}
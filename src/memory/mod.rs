@@ -1,60 +1,80 @@
+pub mod boot_rom;
 pub mod cartridge;
+pub mod hdma;
 
 use crate::apu::APU;
+use crate::cpu::speed::SpeedSwitch;
 use crate::cpu::timers::Timers;
+use crate::debugger::symbols::BankedAddress;
+use crate::debugger::Watchpoints;
 use crate::input::InputState;
-use crate::ppu::PPU;
+use crate::model::Model;
+use crate::ppu::compat_palettes::{self, ButtonCombo, Colourisation};
+use crate::ppu::{OAM_SIZE, PPU};
+use crate::serial::Serial;
+use crate::snapshot::{Snapshot, StateReader, StateWriter};
+use boot_rom::BootRom;
 use cartridge::Cartridge;
+use hdma::{Hdma, HdmaBlock};
 
 pub struct MemoryBus {
+    model: Model,
+    cgb_mode: bool,
     memory: Vec<u8>,
-    boot_rom: [u8; BOOTROM_SIZE],
+    wram: Vec<u8>,
+    wram_bank: usize,
+    boot_rom: BootRom,
     cart_rom: Option<Cartridge>,
     finished_boot: bool,
+    colourise_from_buttons: bool,
     pub ppu: PPU,
     pub apu: APU,
     pub input: InputState,
     pub timer: Timers,
+    pub serial: Serial,
+    pub speed: SpeedSwitch,
+    pub hdma: Hdma,
+    pub watchpoints: Watchpoints,
 }
 
 impl MemoryBus {
-    pub fn new(cart: Option<Cartridge>) -> Self {
+    pub fn new(cart: Option<Cartridge>, model: Model, boot_rom: Option<BootRom>) -> Self {
         MemoryBus {
+            model,
+            cgb_mode: model.is_colour(),
             memory: vec![0; 0x10000],
-            boot_rom: [
-                0x31, 0xFE, 0xFF, 0xAF, 0x21, 0xFF, 0x9F, 0x32, 0xCB, 0x7C, 0x20, 0xFB, 0x21, 0x26,
-                0xFF, 0x0E, 0x11, 0x3E, 0x80, 0x32, 0xE2, 0x0C, 0x3E, 0xF3, 0xE2, 0x32, 0x3E, 0x77,
-                0x77, 0x3E, 0xFC, 0xE0, 0x47, 0x11, 0x04, 0x01, 0x21, 0x10, 0x80, 0x1A, 0xCD, 0x95,
-                0x00, 0xCD, 0x96, 0x00, 0x13, 0x7B, 0xFE, 0x34, 0x20, 0xF3, 0x11, 0xD8, 0x00, 0x06,
-                0x08, 0x1A, 0x13, 0x22, 0x23, 0x05, 0x20, 0xF9, 0x3E, 0x19, 0xEA, 0x10, 0x99, 0x21,
-                0x2F, 0x99, 0x0E, 0x0C, 0x3D, 0x28, 0x08, 0x32, 0x0D, 0x20, 0xF9, 0x2E, 0x0F, 0x18,
-                0xF3, 0x67, 0x3E, 0x64, 0x57, 0xE0, 0x42, 0x3E, 0x91, 0xE0, 0x40, 0x04, 0x1E, 0x02,
-                0x0E, 0x0C, 0xF0, 0x44, 0xFE, 0x90, 0x20, 0xFA, 0x0D, 0x20, 0xF7, 0x1D, 0x20, 0xF2,
-                0x0E, 0x13, 0x24, 0x7C, 0x1E, 0x83, 0xFE, 0x62, 0x28, 0x06, 0x1E, 0xC1, 0xFE, 0x64,
-                0x20, 0x06, 0x7B, 0xE2, 0x0C, 0x3E, 0x87, 0xE2, 0xF0, 0x42, 0x90, 0xE0, 0x42, 0x15,
-                0x20, 0xD2, 0x05, 0x20, 0x4F, 0x16, 0x20, 0x18, 0xCB, 0x4F, 0x06, 0x04, 0xC5, 0xCB,
-                0x11, 0x17, 0xC1, 0xCB, 0x11, 0x17, 0x05, 0x20, 0xF5, 0x22, 0x23, 0x22, 0x23, 0xC9,
-                0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C,
-                0x00, 0x0D, 0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6,
-                0xDD, 0xDD, 0xD9, 0x99, 0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC,
-                0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E, 0x3C, 0x42, 0xB9, 0xA5, 0xB9, 0xA5, 0x42, 0x3C,
-                0x21, 0x04, 0x01, 0x11, 0xA8, 0x00, 0x1A, 0x13, 0xBE, 0x20, 0xFE, 0x23, 0x7D, 0xFE,
-                0x34, 0x20, 0xF5, 0x06, 0x19, 0x78, 0x86, 0x23, 0x05, 0x20, 0xFB, 0x86, 0x20, 0xFE,
-                0x3E, 0x01, 0xE0, 0x50,
-            ],
+            wram: vec![0; WRAM_BANK_SIZE * WRAM_BANKS],
+            wram_bank: 1,
+            finished_boot: boot_rom.is_none(),
+            boot_rom: boot_rom.unwrap_or_default(),
             cart_rom: cart,
-            finished_boot: false,
-            ppu: PPU::new(),
-            apu: APU::new(),
-            input: Default::default(),
+            colourise_from_buttons: false,
+            ppu: PPU::new(model),
+            apu: APU::new(model),
+            input: InputState::new(model),
             timer: Default::default(),
+            serial: Default::default(),
+            speed: Default::default(),
+            hdma: Default::default(),
+            watchpoints: Default::default(),
         }
     }
 
+    // A read by the program, which watchpoints see and which counts as polling the joypad.
     pub fn read_byte(&self, address: u16) -> u8 {
+        self.watchpoints.on_read(address);
+        if self.input.supports_io_register(address as usize) {
+            self.input.on_read();
+        }
+        self.peek_byte(address)
+    }
+
+    // Reads without the side effects of the program reading, for instruction fetches, the
+    // debugger, the tracer and DMA.
+    pub fn peek_byte(&self, address: u16) -> u8 {
         let address = address as usize;
         match address {
-            BOOTROM_BEGIN..=BOOTROM_END if !self.finished_boot => self.boot_rom[address],
+            _ if !self.finished_boot && self.boot_rom.maps(address) => self.boot_rom.read(address),
             CARTRIDGE_ROM_BANK_0_START..=CARTRIDGE_ROM_BANK_0_END
             | CARTRIDGE_ROM_BANK_REST_START..=CARTRIDGE_ROM_BANK_REST_END => {
                 if self.cart_rom.as_ref().is_some()
@@ -66,22 +86,135 @@ impl MemoryBus {
                 }
             }
             VRAM_BEGIN..=VRAM_END => self.ppu.read_vram(address - VRAM_BEGIN),
+            WRAM_BEGIN..=ECHO_RAM_END => self.wram[self.wram_offset(address)],
+            OAM_BEGIN..=OAM_END => self.ppu.read_oam(address - OAM_BEGIN),
             IO_REGISTER_BEGIN..=IO_REGISTER_END => self.read_io_register(address),
             _ => self.memory[address],
         }
     }
 
+    pub fn colourise(&mut self, colourisation: Colourisation) {
+        match colourisation {
+            Colourisation::Auto => {
+                if let Some(cart) = &self.cart_rom {
+                    self.ppu
+                        .set_dmg_colours(compat_palettes::for_cartridge(cart));
+                }
+                self.colourise_from_buttons = true;
+            }
+            Colourisation::Combo(combo) => self.ppu.set_dmg_colours(combo.colours()),
+        }
+    }
+
+    // Sets up the IO registers and VRAM as the boot ROM leaves them, with the LCD on showing the
+    // logo from the cartridge header.
+    pub fn skip_boot(&mut self) {
+        self.finished_boot = true;
+        for &(address, value) in boot_rom::post_boot_io(self.model).iter() {
+            self.write_io_register(value, address);
+        }
+        self.timer
+            .set_divider(boot_rom::post_boot_divider(self.model));
+        let cgb_game = match &self.cart_rom {
+            Some(cart) => cart.supports_cgb(),
+            None => false,
+        };
+        if self.cgb_mode && !cgb_game {
+            self.enter_dmg_compatibility();
+        }
+
+        let logo: Vec<u8> = (LOGO_BEGIN..=LOGO_END)
+            .map(|address| self.peek_byte(address))
+            .collect();
+        for (offset, value) in boot_rom::logo_tiles(&logo).into_iter().enumerate() {
+            self.ppu
+                .write_vram(value, LOGO_TILES_BEGIN - VRAM_BEGIN + offset);
+        }
+        for &(address, tile) in boot_rom::logo_map().iter() {
+            self.ppu.write_vram(tile, address - VRAM_BEGIN);
+        }
+    }
+
+    // The RAM that a reset leaves alone: WRAM, cartridge RAM and HRAM.
+    pub fn ram_contents(&self) -> Vec<u8> {
+        let mut ram = self.wram.clone();
+        ram.extend_from_slice(&self.memory[CARTRIDGE_RAM_BEGIN..=CARTRIDGE_RAM_END]);
+        ram.extend_from_slice(&self.memory[HRAM_BEGIN..=HRAM_END]);
+        ram
+    }
+
+    pub fn restore_ram_contents(&mut self, ram: &[u8]) {
+        let (wram, rest) = ram.split_at(self.wram.len());
+        let (cartridge_ram, hram) = rest.split_at(CARTRIDGE_RAM_END - CARTRIDGE_RAM_BEGIN + 1);
+        self.wram.copy_from_slice(wram);
+        self.memory[CARTRIDGE_RAM_BEGIN..=CARTRIDGE_RAM_END].copy_from_slice(cartridge_ram);
+        self.memory[HRAM_BEGIN..=HRAM_END].copy_from_slice(hram);
+    }
+
+    fn enter_dmg_compatibility(&mut self) {
+        self.cgb_mode = false;
+        self.wram_bank = 1;
+        self.ppu.enter_dmg_compatibility();
+    }
+
+    // Whether the CGB features are in use. DMG games run without them on the CGB and AGB.
+    pub fn cgb_mode(&self) -> bool {
+        self.cgb_mode
+    }
+
+    // Echo RAM mirrors WRAM. 0xD000-0xDFFF is switchable on the CGB and fixed to bank 1 otherwise.
+    fn wram_offset(&self, address: usize) -> usize {
+        let address = if address >= ECHO_RAM_BEGIN {
+            address - (ECHO_RAM_BEGIN - WRAM_BEGIN)
+        } else {
+            address
+        };
+        let offset = (address - WRAM_BEGIN) % WRAM_BANK_SIZE;
+        if address < WRAM_BEGIN + WRAM_BANK_SIZE {
+            offset
+        } else {
+            self.wram_bank * WRAM_BANK_SIZE + offset
+        }
+    }
+
+    pub fn finished_boot(&self) -> bool {
+        self.finished_boot
+    }
+
+    // The address with the bank currently mapped there, as used by RGBDS symbol files.
+    pub fn banked_address(&self, address: u16) -> BankedAddress {
+        let bank = match address as usize {
+            CARTRIDGE_ROM_BANK_REST_START..=CARTRIDGE_ROM_BANK_REST_END => 1,
+            VRAM_BEGIN..=VRAM_END => self.ppu.vram_bank() as u16,
+            0xD000..=0xDFFF => self.wram_bank as u16,
+            _ => 0,
+        };
+        BankedAddress { bank, address }
+    }
+
     pub fn read_byte_from_offset(&self, address_offset: u8) -> u8 {
         self.read_byte(address_offset as u16 + 0xFF00)
     }
 
+    // A write by the program, which watchpoints see.
     pub fn write_byte(&mut self, value: u8, address: u16) {
+        self.watchpoints.on_write(address);
+        self.poke_byte(value, address);
+    }
+
+    // Writes without watchpoints seeing it, for changes the hardware makes itself.
+    pub fn poke_byte(&mut self, value: u8, address: u16) {
         let address = address as usize;
         match address {
-            BOOTROM_BEGIN..=BOOTROM_END if !self.finished_boot => {}
+            _ if !self.finished_boot && self.boot_rom.maps(address) => {}
             CARTRIDGE_ROM_BANK_0_START..=CARTRIDGE_ROM_BANK_0_START
             | CARTRIDGE_ROM_BANK_REST_START..=CARTRIDGE_ROM_BANK_REST_END => {}
             VRAM_BEGIN..=VRAM_END => self.ppu.write_vram(value, address - VRAM_BEGIN),
+            WRAM_BEGIN..=ECHO_RAM_END => {
+                let offset = self.wram_offset(address);
+                self.wram[offset] = value;
+            }
+            OAM_BEGIN..=OAM_END => self.ppu.write_oam(value, address - OAM_BEGIN),
             IO_REGISTER_BEGIN..=IO_REGISTER_END => self.write_io_register(value, address),
             _ => self.memory[address] = value,
         }
@@ -91,21 +224,74 @@ impl MemoryBus {
         self.write_byte(value, address_offset as u16 + 0xFF00)
     }
 
+    // Copies 0xXX00-0xXX9F into OAM. This takes 160 microseconds on hardware, but the copy is done
+    // at once as nothing else here can observe the difference.
+    fn oam_dma(&mut self, source_page: u8) {
+        let source = (source_page as u16) << 8;
+        for offset in 0..OAM_SIZE as u16 {
+            let value = self.peek_byte(source + offset);
+            self.ppu.write_oam(value, offset as usize);
+        }
+    }
+
+    pub fn run_hblank_dma(&mut self) {
+        if let Some(block) = self.hdma.next_hblank_block(self.speed.is_double_speed()) {
+            self.copy_dma_block(block);
+        }
+    }
+
+    fn run_general_dma(&mut self) {
+        while let Some(block) = self.hdma.next_general_block(self.speed.is_double_speed()) {
+            self.copy_dma_block(block);
+        }
+    }
+
+    fn copy_dma_block(&mut self, block: HdmaBlock) {
+        for offset in 0..0x10 {
+            let value = self.peek_byte(block.source.wrapping_add(offset));
+            let destination = (block.destination + offset) as usize;
+            self.ppu.write_vram(value, destination - VRAM_BEGIN);
+        }
+    }
+
     fn read_io_register(&self, address: usize) -> u8 {
         match address {
             _ if self.input.supports_io_register(address) => self.input.read_io_register(address),
             _ if self.ppu.supports_io_register(address) => self.ppu.read_io_register(address),
             _ if APU::supports_io_register(address) => self.apu.read_io_register(address),
             _ if self.timer.supports_io_register(address) => self.timer.read_io_register(address),
+            _ if self.serial.supports_io_register(address) => self.serial.read_io_register(address),
+            SVBK if self.cgb_mode => 0xF8 | self.wram_bank as u8,
+            _ if self.cgb_mode && self.speed.supports_io_register(address) => {
+                self.speed.read_io_register(address)
+            }
+            _ if self.cgb_mode && self.hdma.supports_io_register(address) => {
+                self.hdma.read_io_register(address)
+            }
             _ => self.memory[address],
         }
     }
 
     fn write_io_register(&mut self, value: u8, address: usize) {
         match address {
-            0xFF50 if !self.finished_boot => self.finished_boot = true,
+            0xFF50 if !self.finished_boot => {
+                self.finished_boot = true;
+                // The CGB boot ROM checks for a held button combo as it hands over to the game.
+                if self.colourise_from_buttons {
+                    if let Some(combo) = ButtonCombo::from_joypad(self.input.current_joypad()) {
+                        self.ppu.set_dmg_colours(combo.colours());
+                    }
+                }
+            }
+            OAM_DMA => {
+                self.memory[address] = value;
+                self.oam_dma(value);
+            }
             _ if self.input.supports_io_register(address) => {
-                self.input.write_io_register(value, address)
+                self.input.write_io_register(value, address);
+                while let Some(command) = self.input.take_sgb_command() {
+                    self.ppu.handle_sgb_command(&command);
+                }
             }
             _ if self.ppu.supports_io_register(address) => {
                 self.ppu.write_io_register(value, address)
@@ -114,14 +300,61 @@ impl MemoryBus {
             _ if self.timer.supports_io_register(address) => {
                 self.timer.write_io_register(value, address)
             }
+            _ if self.serial.supports_io_register(address) => {
+                self.serial.write_io_register(value, address)
+            }
+            // The CGB boot ROM switches to DMG compatibility for DMG games with KEY0.
+            KEY0 if self.cgb_mode && !self.finished_boot => {
+                if (value & 0x04) != 0 {
+                    self.enter_dmg_compatibility();
+                }
+            }
+            // Selecting bank 0 selects bank 1.
+            SVBK if self.cgb_mode => self.wram_bank = ((value & 0b111) as usize).max(1),
+            _ if self.cgb_mode && self.speed.supports_io_register(address) => {
+                self.speed.write_io_register(value, address)
+            }
+            _ if self.cgb_mode && self.hdma.supports_io_register(address) => {
+                self.hdma.write_io_register(value, address);
+                self.run_general_dma();
+            }
             _ => self.memory[address] = value,
         }
     }
 }
 
-const BOOTROM_BEGIN: usize = 0x0000;
-const BOOTROM_END: usize = 0x00FF;
-const BOOTROM_SIZE: usize = BOOTROM_END - BOOTROM_BEGIN + 1;
+impl Snapshot for MemoryBus {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.cgb_mode);
+        state.bytes(&self.memory);
+        state.bytes(&self.wram);
+        state.usize(self.wram_bank);
+        state.bool(self.finished_boot);
+        self.ppu.save_state(state);
+        self.apu.save_state(state);
+        self.input.save_state(state);
+        self.timer.save_state(state);
+        self.serial.save_state(state);
+        self.speed.save_state(state);
+        self.hdma.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) {
+        self.cgb_mode = state.bool();
+        state.bytes(&mut self.memory);
+        state.bytes(&mut self.wram);
+        self.wram_bank = state.usize() % WRAM_BANKS;
+        self.finished_boot = state.bool();
+        self.ppu.load_state(state);
+        self.apu.load_state(state);
+        self.input.load_state(state);
+        self.timer.load_state(state);
+        self.serial.load_state(state);
+        self.speed.load_state(state);
+        self.hdma.load_state(state);
+    }
+}
+
 const CARTRIDGE_ROM_BANK_0_START: usize = 0x0000;
 const CARTRIDGE_ROM_BANK_0_END: usize = 0x3FFF;
 const CARTRIDGE_ROM_BANK_REST_START: usize = 0x4000;
@@ -129,5 +362,22 @@ const CARTRIDGE_ROM_BANK_REST_END: usize = 0x7FFF;
 pub const VRAM_BEGIN: usize = 0x8000;
 pub const VRAM_END: usize = 0x9FFF;
 pub const VRAM_SIZE: usize = VRAM_END - VRAM_BEGIN + 1;
+const CARTRIDGE_RAM_BEGIN: usize = 0xA000;
+const CARTRIDGE_RAM_END: usize = 0xBFFF;
+const WRAM_BEGIN: usize = 0xC000;
+const WRAM_BANK_SIZE: usize = 0x1000;
+const WRAM_BANKS: usize = 8;
+const ECHO_RAM_BEGIN: usize = 0xE000;
+const ECHO_RAM_END: usize = 0xFDFF;
+const OAM_BEGIN: usize = 0xFE00;
+const OAM_END: usize = OAM_BEGIN + OAM_SIZE - 1;
 const IO_REGISTER_BEGIN: usize = 0xFF00;
 const IO_REGISTER_END: usize = 0xFF7F;
+const HRAM_BEGIN: usize = 0xFF80;
+const HRAM_END: usize = 0xFFFE;
+const OAM_DMA: usize = 0xFF46;
+const LOGO_BEGIN: u16 = 0x0104;
+const LOGO_END: u16 = 0x0133;
+const LOGO_TILES_BEGIN: usize = 0x8010;
+const KEY0: usize = 0xFF4C;
+const SVBK: usize = 0xFF70;
@@ -0,0 +1,76 @@
+use crate::memory::cartridge::Cartridge;
+use std::str::FromStr;
+
+// The console being emulated. The CGB and AGB run DMG games in a compatibility mode without the
+// CGB hardware features.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Model {
+    // The original DMG, with an earlier boot ROM.
+    DMG0,
+    DMG,
+    // The Game Boy Pocket.
+    MGB,
+    // A DMG running in the Super Game Boy, which adds borders and colour through commands sent
+    // over the joypad port.
+    SGB,
+    CGB,
+    // The Game Boy Advance running Game Boy games.
+    AGB,
+}
+
+impl Model {
+    pub fn is_colour(self) -> bool {
+        match self {
+            Model::CGB | Model::AGB => true,
+            Model::DMG0 | Model::DMG | Model::MGB | Model::SGB => false,
+        }
+    }
+}
+
+impl FromStr for Model {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dmg0" => Ok(Model::DMG0),
+            "dmg" => Ok(Model::DMG),
+            "mgb" => Ok(Model::MGB),
+            "sgb" => Ok(Model::SGB),
+            "cgb" => Ok(Model::CGB),
+            "agb" => Ok(Model::AGB),
+            _ => Err(format!(
+                "Unknown model {} (expected auto, dmg0, dmg, mgb, sgb, cgb or agb)",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum ModelSelection {
+    Auto,
+    Forced(Model),
+}
+
+impl FromStr for ModelSelection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ModelSelection::Auto),
+            _ => s.parse().map(ModelSelection::Forced),
+        }
+    }
+}
+
+impl ModelSelection {
+    // Picks the most capable model the cartridge header says it supports.
+    pub fn resolve(self, cart: Option<&Cartridge>) -> Model {
+        match (self, cart) {
+            (ModelSelection::Forced(model), _) => model,
+            (ModelSelection::Auto, Some(cart)) if cart.supports_cgb() => Model::CGB,
+            (ModelSelection::Auto, Some(cart)) if cart.supports_sgb() => Model::SGB,
+            (ModelSelection::Auto, _) => Model::DMG,
+        }
+    }
+}
@@ -0,0 +1,414 @@
+use super::Movie;
+use crate::input::{JoypadInput, Joypads, JOYPAD_PORTS};
+use crate::model::Model;
+use crate::utils::sha1;
+use std::path::Path;
+
+// BizHawk and VBA both end a frame at VBlank and log one input per frame, including lag frames
+// where the game never reads the joypad. Our frames are a fixed number of cycles from power on,
+// so each logged frame maps onto one of ours, though input can change at a different point in the
+// frame. Our first frame runs before any input is applied, so the first logged frame is dropped.
+fn frames_after_first(mut frames: Vec<Joypads>) -> Vec<Joypads> {
+    if !frames.is_empty() {
+        if frames[0] != Joypads::default() {
+            eprintln!("Input on frame 0 is ignored");
+        }
+        frames.remove(0);
+    }
+    frames
+}
+
+// Neither reset nor power cycling is emulated, so a run that uses them won't play back past them.
+fn report_resets(resets: &[usize]) {
+    if let Some(frame) = resets.first() {
+        eprintln!(
+            "Frame {} resets the console, which isn't supported, so playback will diverge from there ({} resets in all)",
+            frame,
+            resets.len()
+        );
+    }
+}
+
+// A BizHawk movie is a zip archive holding a header of "Key Value" lines and an input log.
+pub fn load_bk2(path: &Path, rom: &[u8]) -> Result<Movie, String> {
+    let archive =
+        std::fs::read(path).map_err(|err| format!("Could not read {}: {}", path.display(), err))?;
+    let invalid = |err: String| format!("Invalid BizHawk movie {}: {}", path.display(), err);
+    let header = read_zip_text(&archive, "Header.txt").map_err(invalid)?;
+    let input_log = read_zip_text(&archive, "Input Log.txt").map_err(invalid)?;
+
+    let header_value = |key: &str| {
+        header.lines().find_map(|line| {
+            let mut parts = line.splitn(2, ' ');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) if name == key => Some(value.trim()),
+                _ => None,
+            }
+        })
+    };
+    if header_value("StartsFromSavestate") == Some("True")
+        || header_value("StartsFromSaveRam") == Some("True")
+    {
+        return Err("Movies that start from a savestate or save data aren't supported".to_string());
+    }
+    match header_value("SHA1") {
+        Some(hash) if !hash.eq_ignore_ascii_case(&hex(&sha1(rom))) => {
+            return Err("The movie was recorded with a different ROM".to_string())
+        }
+        Some(_) => {}
+        None => eprintln!("The movie has no ROM hash, so the ROM can't be checked"),
+    }
+    let model = match (header_value("Platform"), header_value("IsCGBMode")) {
+        (Some("SGB"), _) => Model::SGB,
+        (Some("GBC"), _) | (Some("GB"), Some("1")) => Model::CGB,
+        (Some("GB"), _) => Model::DMG,
+        (platform, _) => {
+            return Err(format!(
+                "Movies for {} aren't supported",
+                platform.unwrap_or("an unknown platform")
+            ))
+        }
+    };
+
+    let (frames, resets) = parse_input_log(&input_log).map_err(invalid)?;
+    report_resets(&resets);
+    Ok(Movie::from_power_on(model, rom, frames_after_first(frames)))
+}
+
+// The log starts with the buttons in the order they appear on each line, in groups for each
+// player, then has one line per frame with '.' for each button that isn't pressed:
+//
+// LogKey:#P1 Up|P1 Down|P1 Left|P1 Right|P1 Start|P1 Select|P1 B|P1 A|P1 Power|
+// |...R...A.|
+//
+// Buttons without a player are the first player's.
+fn parse_input_log(log: &str) -> Result<(Vec<Joypads>, Vec<usize>), String> {
+    let mut buttons: Option<Vec<(usize, &str)>> = None;
+    let mut frames = Vec::new();
+    let mut resets = Vec::new();
+    for line in log.lines() {
+        if let Some(key) = line.strip_prefix("LogKey:") {
+            buttons = Some(
+                key.split(&['#', '|'][..])
+                    .filter(|name| !name.is_empty())
+                    .map(parse_button_name)
+                    .collect(),
+            );
+            continue;
+        }
+        if !line.starts_with('|') {
+            continue;
+        }
+        let buttons = buttons
+            .as_ref()
+            .ok_or_else(|| "The input log has no LogKey".to_string())?;
+        let mut joypads = [JoypadInput::default(); JOYPAD_PORTS];
+        let states = line.chars().filter(|&c| c != '|');
+        for (&(port, name), state) in buttons.iter().zip(states) {
+            if state == '.' || state == ' ' || port >= JOYPAD_PORTS {
+                continue;
+            }
+            let joypad = &mut joypads[port];
+            match name {
+                "Up" => joypad.up = true,
+                "Down" => joypad.down = true,
+                "Left" => joypad.left = true,
+                "Right" => joypad.right = true,
+                "Start" => joypad.start = true,
+                "Select" => joypad.select = true,
+                "B" => joypad.b = true,
+                "A" => joypad.a = true,
+                "Power" | "Reset" => resets.push(frames.len()),
+                _ => {}
+            }
+        }
+        frames.push(joypads);
+    }
+    Ok((frames, resets))
+}
+
+fn parse_button_name(name: &str) -> (usize, &str) {
+    let mut parts = name.splitn(2, ' ');
+    match (parts.next(), parts.next()) {
+        (Some(player), Some(button)) if player.starts_with('P') => {
+            match player[1..].parse::<usize>() {
+                Ok(player) if player > 0 => (player - 1, button),
+                _ => (0, name),
+            }
+        }
+        _ => (0, name),
+    }
+}
+
+// The file names in a zip archive are listed in the central directory at the end, which gives
+// where each file's data starts. Files are either stored as they are or deflated.
+fn read_zip_text(archive: &[u8], name: &str) -> Result<String, String> {
+    let missing = || format!("{} is missing", name);
+    let end_of_directory = (0..archive.len().saturating_sub(21))
+        .rev()
+        .find(|&offset| u32_at(archive, offset) == Some(0x0605_4B50))
+        .ok_or_else(|| "Not a zip archive".to_string())?;
+    let entries = u16_at(archive, end_of_directory + 10).ok_or_else(missing)?;
+    let mut entry = u32_at(archive, end_of_directory + 16).ok_or_else(missing)? as usize;
+    for _ in 0..entries {
+        if u32_at(archive, entry) != Some(0x0201_4B50) {
+            break;
+        }
+        let field = |offset: usize| u16_at(archive, entry + offset).map(|value| value as usize);
+        let (name_length, extra_length, comment_length) = match (field(28), field(30), field(32)) {
+            (Some(name_length), Some(extra_length), Some(comment_length)) => {
+                (name_length, extra_length, comment_length)
+            }
+            _ => break,
+        };
+        if archive.get(entry + 46..entry + 46 + name_length) == Some(name.as_bytes()) {
+            let method = field(10).ok_or_else(missing)?;
+            let size = u32_at(archive, entry + 20).ok_or_else(missing)? as usize;
+            let header = u32_at(archive, entry + 42).ok_or_else(missing)? as usize;
+            let data_start = match (u16_at(archive, header + 26), u16_at(archive, header + 28)) {
+                (Some(name_length), Some(extra_length)) => {
+                    header + 30 + name_length as usize + extra_length as usize
+                }
+                _ => return Err(missing()),
+            };
+            let data = archive
+                .get(data_start..data_start + size)
+                .ok_or_else(|| format!("{} is truncated", name))?;
+            let contents = match method {
+                0 => data.to_vec(),
+                8 => miniz_oxide::inflate::decompress_to_vec(data)
+                    .map_err(|_| format!("{} could not be decompressed", name))?,
+                _ => return Err(format!("{} is compressed in an unsupported way", name)),
+            };
+            return String::from_utf8(contents).map_err(|_| format!("{} is not text", name));
+        }
+        entry += 46 + name_length + extra_length + comment_length;
+    }
+    Err(missing())
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> Option<u16> {
+    let bytes = bytes.get(offset..offset + 2)?;
+    Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Option<u32> {
+    let bytes = bytes.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02X}", byte)).collect()
+}
+
+const VBM_SIGNATURE: u32 = 0x1A4D_4256;
+const VBM_HEADER_SIZE: usize = 0x100;
+const VBM_RESET: u16 = 1 << 11;
+const ROM_TITLE: usize = 0x0134;
+const ROM_HEADER_CHECKSUM: usize = 0x014D;
+
+// A VBA movie has a fixed size header, then two bytes of input per frame for each joypad it
+// uses. The low byte has the buttons in the same order as our JoypadInput bytes.
+pub fn load_vbm(path: &Path, rom: &[u8]) -> Result<Movie, String> {
+    let bytes =
+        std::fs::read(path).map_err(|err| format!("Could not read {}: {}", path.display(), err))?;
+    parse_vbm(&bytes, rom).map_err(|err| format!("{}: {}", path.display(), err))
+}
+
+fn parse_vbm(bytes: &[u8], rom: &[u8]) -> Result<Movie, String> {
+    let invalid = || "Invalid VBA movie".to_string();
+    if bytes.len() < VBM_HEADER_SIZE || u32_at(bytes, 0) != Some(VBM_SIGNATURE) {
+        return Err(invalid());
+    }
+    let frame_count = u32_at(bytes, 0x0C).ok_or_else(invalid)? as usize;
+    let start_flags = bytes[0x14];
+    let controller_flags = bytes[0x15];
+    let system_flags = bytes[0x16];
+    let emulator_type = u32_at(bytes, 0x20).ok_or_else(invalid)?;
+    let input_start = u32_at(bytes, 0x3C).ok_or_else(invalid)? as usize;
+
+    if (start_flags & 0b11) != 0 {
+        return Err("Movies that start from a savestate or save data aren't supported".to_string());
+    }
+    // VBA keeps the title and header checksum from the ROM header.
+    let title_matches = rom.get(ROM_TITLE..ROM_TITLE + 12) == Some(&bytes[0x24..0x30]);
+    if !title_matches || rom.get(ROM_HEADER_CHECKSUM) != Some(&bytes[0x31]) {
+        return Err("The movie was recorded with a different ROM".to_string());
+    }
+    // The emulator type picks the GBA for Game Boy games, which shows as a CGB game.
+    let model = match system_flags {
+        flags if (flags & 0b001) != 0 => {
+            return Err("Game Boy Advance movies aren't supported".to_string())
+        }
+        flags if (flags & 0b010) != 0 && emulator_type == 4 => Model::AGB,
+        flags if (flags & 0b010) != 0 => Model::CGB,
+        flags if (flags & 0b100) != 0 => Model::SGB,
+        _ => Model::DMG,
+    };
+
+    let ports: Vec<usize> = (0..JOYPAD_PORTS)
+        .filter(|port| (controller_flags & (1 << port)) != 0)
+        .collect();
+    // The frame count comes from the file, so check the input is all there before trusting it.
+    let input_end = frame_count
+        .checked_mul(ports.len() * 2)
+        .and_then(|input_size| input_start.checked_add(input_size));
+    if ports.is_empty() && frame_count > 0 || !matches!(input_end, Some(end) if end <= bytes.len())
+    {
+        return Err(invalid());
+    }
+    let mut frames = Vec::with_capacity(frame_count);
+    let mut resets = Vec::new();
+    for frame in 0..frame_count {
+        let mut joypads = [JoypadInput::default(); JOYPAD_PORTS];
+        for (index, &port) in ports.iter().enumerate() {
+            let offset = input_start + (frame * ports.len() + index) * 2;
+            let input = u16_at(bytes, offset).ok_or_else(invalid)?;
+            if (input & VBM_RESET) != 0 && resets.last() != Some(&frame) {
+                resets.push(frame);
+            }
+            joypads[port] = JoypadInput::from(input as u8);
+        }
+        frames.push(joypads);
+    }
+    report_resets(&resets);
+    Ok(Movie::from_power_on(model, rom, frames_after_first(frames)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A zip archive with the files stored uncompressed.
+    fn zip(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut archive = Vec::new();
+        let mut directory = Vec::new();
+        for (name, contents) in files {
+            let header = archive.len() as u32;
+            let size = (contents.len() as u32).to_le_bytes();
+            archive.extend_from_slice(&0x0403_4B50u32.to_le_bytes());
+            archive.extend_from_slice(&[0; 14]);
+            archive.extend_from_slice(&size);
+            archive.extend_from_slice(&size);
+            archive.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            archive.extend_from_slice(&[0; 2]);
+            archive.extend_from_slice(name.as_bytes());
+            archive.extend_from_slice(contents.as_bytes());
+
+            directory.extend_from_slice(&0x0201_4B50u32.to_le_bytes());
+            directory.extend_from_slice(&[0; 16]);
+            directory.extend_from_slice(&size);
+            directory.extend_from_slice(&size);
+            directory.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            directory.extend_from_slice(&[0; 12]);
+            directory.extend_from_slice(&header.to_le_bytes());
+            directory.extend_from_slice(name.as_bytes());
+        }
+        let directory_start = archive.len() as u32;
+        archive.extend_from_slice(&directory);
+        archive.extend_from_slice(&0x0605_4B50u32.to_le_bytes());
+        archive.extend_from_slice(&[0; 6]);
+        archive.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        archive.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+        archive.extend_from_slice(&directory_start.to_le_bytes());
+        archive.extend_from_slice(&[0; 2]);
+        archive
+    }
+
+    #[test]
+    fn reads_stored_zip_entries() {
+        let archive = zip(&[("Header.txt", "Platform GB\n"), ("Input Log.txt", "|..|\n")]);
+        assert_eq!(
+            read_zip_text(&archive, "Input Log.txt"),
+            Ok("|..|\n".to_string())
+        );
+        assert_eq!(
+            read_zip_text(&archive, "Header.txt"),
+            Ok("Platform GB\n".to_string())
+        );
+        assert_eq!(
+            read_zip_text(&archive, "Comments.txt"),
+            Err("Comments.txt is missing".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_zips() {
+        let archive = zip(&[("Header.txt", "Platform GB\n")]);
+        let directory = u32_at(&archive, archive.len() - 6).unwrap() as usize;
+        let end = archive.len();
+        for &length in [0, 4, 30, directory, directory + 20, end - 1].iter() {
+            assert!(read_zip_text(&archive[..length], "Header.txt").is_err());
+        }
+        // The directory is intact, but says the file is longer than the data that's there.
+        let mut oversized = archive.clone();
+        oversized[directory + 20..directory + 24].copy_from_slice(&0x1000u32.to_le_bytes());
+        assert_eq!(
+            read_zip_text(&oversized, "Header.txt"),
+            Err("Header.txt is truncated".to_string())
+        );
+    }
+
+    #[test]
+    fn parses_input_logs() {
+        let log = "[Input]\n\
+                   LogKey:#P1 Up|P1 Down|P1 Left|P1 Right|P1 Start|P1 Select|P1 B|P1 A|P1 Power|\
+                   #P2 Up|P2 Down|P2 Left|P2 Right|P2 Start|P2 Select|P2 B|P2 A|\n\
+                   |.........|........|\n\
+                   |...R...A.|U.......|\n\
+                   |........P|........|\n\
+                   [/Input]\n";
+        let (frames, resets) = parse_input_log(log).unwrap();
+        assert_eq!(frames.len(), 3);
+        assert!(frames[0] == Joypads::default());
+        assert!(frames[1][0].right && frames[1][0].a && !frames[1][0].b);
+        assert!(frames[1][1].up && !frames[1][1].a);
+        assert_eq!(resets, [2]);
+
+        assert!(parse_input_log("|..|\n").is_err());
+    }
+
+    fn vbm(rom: &[u8], controller_flags: u8, frame_count: u32, input: &[u16]) -> Vec<u8> {
+        let mut bytes = vec![0; VBM_HEADER_SIZE];
+        bytes[0..4].copy_from_slice(&VBM_SIGNATURE.to_le_bytes());
+        bytes[0x0C..0x10].copy_from_slice(&frame_count.to_le_bytes());
+        bytes[0x15] = controller_flags;
+        bytes[0x24..0x30].copy_from_slice(&rom[ROM_TITLE..ROM_TITLE + 12]);
+        bytes[0x31] = rom[ROM_HEADER_CHECKSUM];
+        bytes[0x3C..0x40].copy_from_slice(&(VBM_HEADER_SIZE as u32).to_le_bytes());
+        for value in input {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes
+    }
+
+    #[test]
+    fn parses_vbm_movies() {
+        let mut rom = vec![0; 0x8000];
+        rom[ROM_TITLE..ROM_TITLE + 4].copy_from_slice(b"TEST");
+        rom[ROM_HEADER_CHECKSUM] = 0x42;
+
+        // The first frame is dropped, as it runs before any input is applied.
+        let a = u8::from(&JoypadInput {
+            a: true,
+            ..JoypadInput::default()
+        }) as u16;
+        let movie = parse_vbm(&vbm(&rom, 0b01, 3, &[0, a, 0]), &rom).unwrap();
+        assert_eq!(movie.model, Model::DMG);
+        assert_eq!(movie.frames.len(), 2);
+        assert!(movie.frames[0][0].a);
+        assert!(movie.frames[1] == Joypads::default());
+
+        // Two joypads, with their input interleaved.
+        let movie = parse_vbm(&vbm(&rom, 0b11, 2, &[0, 0, 0, a]), &rom).unwrap();
+        assert!(!movie.frames[0][0].a && movie.frames[0][1].a);
+
+        assert!(parse_vbm(&vbm(&rom, 0b01, 4, &[0, a, 0]), &rom).is_err());
+        assert!(parse_vbm(&vbm(&rom, 0b01, u32::MAX, &[]), &rom).is_err());
+        assert!(parse_vbm(&vbm(&rom, 0, 3, &[]), &rom).is_err());
+        assert!(parse_vbm(&vbm(&rom, 0b01, 3, &[0, a, 0])[..0x80], &rom).is_err());
+        let mut other_rom = rom.clone();
+        other_rom[ROM_HEADER_CHECKSUM] = 0;
+        assert!(parse_vbm(&vbm(&rom, 0b01, 3, &[0, a, 0]), &other_rom).is_err());
+    }
+}
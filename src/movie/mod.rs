@@ -0,0 +1,346 @@
+pub mod import;
+
+use crate::cpu::CPU;
+use crate::input::{JoypadInput, Joypads, JOYPAD_PORTS};
+use crate::model::Model;
+use crate::snapshot::{StateReader, StateWriter};
+use crate::utils::crc32;
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 4] = b"GBMV";
+const VERSION: u8 = 3;
+// How often the state is checked against the recording.
+const FRAMES_PER_CHECKPOINT: usize = 60;
+const MODELS: [Model; 6] = [
+    Model::DMG0,
+    Model::DMG,
+    Model::MGB,
+    Model::SGB,
+    Model::CGB,
+    Model::AGB,
+];
+
+// The input applied at each frame from a starting state, which replays the same run on the same
+// ROM and model. Checksums of the state are recorded along the way so that playback can tell when
+// it no longer matches the recording.
+pub struct Movie {
+    pub model: Model,
+    pub rom_crc: u32,
+    start_frame: u64,
+    // None for movies that start from power on.
+    start_state: Option<Vec<u8>>,
+    frames: Vec<Joypads>,
+    checksums: Vec<u32>,
+}
+
+impl Movie {
+    pub fn start(cpu: &CPU, model: Model, rom: &[u8]) -> Self {
+        Movie {
+            model,
+            rom_crc: crc32(rom),
+            start_frame: cpu.frame(),
+            start_state: Some(cpu.snapshot()),
+            frames: Vec::new(),
+            checksums: Vec::new(),
+        }
+    }
+
+    // A movie from another emulator, which has no checksums to check playback against.
+    pub fn from_power_on(model: Model, rom: &[u8], frames: Vec<Joypads>) -> Self {
+        Movie {
+            model,
+            rom_crc: crc32(rom),
+            start_frame: 0,
+            start_state: None,
+            frames,
+            checksums: Vec::new(),
+        }
+    }
+
+    // Our own movies, or BizHawk and VBA movies by their extension.
+    pub fn open(path: &Path, rom: &[u8]) -> Result<Self, String> {
+        let extension = path.extension().and_then(|extension| extension.to_str());
+        let movie = match extension.map(|extension| extension.to_ascii_lowercase()) {
+            Some(extension) if extension == "bk2" => import::load_bk2(path, rom)?,
+            Some(extension) if extension == "vbm" => import::load_vbm(path, rom)?,
+            _ => Self::load(path)?,
+        };
+        movie.check_rom(rom)?;
+        Ok(movie)
+    }
+
+    fn load(path: &Path) -> Result<Self, String> {
+        let bytes = std::fs::read(path)
+            .map_err(|err| format!("Could not read {}: {}", path.display(), err))?;
+        Self::parse(&bytes).ok_or_else(|| format!("Invalid movie file {}", path.display()))
+    }
+
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        let mut state = StateReader::new(bytes);
+        let mut magic = [0; 4];
+        state.bytes(&mut magic);
+        if &magic != MAGIC || state.u8() != VERSION {
+            return None;
+        }
+        let model = *MODELS.get(state.u8() as usize)?;
+        let rom_crc = state.u32();
+        let start_frame = state.u64();
+        let start_state = Some(state.vec()).filter(|start_state| !start_state.is_empty());
+        let frames = (0..state.usize())
+            .map(|_| {
+                let mut joypads = [JoypadInput::default(); JOYPAD_PORTS];
+                for joypad in joypads.iter_mut() {
+                    *joypad = JoypadInput::from(state.u8());
+                }
+                joypads
+            })
+            .collect();
+        let checksums = (0..state.usize()).map(|_| state.u32()).collect();
+        if !state.is_complete() {
+            return None;
+        }
+        Some(Movie {
+            model,
+            rom_crc,
+            start_frame,
+            start_state,
+            frames,
+            checksums,
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        std::fs::write(path, self.to_bytes())
+            .map_err(|err| format!("Could not write {}: {}", path.display(), err))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut state = StateWriter::default();
+        state.bytes(MAGIC);
+        state.u8(VERSION);
+        let model = MODELS
+            .iter()
+            .position(|&model| model == self.model)
+            .unwrap();
+        state.u8(model as u8);
+        state.u32(self.rom_crc);
+        state.u64(self.start_frame);
+        state.vec(self.start_state.as_deref().unwrap_or_default());
+        state.usize(self.frames.len());
+        for joypads in &self.frames {
+            for joypad in joypads {
+                state.u8(joypad.into());
+            }
+        }
+        state.usize(self.checksums.len());
+        for &checksum in &self.checksums {
+            state.u32(checksum);
+        }
+        state.into_bytes()
+    }
+
+    pub fn check_rom(&self, rom: &[u8]) -> Result<(), String> {
+        if crc32(rom) != self.rom_crc {
+            return Err("The movie was recorded with a different ROM".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn start_state(&self) -> Option<&[u8]> {
+        self.start_state.as_deref()
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum MovieStatus {
+    Recording,
+    Playing,
+    Finished,
+    Desynced(String),
+}
+
+// A movie being recorded or played back, which the CPU calls into at the start of each frame.
+// Both follow the CPU's frame counter, so restoring a snapshot or rewinding carries on from the
+// restored frame: a recording drops the frames after it and playback seeks to it. The first
+// recorded input is for the frame after the movie starts, which is frame 1 in messages.
+pub struct MovieSession {
+    movie: Movie,
+    path: Option<PathBuf>,
+    status: MovieStatus,
+    // Whether each frame played so far was a lag frame, where the game never read the joypad.
+    lag_frames: Vec<bool>,
+    reported_lag_input: bool,
+}
+
+impl MovieSession {
+    // The recording is saved to the path when the session is dropped.
+    pub fn record(movie: Movie, path: PathBuf) -> Self {
+        MovieSession {
+            movie,
+            path: Some(path),
+            status: MovieStatus::Recording,
+            lag_frames: Vec::new(),
+            reported_lag_input: false,
+        }
+    }
+
+    pub fn play(movie: Movie) -> Self {
+        MovieSession {
+            movie,
+            path: None,
+            status: MovieStatus::Playing,
+            lag_frames: Vec::new(),
+            reported_lag_input: false,
+        }
+    }
+
+    pub fn status(&self) -> &MovieStatus {
+        &self.status
+    }
+
+    // Records the joypads the frontend set, or replaces them with the recorded ones. `lagged` is
+    // whether the game read the joypad during the frame that just ended.
+    pub fn start_frame(&mut self, cpu: &CPU, lagged: bool, joypads: &mut Joypads) {
+        let frame = match cpu.frame().checked_sub(self.movie.start_frame + 1) {
+            Some(frame) => frame as usize,
+            None => return,
+        };
+        if frame > 0 {
+            self.lag_frames.resize(frame - 1, false);
+            self.lag_frames.push(lagged);
+            self.check_lag_input(frame - 1);
+        }
+        let checkpoint = frame / FRAMES_PER_CHECKPOINT;
+        let at_checkpoint = frame % FRAMES_PER_CHECKPOINT == 0;
+        match self.status {
+            // A state from after the end of the recording would leave a gap in the input.
+            MovieStatus::Recording if frame > self.movie.frames.len() => {
+                eprintln!(
+                    "Stopped recording at frame {}, the state was from frame {}",
+                    self.movie.frames.len(),
+                    frame + 1
+                );
+                self.status = MovieStatus::Finished;
+            }
+            MovieStatus::Recording => {
+                if at_checkpoint {
+                    self.movie.checksums.truncate(checkpoint);
+                    self.movie.checksums.push(cpu.state_checksum());
+                }
+                self.movie.frames.truncate(frame);
+                self.movie.frames.push(*joypads);
+            }
+            MovieStatus::Playing => {
+                if let (true, Some(&expected)) =
+                    (at_checkpoint, self.movie.checksums.get(checkpoint))
+                {
+                    let checksum = cpu.state_checksum();
+                    if checksum != expected {
+                        let err = format!(
+                            "Movie desynced at frame {}: state checksum {:08X}, recorded {:08X}",
+                            frame + 1,
+                            checksum,
+                            expected
+                        );
+                        eprintln!("{}", err);
+                        self.status = MovieStatus::Desynced(err);
+                        return;
+                    }
+                }
+                match self.movie.frames.get(frame) {
+                    Some(recorded) => *joypads = *recorded,
+                    None => {
+                        let lag_frames = self.lag_frames.iter().filter(|&&lagged| lagged).count();
+                        println!(
+                            "Movie finished after {} frames, {} of them lag frames",
+                            frame, lag_frames
+                        );
+                        self.status = MovieStatus::Finished;
+                    }
+                }
+            }
+            MovieStatus::Finished | MovieStatus::Desynced(_) => {}
+        }
+    }
+
+    // Movies from other emulators have no checksums, so the best sign of where playback went
+    // wrong is the first new input the game didn't read. Runs rarely waste input like that, while
+    // a desynced game often sits in a loop that doesn't poll the joypad.
+    fn check_lag_input(&mut self, frame: usize) {
+        if self.status != MovieStatus::Playing
+            || !self.movie.checksums.is_empty()
+            || self.reported_lag_input
+            || !self.lag_frames[frame]
+        {
+            return;
+        }
+        let previous = match frame {
+            0 => Joypads::default(),
+            _ => self.movie.frames[frame - 1],
+        };
+        if matches!(self.movie.frames.get(frame), Some(joypads) if *joypads != previous) {
+            eprintln!(
+                "Input changed on lag frame {}, so playback may have diverged from here",
+                frame + 1
+            );
+            self.reported_lag_input = true;
+        }
+    }
+}
+
+impl Drop for MovieSession {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            match self.movie.save(path) {
+                Ok(()) => println!(
+                    "Saved {} frames of input to {}",
+                    self.movie.frames.len(),
+                    path.display()
+                ),
+                Err(err) => eprintln!("{}", err),
+            }
+        }
+    }
+}
+
+// Plays back the attached movie as fast as possible without a window or sound, returning the
+// frames played once it finishes in sync.
+pub fn play_headless(cpu: &mut CPU) -> Result<u64, String> {
+    let mut frames_played = 0;
+    loop {
+        cpu.run_frame();
+        match cpu.movie_status() {
+            Some(MovieStatus::Playing) => frames_played += 1,
+            Some(MovieStatus::Finished) => return Ok(frames_played),
+            Some(MovieStatus::Desynced(err)) => return Err(err.clone()),
+            Some(MovieStatus::Recording) | None => return Err("No movie is playing".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saved_movies_parse() {
+        let mut joypads = Joypads::default();
+        joypads[0].a = true;
+        joypads[1].start = true;
+        let mut movie = Movie::from_power_on(Model::SGB, b"rom", vec![Joypads::default(), joypads]);
+        movie.start_frame = 120;
+        movie.start_state = Some(vec![1, 2, 3]);
+        movie.checksums = vec![0xDEADBEEF, 0x12345678];
+
+        let parsed = Movie::parse(&movie.to_bytes()).unwrap();
+        assert_eq!(parsed.model, Model::SGB);
+        assert_eq!(parsed.rom_crc, crc32(b"rom"));
+        assert_eq!(parsed.start_frame, 120);
+        assert_eq!(parsed.start_state, movie.start_state);
+        assert!(parsed.frames == movie.frames);
+        assert_eq!(parsed.checksums, movie.checksums);
+
+        let bytes = movie.to_bytes();
+        assert!(Movie::parse(&bytes[..bytes.len() - 1]).is_none());
+    }
+}
@@ -0,0 +1,108 @@
+use super::palette::{DmgColours, ShadeColours, GREY_SHADES};
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+// How the four DMG shades are shown on screen. This only changes the colours written to the
+// framebuffer, never what the game sees.
+#[derive(Clone, Debug)]
+pub enum ColourScheme {
+    Grey,
+    Green,
+    Pocket,
+    Light,
+    HighContrast,
+    File(PathBuf),
+}
+
+pub const PRESETS: [ColourScheme; 5] = [
+    ColourScheme::Grey,
+    ColourScheme::Green,
+    ColourScheme::Pocket,
+    ColourScheme::Light,
+    ColourScheme::HighContrast,
+];
+
+impl FromStr for ColourScheme {
+    type Err = String;
+
+    // Anything that is not a preset name is taken as the path of a palette file.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "grey" => Ok(ColourScheme::Grey),
+            "green" => Ok(ColourScheme::Green),
+            "pocket" => Ok(ColourScheme::Pocket),
+            "light" => Ok(ColourScheme::Light),
+            "high-contrast" => Ok(ColourScheme::HighContrast),
+            _ => Ok(ColourScheme::File(PathBuf::from(s))),
+        }
+    }
+}
+
+impl ColourScheme {
+    pub fn load(&self) -> Result<DmgColours, String> {
+        match self {
+            ColourScheme::Grey => Ok(DmgColours::uniform(GREY_SHADES)),
+            ColourScheme::Green => Ok(DmgColours::uniform([
+                0x9BBC0F, 0x8BAC0F, 0x306230, 0x0F380F,
+            ])),
+            ColourScheme::Pocket => Ok(DmgColours::uniform([
+                0xC4CFA1, 0x8B956D, 0x4D533C, 0x1F1F1F,
+            ])),
+            ColourScheme::Light => Ok(DmgColours::uniform([
+                0x00B581, 0x009A71, 0x00694A, 0x004F3B,
+            ])),
+            ColourScheme::HighContrast => Ok(DmgColours::uniform([
+                0xFFFFFF, 0xAAAAAA, 0x555555, 0x000000,
+            ])),
+            ColourScheme::File(path) => {
+                let contents = std::fs::read_to_string(path)
+                    .map_err(|err| format!("Could not read {}: {}", path.display(), err))?;
+                parse_palette_file(&contents)
+                    .map_err(|err| format!("Invalid palette file {}: {}", path.display(), err))
+            }
+        }
+    }
+}
+
+// A palette file lists colours from lightest to darkest, for example:
+//
+// colours = ["#E0F8D0", "#88C070", "#346856", "#081820"]
+//
+// The BG and either sprite palette can be given their own colours with `bg`, `sprite0` and
+// `sprite1`, falling back to `colours`.
+#[derive(Deserialize)]
+struct PaletteFile {
+    colours: Option<Vec<String>>,
+    bg: Option<Vec<String>>,
+    sprite0: Option<Vec<String>>,
+    sprite1: Option<Vec<String>>,
+}
+
+fn parse_palette_file(contents: &str) -> Result<DmgColours, String> {
+    let file: PaletteFile = toml::from_str(contents).map_err(|err| err.to_string())?;
+    let default = file.colours.as_deref().map(parse_shades).transpose()?;
+    let shades = |colours: Option<Vec<String>>| match colours {
+        Some(colours) => parse_shades(&colours),
+        None => default.ok_or_else(|| "expected a list of colours".to_string()),
+    };
+    Ok(DmgColours {
+        bg: shades(file.bg)?,
+        sprites: [shades(file.sprite0)?, shades(file.sprite1)?],
+    })
+}
+
+fn parse_shades(colours: &[String]) -> Result<ShadeColours, String> {
+    if colours.len() != 4 {
+        return Err(format!("expected 4 colours, found {}", colours.len()));
+    }
+    let mut shades = [0; 4];
+    for (shade, colour) in shades.iter_mut().zip(colours) {
+        let digits = colour.trim_start_matches('#').trim_start_matches("0x");
+        *shade = match u32::from_str_radix(digits, 16) {
+            Ok(value) if digits.len() == 6 => value,
+            _ => return Err(format!("{} is not a colour like #RRGGBB", colour)),
+        };
+    }
+    Ok(shades)
+}
@@ -0,0 +1,271 @@
+use super::palette::{colour_from_bgr555, DmgColours, ShadeColours};
+use crate::input::JoypadInput;
+use crate::memory::cartridge::Cartridge;
+use std::str::FromStr;
+
+// Colourisation the CGB boot ROM applies to DMG games, either picked from the cartridge title or
+// chosen by holding a direction (optionally with A or B) while the boot logo is shown.
+#[derive(Copy, Clone, Debug)]
+pub enum Colourisation {
+    Auto,
+    Combo(ButtonCombo),
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum ButtonCombo {
+    Up,
+    UpA,
+    UpB,
+    Left,
+    LeftA,
+    LeftB,
+    Down,
+    DownA,
+    DownB,
+    Right,
+    RightA,
+    RightB,
+}
+
+const WHITE_BROWN: ShadeColours = [0xFFFFFF, 0xFFAD63, 0x843100, 0x000000];
+const WHITE_RED: ShadeColours = [0xFFFFFF, 0xFF8484, 0x943A3A, 0x000000];
+const WHITE_GREEN: ShadeColours = [0xFFFFFF, 0x7BFF31, 0x008400, 0x000000];
+const WHITE_BLUE: ShadeColours = [0xFFFFFF, 0x63A5FF, 0x0000FF, 0x000000];
+
+// Right + A, the same colours the boot ROM gives games it does not recognise.
+const DEFAULT_COLOURS: DmgColours = DmgColours {
+    bg: [0xFFFFFF, 0x7BFF31, 0x0063C5, 0x000000],
+    sprites: [WHITE_RED, WHITE_RED],
+};
+
+impl ButtonCombo {
+    pub fn colours(self) -> DmgColours {
+        match self {
+            ButtonCombo::Up => DmgColours::uniform(WHITE_BROWN),
+            ButtonCombo::UpA => DmgColours {
+                bg: WHITE_RED,
+                sprites: [WHITE_GREEN, WHITE_BLUE],
+            },
+            ButtonCombo::UpB => DmgColours::uniform([0xFFE6C5, 0xCE9C84, 0x846B29, 0x5A3108]),
+            ButtonCombo::Left => DmgColours {
+                bg: WHITE_BLUE,
+                sprites: [WHITE_RED, WHITE_GREEN],
+            },
+            ButtonCombo::LeftA => DmgColours {
+                bg: [0xFFFFFF, 0x8C8CDE, 0x52528C, 0x000000],
+                sprites: [WHITE_RED, WHITE_BROWN],
+            },
+            ButtonCombo::LeftB => DmgColours::uniform([0xFFFFFF, 0xA5A5A5, 0x525252, 0x000000]),
+            ButtonCombo::Down => DmgColours::uniform([0xFFFFA5, 0xFF9494, 0x9494FF, 0x000000]),
+            ButtonCombo::DownA => DmgColours::uniform([0xFFFFFF, 0xFFFF00, 0xFF0000, 0x000000]),
+            ButtonCombo::DownB => DmgColours {
+                bg: [0xFFFFFF, 0xFFFF00, 0x7B4A00, 0x000000],
+                sprites: [WHITE_BLUE, WHITE_GREEN],
+            },
+            ButtonCombo::Right => DmgColours::uniform([0xFFFFFF, 0x52FF00, 0xFF4200, 0x000000]),
+            ButtonCombo::RightA => DEFAULT_COLOURS,
+            ButtonCombo::RightB => DmgColours::uniform([0x000000, 0x008484, 0xFFDE00, 0xFFFFFF]),
+        }
+    }
+
+    pub fn from_joypad(joypad: &JoypadInput) -> Option<Self> {
+        let combo = match (joypad.up, joypad.left, joypad.down, joypad.right) {
+            (true, false, false, false) => [ButtonCombo::Up, ButtonCombo::UpA, ButtonCombo::UpB],
+            (false, true, false, false) => {
+                [ButtonCombo::Left, ButtonCombo::LeftA, ButtonCombo::LeftB]
+            }
+            (false, false, true, false) => {
+                [ButtonCombo::Down, ButtonCombo::DownA, ButtonCombo::DownB]
+            }
+            (false, false, false, true) => {
+                [ButtonCombo::Right, ButtonCombo::RightA, ButtonCombo::RightB]
+            }
+            _ => return None,
+        };
+        match (joypad.a, joypad.b) {
+            (false, false) => Some(combo[0]),
+            (true, false) => Some(combo[1]),
+            (false, true) => Some(combo[2]),
+            (true, true) => None,
+        }
+    }
+}
+
+impl FromStr for ButtonCombo {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "up" => Ok(ButtonCombo::Up),
+            "up+a" => Ok(ButtonCombo::UpA),
+            "up+b" => Ok(ButtonCombo::UpB),
+            "left" => Ok(ButtonCombo::Left),
+            "left+a" => Ok(ButtonCombo::LeftA),
+            "left+b" => Ok(ButtonCombo::LeftB),
+            "down" => Ok(ButtonCombo::Down),
+            "down+a" => Ok(ButtonCombo::DownA),
+            "down+b" => Ok(ButtonCombo::DownB),
+            "right" => Ok(ButtonCombo::Right),
+            "right+a" => Ok(ButtonCombo::RightA),
+            "right+b" => Ok(ButtonCombo::RightB),
+            _ => Err(format!(
+                "Unknown button combo {} (expected a direction, optionally followed by +a or +b)",
+                s
+            )),
+        }
+    }
+}
+
+impl FromStr for Colourisation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Colourisation::Auto),
+            _ => s.parse().map(Colourisation::Combo),
+        }
+    }
+}
+
+// The tables below are the CGB boot ROM's. A title's checksum is looked up in TITLE_CHECKSUMS;
+// checksums from FIRST_AMBIGUOUS_CHECKSUM onwards are shared by several titles and only match if
+// the title's fourth letter matches too. The position of the match picks a combination of
+// palettes from PALETTE_PER_CHECKSUM, and unrecognised titles use the first combination.
+const TITLE_CHECKSUMS: [u8; 94] = [
+    0x00, 0x88, 0x16, 0x36, 0xD1, 0xDB, 0xF2, 0x3C, 0x8C, 0x92, 0x3D, 0x5C, 0x58, 0xC9, 0x3E, 0x70,
+    0x1D, 0x59, 0x69, 0x19, 0x35, 0xA8, 0x14, 0xAA, 0x75, 0x95, 0x99, 0x34, 0x6F, 0x15, 0xFF, 0x97,
+    0x4B, 0x90, 0x17, 0x10, 0x39, 0xF7, 0xF6, 0xA2, 0x49, 0x4E, 0x43, 0x68, 0xE0, 0x8B, 0xF0, 0xCE,
+    0x0C, 0x29, 0xE8, 0xB7, 0x86, 0x9A, 0x52, 0x01, 0x9D, 0x71, 0x9C, 0xBD, 0x5D, 0x6D, 0x67, 0x3F,
+    0x6B, 0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4, 0xB3,
+    0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4, 0xB3,
+];
+
+const FIRST_AMBIGUOUS_CHECKSUM: usize = 0x41;
+
+const FOURTH_LETTERS: &[u8; 29] = b"BEFAARBEKEK R-URAR INAILICE R";
+
+const PALETTE_PER_CHECKSUM: [u8; 94] = [
+    0, 4, 5, 35, 34, 3, 31, 15, 10, 5, 19, 36, 7, 37, 30, 44, 21, 32, 31, 20, 5, 33, 13, 14, 5, 29,
+    5, 18, 9, 3, 2, 26, 25, 25, 41, 42, 26, 45, 42, 45, 36, 38, 26, 42, 30, 41, 34, 34, 5, 42, 6,
+    5, 33, 25, 42, 42, 40, 2, 16, 25, 42, 42, 5, 0, 39, 36, 22, 25, 6, 32, 12, 36, 11, 39, 18, 39,
+    24, 31, 50, 17, 46, 6, 27, 0, 47, 41, 41, 0, 0, 19, 34, 23, 18, 29,
+];
+
+// Each combination gives the first colour of the first sprite palette, the second sprite palette
+// and the background palette in PALETTE_COLOURS. Most start on a palette boundary, but a few
+// straddle two palettes.
+const PALETTE_COMBINATIONS: [[usize; 3]; 51] = [
+    [4 * 4, 4 * 4, 29 * 4],
+    [18 * 4, 18 * 4, 18 * 4],
+    [20 * 4, 20 * 4, 20 * 4],
+    [24 * 4, 24 * 4, 24 * 4],
+    [9 * 4, 9 * 4, 9 * 4],
+    [0, 0, 0],
+    [27 * 4, 27 * 4, 27 * 4],
+    [5 * 4, 5 * 4, 5 * 4],
+    [12 * 4, 12 * 4, 12 * 4],
+    [26 * 4, 26 * 4, 26 * 4],
+    [16 * 4, 8 * 4, 8 * 4],
+    [4 * 4, 28 * 4, 28 * 4],
+    [4 * 4, 2 * 4, 2 * 4],
+    [3 * 4, 4 * 4, 4 * 4],
+    [4 * 4, 29 * 4, 29 * 4],
+    [28 * 4, 4 * 4, 28 * 4],
+    [2 * 4, 17 * 4, 2 * 4],
+    [16 * 4, 16 * 4, 8 * 4],
+    [4 * 4, 4 * 4, 7 * 4],
+    [4 * 4, 4 * 4, 18 * 4],
+    [4 * 4, 4 * 4, 20 * 4],
+    [19 * 4, 19 * 4, 9 * 4],
+    [4 * 4 - 1, 4 * 4 - 1, 11 * 4],
+    [17 * 4, 17 * 4, 2 * 4],
+    [4 * 4, 4 * 4, 2 * 4],
+    [4 * 4, 4 * 4, 3 * 4],
+    [28 * 4, 28 * 4, 0],
+    [3 * 4, 3 * 4, 0],
+    [0, 0, 4],
+    [18 * 4, 22 * 4, 18 * 4],
+    [20 * 4, 22 * 4, 20 * 4],
+    [24 * 4, 22 * 4, 24 * 4],
+    [16 * 4, 22 * 4, 8 * 4],
+    [17 * 4, 4 * 4, 13 * 4],
+    [28 * 4 - 1, 0, 14 * 4],
+    [28 * 4 - 1, 4 * 4, 15 * 4],
+    [19 * 4, 22 * 4, 9 * 4],
+    [16 * 4, 28 * 4, 10 * 4],
+    [4 * 4, 23 * 4, 28 * 4],
+    [17 * 4, 22 * 4, 2 * 4],
+    [4 * 4, 0, 2 * 4],
+    [4 * 4, 28 * 4, 3 * 4],
+    [28 * 4, 3 * 4, 0],
+    [3 * 4, 28 * 4, 4 * 4],
+    [21 * 4, 28 * 4, 4 * 4],
+    [3 * 4, 28 * 4, 0],
+    [25 * 4, 3 * 4, 28 * 4],
+    [0, 28 * 4, 8 * 4],
+    [4 * 4, 3 * 4, 28 * 4],
+    [28 * 4, 3 * 4, 6 * 4],
+    [4 * 4, 28 * 4, 29 * 4],
+];
+
+// Four BGR555 colours per palette.
+const PALETTE_COLOURS: [u16; 120] = [
+    0x7FFF, 0x32BF, 0x00D0, 0x0000, 0x639F, 0x4279, 0x15B0, 0x04CB, 0x7FFF, 0x6E31, 0x454A, 0x0000,
+    0x7FFF, 0x1BEF, 0x0200, 0x0000, 0x7FFF, 0x421F, 0x1CF2, 0x0000, 0x7FFF, 0x5294, 0x294A, 0x0000,
+    0x7FFF, 0x03FF, 0x012F, 0x0000, 0x7FFF, 0x03EF, 0x01D6, 0x0000, 0x7FFF, 0x42B5, 0x3DC8, 0x0000,
+    0x7E74, 0x03FF, 0x0180, 0x0000, 0x67FF, 0x77AC, 0x1A13, 0x2D6B, 0x7ED6, 0x4BFF, 0x2175, 0x0000,
+    0x53FF, 0x4A5F, 0x7E52, 0x0000, 0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0, 0x03ED, 0x7FFF, 0x255F, 0x0000,
+    0x036A, 0x021F, 0x03FF, 0x7FFF, 0x7FFF, 0x01DF, 0x0112, 0x0000, 0x231F, 0x035F, 0x00F2, 0x0009,
+    0x7FFF, 0x03EA, 0x011F, 0x0000, 0x299F, 0x001A, 0x000C, 0x0000, 0x7FFF, 0x027F, 0x001F, 0x0000,
+    0x7FFF, 0x03E0, 0x0206, 0x0120, 0x7FFF, 0x7EEB, 0x001F, 0x7C00, 0x7FFF, 0x3FFF, 0x7E00, 0x001F,
+    0x7FFF, 0x03FF, 0x001F, 0x0000, 0x03FF, 0x001F, 0x000C, 0x0000, 0x7FFF, 0x033F, 0x0193, 0x0000,
+    0x0000, 0x4200, 0x037F, 0x7FFF, 0x7FFF, 0x7E8C, 0x7C00, 0x0000, 0x7FFF, 0x1BEF, 0x6180, 0x0000,
+];
+
+const TITLE_START: usize = 0x0134;
+const TITLE_END: usize = 0x0143;
+const NEW_LICENSEE_CODE: usize = 0x0144;
+const OLD_LICENSEE_CODE: usize = 0x014B;
+
+pub fn for_cartridge(cart: &Cartridge) -> DmgColours {
+    if cart.rom.len() <= OLD_LICENSEE_CODE || !published_by_nintendo(&cart.rom) {
+        return combination_colours(0);
+    }
+    let title = &cart.rom[TITLE_START..=TITLE_END];
+    let checksum = title.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    let fourth_letter = title[3];
+    let index = TITLE_CHECKSUMS
+        .iter()
+        .enumerate()
+        .position(|(index, &entry)| {
+            entry == checksum
+                && (index < FIRST_AMBIGUOUS_CHECKSUM
+                    || FOURTH_LETTERS[index - FIRST_AMBIGUOUS_CHECKSUM] == fourth_letter)
+        })
+        .unwrap_or(0);
+    combination_colours(PALETTE_PER_CHECKSUM[index] as usize)
+}
+
+fn combination_colours(combination: usize) -> DmgColours {
+    let [first_sprite, second_sprite, bg] = PALETTE_COMBINATIONS[combination];
+    DmgColours {
+        bg: shade_colours(bg),
+        sprites: [shade_colours(first_sprite), shade_colours(second_sprite)],
+    }
+}
+
+fn shade_colours(first_colour: usize) -> ShadeColours {
+    let mut colours = [0; 4];
+    for (i, colour) in colours.iter_mut().enumerate() {
+        *colour = colour_from_bgr555(PALETTE_COLOURS[first_colour + i]);
+    }
+    colours
+}
+
+// The boot ROM only looks up games published by Nintendo.
+fn published_by_nintendo(rom: &[u8]) -> bool {
+    match rom[OLD_LICENSEE_CODE] {
+        0x33 => &rom[NEW_LICENSEE_CODE..NEW_LICENSEE_CODE + 2] == b"01",
+        code => code == 0x01,
+    }
+}
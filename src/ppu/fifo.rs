@@ -0,0 +1,530 @@
+use super::oam::Sprite;
+use super::palette::PixelValue;
+use super::tile::TileAttributes;
+use super::{BackgroundPixel, LCD_WIDTH, PPU, TILES_PER_BANK};
+use crate::memory::VRAM_SIZE;
+use crate::snapshot::{Snapshot, StateReader, StateWriter};
+use std::collections::VecDeque;
+
+// Draws a line a dot at a time the way the hardware does, so that registers changed during pixel
+// transfer only affect the pixels after the change, and the transfer takes longer for fine
+// scrolling, the window and sprites.
+//
+// A fetcher reads 8 pixels of the background or window at a time, spending two dots on each of
+// the tile number and its two bytes of data, then pushes them into the background FIFO once it's
+// empty. Every dot shifts a pixel out of the FIFO onto the screen, mixed with any sprite pixel
+// shifted out of the sprite FIFO alongside it. Shifting stops whenever a sprite starts at the
+// next pixel, until its row has been fetched into the sprite FIFO.
+const FETCH_STEP_DOTS: u8 = 2;
+// The first tile of each line is fetched twice, which holds back the first pixel.
+const FIRST_FETCH_DOTS: u8 = 6;
+const SPRITE_FETCH_DOTS: u8 = 6;
+const TILE_WIDTH: i16 = 8;
+const TILES_PER_ROW: usize = 0x20;
+const NO_SPRITE: u8 = 0xFF;
+const NO_TILE: u16 = 0xFFFF;
+// Set in the tiles the sprite penalty has been counted for, to tell the window's from the BG's.
+const WINDOW_TILE: u16 = 0x100;
+
+#[derive(Copy, Clone, PartialEq)]
+enum FetchStep {
+    TileNumber,
+    DataLow,
+    DataHigh,
+    // Waiting for the background FIFO to empty.
+    Push,
+}
+
+#[derive(Copy, Clone)]
+struct FifoPixel {
+    value: PixelValue,
+    // The CGB attributes of a background pixel, or which of the line's sprites a sprite pixel is
+    // from.
+    attributes: u8,
+}
+
+pub(super) struct PixelFifo {
+    dots: u16,
+    // The next column to draw.
+    x: u8,
+    // Pixels still to throw away at the start of the line for fine scrolling.
+    discard: u8,
+    delay: u8,
+    background: VecDeque<FifoPixel>,
+    sprite_pixels: VecDeque<FifoPixel>,
+    step: FetchStep,
+    step_dots: u8,
+    // Counts tiles along from the left of the BG as scrolled, or of the window.
+    tile_column: u8,
+    tile_number: u8,
+    tile_attributes: u8,
+    tile_row: [PixelValue; 8],
+    window: bool,
+    // Where the window's left edge is on screen, which is off the left for WX below 7.
+    window_start: i16,
+    // The sprites found by the OAM scan, and which of them have been fetched.
+    sprites: Vec<Sprite>,
+    fetched_sprites: u16,
+    fetching_sprite: u8,
+    sprite_fetch_dots: u8,
+    // Sprites in the same tile after the first wait less for the fetcher.
+    penalised_tile: u16,
+    line: [u32; LCD_WIDTH as usize],
+}
+
+impl Default for PixelFifo {
+    fn default() -> Self {
+        PixelFifo {
+            dots: 0,
+            x: 0,
+            discard: 0,
+            delay: 0,
+            background: VecDeque::new(),
+            sprite_pixels: VecDeque::new(),
+            step: FetchStep::TileNumber,
+            step_dots: 0,
+            tile_column: 0,
+            tile_number: 0,
+            tile_attributes: 0,
+            tile_row: [PixelValue::Zero; 8],
+            window: false,
+            window_start: 0,
+            sprites: Vec::new(),
+            fetched_sprites: 0,
+            fetching_sprite: NO_SPRITE,
+            sprite_fetch_dots: 0,
+            penalised_tile: NO_TILE,
+            line: [0; LCD_WIDTH as usize],
+        }
+    }
+}
+
+impl PixelFifo {
+    // Starts pixel transfer for the PPU's current line, with the sprites the OAM scan finds.
+    pub fn start_line(&mut self, ppu: &PPU) {
+        let sprite_height = if ppu.lcd_control.tall_sprites { 16 } else { 8 };
+        self.dots = 0;
+        self.x = 0;
+        self.discard = ppu.scroll.horiz % 8;
+        self.delay = FIRST_FETCH_DOTS;
+        self.background.clear();
+        self.sprite_pixels.clear();
+        self.step = FetchStep::TileNumber;
+        self.step_dots = 0;
+        self.tile_column = 0;
+        self.window = false;
+        self.sprites = ppu.oam.sprites_on_line(ppu.line, sprite_height);
+        self.fetched_sprites = 0;
+        self.fetching_sprite = NO_SPRITE;
+        self.sprite_fetch_dots = 0;
+        self.penalised_tile = NO_TILE;
+    }
+
+    // Runs until the dots taken reach the given count or the line is finished, returning whether
+    // it's finished.
+    pub fn run(&mut self, ppu: &PPU, dots: u16) -> bool {
+        while self.dots < dots && !self.finished() {
+            self.dot(ppu);
+        }
+        self.finished()
+    }
+
+    pub fn finished(&self) -> bool {
+        self.x >= LCD_WIDTH
+    }
+
+    pub fn dots(&self) -> u16 {
+        self.dots
+    }
+
+    pub fn drew_window(&self) -> bool {
+        self.window
+    }
+
+    pub fn line(&self) -> &[u32] {
+        &self.line
+    }
+
+    fn dot(&mut self, ppu: &PPU) {
+        self.dots += 1;
+        if self.delay > 0 {
+            self.delay -= 1;
+            return;
+        }
+        // The window starts as the pixel at its left edge is about to be shifted out, once the
+        // first BG tile has been fetched.
+        let pixels_ready = !self.background.is_empty() || self.step == FetchStep::Push;
+        if !self.window && self.discard == 0 && pixels_ready && self.window_starts(ppu) {
+            self.start_window(ppu);
+        }
+        self.fetch(ppu);
+
+        if self.fetching_sprite == NO_SPRITE && !self.background.is_empty() {
+            if let Some(index) = self.next_sprite(ppu) {
+                self.fetching_sprite = index;
+                self.sprite_fetch_dots = SPRITE_FETCH_DOTS + self.fetcher_wait(ppu);
+            }
+        }
+        if self.fetching_sprite != NO_SPRITE {
+            self.sprite_fetch_dots = self.sprite_fetch_dots.saturating_sub(1);
+            if self.sprite_fetch_dots == 0 {
+                self.fetch_sprite(ppu, self.fetching_sprite as usize);
+                self.fetching_sprite = NO_SPRITE;
+            }
+            return;
+        }
+        self.shift_pixel(ppu);
+    }
+
+    fn window_starts(&self, ppu: &PPU) -> bool {
+        ppu.lcd_control.window_enabled
+            && (ppu.lcd_control.bg_enabled || ppu.cgb_mode)
+            && ppu.line >= ppu.window.y
+            && ppu.window.x < LCD_WIDTH + 7
+            && self.x as i16 + 7 >= ppu.window.x as i16
+    }
+
+    // Throws away what the BG fetch had so far and starts fetching the window from its left.
+    fn start_window(&mut self, ppu: &PPU) {
+        self.window = true;
+        self.window_start = ppu.window.x as i16 - 7;
+        self.discard = (self.x as i16 - self.window_start) as u8;
+        self.background.clear();
+        self.step = FetchStep::TileNumber;
+        self.step_dots = 0;
+        self.tile_column = 0;
+    }
+
+    fn fetch(&mut self, ppu: &PPU) {
+        if self.step == FetchStep::Push {
+            if self.background.is_empty() {
+                for &value in self.tile_row.iter() {
+                    self.background.push_back(FifoPixel {
+                        value,
+                        attributes: self.tile_attributes,
+                    });
+                }
+                self.tile_column = self.tile_column.wrapping_add(1);
+                self.step = FetchStep::TileNumber;
+            }
+            return;
+        }
+        self.step_dots += 1;
+        if self.step_dots < FETCH_STEP_DOTS {
+            return;
+        }
+        self.step_dots = 0;
+        self.step = match self.step {
+            FetchStep::TileNumber => {
+                self.fetch_tile_number(ppu);
+                FetchStep::DataLow
+            }
+            FetchStep::DataLow => FetchStep::DataHigh,
+            FetchStep::DataHigh | FetchStep::Push => {
+                self.fetch_tile_row(ppu);
+                FetchStep::Push
+            }
+        };
+    }
+
+    fn fetch_tile_number(&mut self, ppu: &PPU) {
+        let (tile_map, map_row, map_column) = match self.window {
+            true => (
+                ppu.tile_map(ppu.lcd_control.window_alternate_tile_map),
+                ppu.window_line as usize / 8,
+                self.tile_column as usize,
+            ),
+            false => (
+                ppu.tile_map(ppu.lcd_control.bg_alternate_tile_map),
+                ppu.line.wrapping_add(ppu.scroll.vert) as usize / 8,
+                (ppu.scroll.horiz / 8).wrapping_add(self.tile_column) as usize,
+            ),
+        };
+        let map_offset = tile_map + map_row * TILES_PER_ROW + map_column % TILES_PER_ROW;
+        self.tile_number = ppu.vram[map_offset];
+        // The CGB keeps the attributes for each tile at the same position in VRAM bank 1.
+        self.tile_attributes = match ppu.cgb_mode {
+            true => ppu.vram[VRAM_SIZE + map_offset],
+            false => 0,
+        };
+    }
+
+    fn fetch_tile_row(&mut self, ppu: &PPU) {
+        let row = match self.window {
+            true => ppu.window_line,
+            false => ppu.line.wrapping_add(ppu.scroll.vert),
+        } as usize
+            % 8;
+        let attributes = TileAttributes::from(self.tile_attributes);
+        // Tile numbers are signed and relative to 0x9000 unless unsigned tile data is selected.
+        let tile_number = self.tile_number as usize;
+        let tile_index = match ppu.lcd_control.unsigned_tile_data {
+            false if tile_number < 128 => tile_number + 256,
+            _ => tile_number,
+        };
+        for (column, value) in self.tile_row.iter_mut().enumerate() {
+            *value = ppu.tile_pixel(
+                attributes.vram_bank * TILES_PER_BANK + tile_index,
+                row,
+                column,
+                &attributes,
+            );
+        }
+    }
+
+    // The sprite that hasn't been fetched furthest left starting at or before the next pixel,
+    // going by OAM order for ties. Only sprites partly off the left of the screen are fetched
+    // after the pixel they start at, all at the start of the line, and sprites at X 0 are fetched
+    // then too even though none of them is shown.
+    fn next_sprite(&self, ppu: &PPU) -> Option<u8> {
+        if !ppu.lcd_control.sprites_enabled || self.discard > 0 {
+            return None;
+        }
+        self.sprites
+            .iter()
+            .enumerate()
+            .filter(|(index, sprite)| {
+                (self.fetched_sprites & (1 << index)) == 0
+                    && sprite.x <= self.x as i16
+                    && sprite.x >= -TILE_WIDTH
+            })
+            .min_by_key(|(_, sprite)| sprite.x)
+            .map(|(index, _)| index as u8)
+    }
+
+    // A sprite fetch waits for the BG fetch of the tile under the sprite's first pixel to get far
+    // enough along, which it has already done for later sprites in the same tile.
+    fn fetcher_wait(&mut self, ppu: &PPU) -> u8 {
+        let (tile, column) = match self.window {
+            true => {
+                let column = self.x as i16 - self.window_start;
+                (WINDOW_TILE | (column / TILE_WIDTH) as u16, column)
+            }
+            false => {
+                let column = self.x as i16 + (ppu.scroll.horiz % 8) as i16;
+                ((column / TILE_WIDTH) as u16, column)
+            }
+        };
+        if tile == self.penalised_tile {
+            return 0;
+        }
+        self.penalised_tile = tile;
+        (TILE_WIDTH - 1 - column % TILE_WIDTH - 2).max(0) as u8
+    }
+
+    // Lays the sprite's row over the sprite FIFO. Where sprites overlap the first one fetched
+    // wins on the DMG, which is the one furthest left, while the CGB goes by OAM order.
+    fn fetch_sprite(&mut self, ppu: &PPU, index: usize) {
+        self.fetched_sprites |= 1 << index;
+        let sprite = self.sprites[index];
+        let sprite_height = if ppu.lcd_control.tall_sprites { 16 } else { 8 };
+        // Only a sprite off the left of the screen has pixels that have already gone by.
+        let skip = (self.x as i16 - sprite.x).max(0) as usize;
+        for column in skip..TILE_WIDTH as usize {
+            let pixel = FifoPixel {
+                value: ppu.sprite_pixel(&sprite, ppu.line, sprite_height, column),
+                attributes: index as u8,
+            };
+            match self.sprite_pixels.get_mut(column - skip) {
+                Some(existing) => {
+                    let replaces = existing.value == PixelValue::Zero
+                        || (ppu.cgb_mode && index < existing.attributes as usize);
+                    if replaces && pixel.value != PixelValue::Zero {
+                        *existing = pixel;
+                    }
+                }
+                None => self.sprite_pixels.push_back(pixel),
+            }
+        }
+    }
+
+    fn shift_pixel(&mut self, ppu: &PPU) {
+        let background = match self.background.pop_front() {
+            Some(background) => background,
+            None => return,
+        };
+        if self.discard > 0 {
+            self.discard -= 1;
+            return;
+        }
+        let sprite_pixel = self.sprite_pixels.pop_front();
+
+        // On the DMG the BG enable bit blanks both the BG and the window.
+        let bg_visible = ppu.lcd_control.bg_enabled || ppu.cgb_mode;
+        let attributes = TileAttributes::from(background.attributes);
+        let background = BackgroundPixel {
+            value: match bg_visible {
+                true => background.value,
+                false => PixelValue::Zero,
+            },
+            priority: attributes.priority,
+        };
+        let mut colour = ppu.background_colour(&attributes, &background.value);
+        if let Some(sprite_pixel) = sprite_pixel {
+            let sprite = &self.sprites[sprite_pixel.attributes as usize];
+            if ppu.lcd_control.sprites_enabled
+                && sprite_pixel.value != PixelValue::Zero
+                && !ppu.background_wins(&background, sprite)
+            {
+                colour = ppu.sprite_colour(sprite, &sprite_pixel.value);
+            }
+        }
+        self.line[self.x as usize] = colour;
+        self.x += 1;
+    }
+}
+
+impl Snapshot for PixelFifo {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u16(self.dots);
+        state.u8(self.x);
+        state.u8(self.discard);
+        state.u8(self.delay);
+        for pixels in [&self.background, &self.sprite_pixels].iter() {
+            state.usize(pixels.len());
+            for pixel in pixels.iter() {
+                state.u8(pixel.value.index() as u8);
+                state.u8(pixel.attributes);
+            }
+        }
+        state.u8(match self.step {
+            FetchStep::TileNumber => 0,
+            FetchStep::DataLow => 1,
+            FetchStep::DataHigh => 2,
+            FetchStep::Push => 3,
+        });
+        state.u8(self.step_dots);
+        state.u8(self.tile_column);
+        state.u8(self.tile_number);
+        state.u8(self.tile_attributes);
+        for value in self.tile_row.iter() {
+            state.u8(value.index() as u8);
+        }
+        state.bool(self.window);
+        state.u16(self.window_start as u16);
+        state.usize(self.sprites.len());
+        for sprite in self.sprites.iter() {
+            state.bytes(&<[u8; 4]>::from(sprite));
+        }
+        state.u16(self.fetched_sprites);
+        state.u8(self.fetching_sprite);
+        state.u8(self.sprite_fetch_dots);
+        state.u16(self.penalised_tile);
+        if state.includes_output() {
+            for pixel in self.line.iter() {
+                state.u32(*pixel);
+            }
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) {
+        self.dots = state.u16();
+        self.x = state.u8().min(LCD_WIDTH);
+        self.discard = state.u8();
+        self.delay = state.u8();
+        for pixels in [&mut self.background, &mut self.sprite_pixels].iter_mut() {
+            let length = state.usize().min(16);
+            pixels.clear();
+            for _ in 0..length {
+                let value = PixelValue::from(state.u8());
+                let attributes = state.u8();
+                pixels.push_back(FifoPixel { value, attributes });
+            }
+        }
+        self.step = match state.u8() {
+            0 => FetchStep::TileNumber,
+            1 => FetchStep::DataLow,
+            2 => FetchStep::DataHigh,
+            _ => FetchStep::Push,
+        };
+        self.step_dots = state.u8();
+        self.tile_column = state.u8();
+        self.tile_number = state.u8();
+        self.tile_attributes = state.u8();
+        for value in self.tile_row.iter_mut() {
+            *value = PixelValue::from(state.u8());
+        }
+        self.window = state.bool();
+        self.window_start = state.u16() as i16;
+        let sprites = state.usize().min(10);
+        self.sprites.clear();
+        for _ in 0..sprites {
+            let mut entry = [0; 4];
+            state.bytes(&mut entry);
+            self.sprites.push(Sprite::from(entry));
+        }
+        self.fetched_sprites = state.u16();
+        self.fetching_sprite = state.u8();
+        if self.fetching_sprite as usize >= self.sprites.len() {
+            self.fetching_sprite = NO_SPRITE;
+        }
+        self.sprite_fetch_dots = state.u8();
+        self.penalised_tile = state.u16();
+        for pixel in self.line.iter_mut() {
+            *pixel = state.u32();
+        }
+        // Sprite pixels refer to the sprites by index.
+        let sprite_count = self.sprites.len() as u8;
+        self.sprite_pixels
+            .retain(|pixel| pixel.attributes < sprite_count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Model;
+
+    // Fine scrolling, the window starting partway along and a sprite, with registers left alone
+    // during the line so that both renderers should draw the same pixels.
+    fn render(pixel_fifo: bool) -> Vec<u32> {
+        let mut ppu = PPU::new(Model::DMG);
+        if pixel_fifo {
+            ppu.use_pixel_fifo();
+        }
+        for row in 0..8 {
+            ppu.write_vram(0x5A, 0x10 + row * 2);
+            ppu.write_vram(0x3C, 0x10 + row * 2 + 1);
+            ppu.write_vram(0xF0, 0x20 + row * 2);
+            ppu.write_vram(0x0F << (row % 4), 0x20 + row * 2 + 1);
+            ppu.write_vram(0x81, 0x30 + row * 2);
+            ppu.write_vram(0xFF, 0x30 + row * 2 + 1);
+        }
+        for tile in 0..0x400 {
+            ppu.write_vram((tile % 3 != 0) as u8, 0x1800 + tile);
+            ppu.write_vram(2, 0x1C00 + tile);
+        }
+        let sprite = [16, 8 + 37, 3, 0];
+        for (offset, &value) in sprite.iter().enumerate() {
+            ppu.write_oam(value, offset);
+        }
+        ppu.write_io_register(3, 0xFF43);
+        ppu.write_io_register(0, 0xFF4A);
+        ppu.write_io_register(7 + 80, 0xFF4B);
+        ppu.write_io_register(0xE4, 0xFF47);
+        ppu.write_io_register(0xD2, 0xFF48);
+        ppu.write_io_register(0xF3, 0xFF40);
+        // Enough for the first 8 lines to be drawn.
+        for _ in 0..(456 * 9 / 4) {
+            ppu.step(4, 4);
+        }
+        ppu.framebuffer[..LCD_WIDTH as usize * 8].to_vec()
+    }
+
+    #[test]
+    fn draws_the_same_line_as_the_line_renderer() {
+        let line_renderer = render(false);
+        let pixel_fifo = render(true);
+        for (line, (expected, drawn)) in line_renderer
+            .chunks(LCD_WIDTH as usize)
+            .zip(pixel_fifo.chunks(LCD_WIDTH as usize))
+            .enumerate()
+        {
+            assert_eq!(expected, drawn, "line {}", line);
+        }
+        // Check the scene isn't blank, so that the comparison means something.
+        let first_line = &line_renderer[..LCD_WIDTH as usize];
+        assert!(first_line[..80].iter().any(|&pixel| pixel != first_line[0]));
+        assert_ne!(first_line[40], first_line[45]);
+    }
+}
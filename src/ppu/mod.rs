@@ -1,25 +1,70 @@
+pub mod colour_schemes;
+pub mod compat_palettes;
+mod fifo;
 mod palette;
 mod tile;
 mod oam;
+mod sgb;
 
 use crate::cpu::interrupts::{Interrupt, InterruptsToSet};
 use crate::memory::{VRAM_BEGIN, VRAM_SIZE};
+use crate::model::Model;
+use crate::snapshot::{Snapshot, StateReader, StateWriter};
+use crate::utils::triple_buffer::{triple_buffer, TripleBufferReader, TripleBufferWriter};
+use fifo::PixelFifo;
+use oam::{ObjectAttributeMemory, Sprite};
 use palette::*;
-use std::collections::HashMap;
+use sgb::{SuperGameBoy, SGB_HEIGHT, SGB_WIDTH};
 use std::sync::{Arc, Mutex};
-use tile::Tile;
+use tile::{Tile, TileAttributes};
+
+pub use oam::OAM_SIZE;
 
 pub struct PPU {
-    vram: [u8; VRAM_SIZE],
-    tile_set: [Tile; 384],
+    // Whether the CGB features are in use, which they are not for DMG games on a CGB.
+    cgb_mode: bool,
+    vram: [u8; VRAM_SIZE * VRAM_BANKS],
+    vram_bank: usize,
+    tile_set: [Tile; TILES_PER_BANK * VRAM_BANKS],
     mode: PPUMode,
     cycles: u16,
+    // How long pixel transfer took on this line, which H-Blank makes up to the full line.
+    transfer_cycles: u16,
+    entered_hblank: bool,
+    // The CPU writes in the last machine cycle of an instruction, so register writes made while
+    // an instruction runs are held until the PPU has caught up to that cycle. They are always
+    // applied by the end of the step, so they aren't part of the saved state.
+    holding_writes: bool,
+    held_writes: Vec<(usize, u8)>,
     line: Line,
+    lcd_control: LcdControl,
+    // The STAT interrupt sources selected, as bits 3 to 6 of STAT.
+    status_interrupts: u8,
+    line_compare: u8,
+    // The STAT interrupt fires as any selected source starts, but not while another still holds.
+    status_line: bool,
+    // The LY=LYC flag stops updating while the LCD is off, keeping the value it had.
+    latched_coincidence: bool,
     scroll: Scroll,
+    window: WindowPosition,
     palette: Palette,
-    lines_to_render: LinesToRender,
+    sprite_palettes: [Palette; 2],
+    bg_colour_palettes: ColourPaletteRam,
+    sprite_colour_palettes: ColourPaletteRam,
+    dmg_colours: DmgColours,
+    pub next_dmg_colours: Arc<Mutex<Option<DmgColours>>>,
+    oam: ObjectAttributeMemory,
+    sgb: Option<SuperGameBoy>,
+    // The lines of the window drawn so far this frame, which is the next line of it to draw.
+    window_line: u8,
+    // Draws each line a pixel at a time during pixel transfer when set, rather than all at once
+    // at the end of it.
+    pixel_fifo: Option<PixelFifo>,
+    // The frame being drawn, a line at a time as each finishes pixel transfer.
     framebuffer: Framebuffer,
-    pub displayable_framebuffer: Arc<Mutex<Framebuffer>>,
+    // Completed frames as they're shown, with the border in SGB mode.
+    displayable_framebuffer: TripleBufferWriter<Framebuffer>,
+    displayable_framebuffer_reader: Option<TripleBufferReader<Framebuffer>>,
 }
 
 enum PPUMode {
@@ -30,8 +75,19 @@ enum PPUMode {
 }
 
 const BG_MAP_START: usize = 0x9800;
+const BG_MAP_ALTERNATE_START: usize = 0x9C00;
+const VRAM_BANKS: usize = 2;
+const TILES_PER_BANK: usize = 384;
 pub const LCD_WIDTH: u8 = 160;
 pub const LCD_HEIGHT: u8 = 144;
+const LINES_PER_FRAME: u8 = 154;
+const CYCLES_PER_LINE: u16 = 456;
+const OAM_SCAN_CYCLES: u16 = 80;
+// Without fine scrolling, the window or sprites.
+const PIXEL_TRANSFER_CYCLES: u16 = 172;
+// Refetching the first tile when the window starts.
+const WINDOW_START_CYCLES: u16 = 6;
+const SPRITE_FETCH_CYCLES: u16 = 6;
 
 type Line = u8;
 type Framebuffer = Vec<u32>;
@@ -42,74 +98,304 @@ struct Scroll {
     vert: u8,
 }
 
-struct LinesToRender {
-    pub jobs: HashMap<Line, Scroll>,
+#[derive(Copy, Clone)]
+struct WindowPosition {
+    x: u8,
+    y: u8,
+}
+
+#[derive(Copy, Clone)]
+struct LcdControl {
+    display_enabled: bool,
+    window_alternate_tile_map: bool,
+    window_enabled: bool,
+    unsigned_tile_data: bool,
+    bg_alternate_tile_map: bool,
+    tall_sprites: bool,
+    sprites_enabled: bool,
+    // On the CGB this instead lets the BG and window take priority over sprites.
+    bg_enabled: bool,
+}
+
+impl From<u8> for LcdControl {
+    fn from(value: u8) -> Self {
+        LcdControl {
+            display_enabled: (value & (1 << 7)) != 0,
+            window_alternate_tile_map: (value & (1 << 6)) != 0,
+            window_enabled: (value & (1 << 5)) != 0,
+            unsigned_tile_data: (value & (1 << 4)) != 0,
+            bg_alternate_tile_map: (value & (1 << 3)) != 0,
+            tall_sprites: (value & (1 << 2)) != 0,
+            sprites_enabled: (value & (1 << 1)) != 0,
+            bg_enabled: (value & 1) != 0,
+        }
+    }
+}
+
+impl From<&LcdControl> for u8 {
+    fn from(lcd_control: &LcdControl) -> u8 {
+        (lcd_control.display_enabled as u8) << 7
+            | (lcd_control.window_alternate_tile_map as u8) << 6
+            | (lcd_control.window_enabled as u8) << 5
+            | (lcd_control.unsigned_tile_data as u8) << 4
+            | (lcd_control.bg_alternate_tile_map as u8) << 3
+            | (lcd_control.tall_sprites as u8) << 2
+            | (lcd_control.sprites_enabled as u8) << 1
+            | (lcd_control.bg_enabled as u8)
+    }
+}
+
+// What a sprite pixel needs to know about the BG/window pixel underneath it.
+#[derive(Copy, Clone)]
+struct BackgroundPixel {
+    value: PixelValue,
+    priority: bool,
 }
 
 impl PPU {
-    pub fn new() -> Self {
+    pub fn new(model: Model) -> Self {
+        let (sgb, dmg_colours) = match model {
+            Model::SGB => (
+                Some(SuperGameBoy::default()),
+                DmgColours::uniform(sgb::SHADE_NUMBERS),
+            ),
+            _ => (None, Default::default()),
+        };
+        let (output_width, output_height) = match model {
+            Model::SGB => (SGB_WIDTH, SGB_HEIGHT),
+            _ => (LCD_WIDTH as usize, LCD_HEIGHT as usize),
+        };
+        let (displayable_framebuffer, displayable_framebuffer_reader) =
+            triple_buffer(vec![0; output_width * output_height]);
         PPU {
-            vram: [0; VRAM_SIZE],
-            tile_set: [Tile::empty_tile(); 384],
+            cgb_mode: model.is_colour(),
+            vram: [0; VRAM_SIZE * VRAM_BANKS],
+            vram_bank: 0,
+            tile_set: [Tile::empty_tile(); TILES_PER_BANK * VRAM_BANKS],
             mode: PPUMode::HBlank,
             cycles: 0,
+            transfer_cycles: PIXEL_TRANSFER_CYCLES,
+            entered_hblank: false,
+            holding_writes: false,
+            held_writes: Vec::new(),
             line: 0,
+            lcd_control: LcdControl::from(0),
+            status_interrupts: 0,
+            line_compare: 0,
+            status_line: false,
+            latched_coincidence: false,
             scroll: Scroll { horiz: 0, vert: 0 },
+            window: WindowPosition { x: 0, y: 0 },
             palette: Palette::default(),
-            lines_to_render: LinesToRender {
-                jobs: Default::default(),
-            },
+            sprite_palettes: [Palette::default(), Palette::default()],
+            bg_colour_palettes: Default::default(),
+            sprite_colour_palettes: Default::default(),
+            dmg_colours,
+            next_dmg_colours: Arc::new(Mutex::new(None)),
+            oam: Default::default(),
+            sgb,
+            window_line: 0,
+            pixel_fifo: None,
             framebuffer: vec![0; LCD_WIDTH as usize * LCD_HEIGHT as usize],
-            displayable_framebuffer: Arc::new(Mutex::new(vec![
-                0;
-                LCD_WIDTH as usize
-                    * LCD_HEIGHT as usize
-            ])),
+            displayable_framebuffer,
+            displayable_framebuffer_reader: Some(displayable_framebuffer_reader),
         }
     }
 
     pub fn supports_io_register(&self, address: usize) -> bool {
-        matches!(address, 0xFF42 | 0xFF43 | 0xFF44 | 0xFF47)
+        match address {
+            0xFF40..=0xFF45 | 0xFF47..=0xFF4B => true,
+            0xFF4F | 0xFF68..=0xFF6B => self.cgb_mode,
+            _ => false,
+        }
     }
 
     pub fn read_io_register(&self, address: usize) -> u8 {
         match address {
+            0xFF40 => u8::from(&self.lcd_control),
+            0xFF41 => {
+                let (coincidence, mode) = match self.lcd_control.display_enabled {
+                    true => (self.line == self.line_compare, self.mode_number()),
+                    false => (self.latched_coincidence, 0),
+                };
+                0x80 | self.status_interrupts | (coincidence as u8) << 2 | mode
+            }
             0xFF42 => self.scroll.vert,
             0xFF43 => self.scroll.horiz,
             0xFF44 => self.line,
+            0xFF45 => self.line_compare,
             0xFF47 => u8::from(&self.palette),
+            0xFF48 => u8::from(&self.sprite_palettes[0]),
+            0xFF49 => u8::from(&self.sprite_palettes[1]),
+            0xFF4A => self.window.y,
+            0xFF4B => self.window.x,
+            0xFF4F => 0xFE | self.vram_bank as u8,
+            0xFF68 => self.bg_colour_palettes.read_specification(),
+            0xFF69 => self.bg_colour_palettes.read_data(),
+            0xFF6A => self.sprite_colour_palettes.read_specification(),
+            0xFF6B => self.sprite_colour_palettes.read_data(),
             _ => panic!("Trying to read unknown IO register related to PPU!"),
         }
     }
 
     pub fn write_io_register(&mut self, value: u8, address: usize) {
+        if self.holding_writes {
+            self.held_writes.push((address, value));
+            return;
+        }
         match address {
+            0xFF40 => self.write_lcd_control(value),
+            0xFF41 => self.status_interrupts = value & 0x78,
             0xFF42 => self.scroll.vert = value,
             0xFF43 => self.scroll.horiz = value,
+            0xFF44 => {}
+            0xFF45 => self.line_compare = value,
             0xFF47 => self.palette = Palette::from(value),
+            0xFF48 => self.sprite_palettes[0] = Palette::from(value),
+            0xFF49 => self.sprite_palettes[1] = Palette::from(value),
+            0xFF4A => self.window.y = value,
+            0xFF4B => self.window.x = value,
+            0xFF4F => self.vram_bank = (value & 1) as usize,
+            0xFF68 => self.bg_colour_palettes.write_specification(value),
+            0xFF69 => self.bg_colour_palettes.write_data(value),
+            0xFF6A => self.sprite_colour_palettes.write_specification(value),
+            0xFF6B => self.sprite_colour_palettes.write_data(value),
             _ => panic!("Trying to write to unknown IO register in PPU!"),
         }
     }
 
+    fn write_lcd_control(&mut self, value: u8) {
+        let lcd_control = LcdControl::from(value);
+        // Turning the display off resets LY, and it starts again from the top when turned back on.
+        if self.lcd_control.display_enabled && !lcd_control.display_enabled {
+            self.latched_coincidence = self.line == self.line_compare;
+            self.line = 0;
+            self.cycles = 0;
+            self.mode = PPUMode::HBlank;
+            self.window_line = 0;
+            self.apply_next_dmg_colours();
+            self.publish_blank_frame();
+        }
+        if !self.lcd_control.display_enabled && lcd_control.display_enabled {
+            self.line = 0;
+            self.cycles = 0;
+            self.mode = PPUMode::OAMAccess;
+            // A line the FIFO was drawing when the display went off is abandoned.
+            if let Some(pixel_fifo) = &mut self.pixel_fifo {
+                *pixel_fifo = PixelFifo::default();
+            }
+        }
+        self.lcd_control = lcd_control;
+    }
+
+    // Set as the CGB boot ROM hands over to a DMG game.
+    pub fn enter_dmg_compatibility(&mut self) {
+        self.cgb_mode = false;
+        self.vram_bank = 0;
+    }
+
+    // The SGB colours the screen itself, so DMG colours are ignored in SGB mode.
+    pub fn set_dmg_colours(&mut self, colours: DmgColours) {
+        if self.sgb.is_none() {
+            self.dmg_colours = colours;
+        }
+    }
+
+    // Draws with a pixel FIFO like the hardware, which is slower than drawing whole lines but
+    // shows changes made partway through a line.
+    pub fn use_pixel_fifo(&mut self) {
+        let mut pixel_fifo = PixelFifo::default();
+        if let PPUMode::VRAMAccess = self.mode {
+            pixel_fifo.start_line(self);
+        }
+        self.pixel_fifo = Some(pixel_fifo);
+    }
+
+    // The size of the displayable framebuffer, which in SGB mode includes the border.
+    pub fn output_size(&self) -> (usize, usize) {
+        match self.sgb {
+            Some(_) => (SGB_WIDTH, SGB_HEIGHT),
+            None => (LCD_WIDTH as usize, LCD_HEIGHT as usize),
+        }
+    }
+
+    // The frontend's end of the completed frames, which there is only one of.
+    pub fn take_displayable_framebuffer(&mut self) -> Option<TripleBufferReader<Framebuffer>> {
+        self.displayable_framebuffer_reader.take()
+    }
+
+    pub fn handle_sgb_command(&mut self, command: &[u8]) {
+        if self.sgb.is_none() {
+            return;
+        }
+        let transfer = match sgb::transfers_vram(command) {
+            true => self.displayed_tile_data(),
+            false => Vec::new(),
+        };
+        if let Some(sgb) = &mut self.sgb {
+            sgb.handle_command(command, &transfer);
+        }
+    }
+
+    // The SGB reads VRAM transfers from the screen, so the data is the tiles shown on it in
+    // order, a row of 20 tiles at a time.
+    fn displayed_tile_data(&self) -> Vec<u8> {
+        const TILES_PER_ROW: usize = 0x20;
+        const TILES_PER_LINE: usize = LCD_WIDTH as usize / 8;
+        const BYTES_PER_TILE: usize = 16;
+        let bg_map = self.tile_map(self.lcd_control.bg_alternate_tile_map);
+        let first_row = self.scroll.vert as usize / 8;
+        let first_column = self.scroll.horiz as usize / 8;
+        let mut transfer = Vec::with_capacity(sgb::TRANSFER_SIZE);
+        for tile in 0..sgb::TRANSFER_SIZE / BYTES_PER_TILE {
+            let row = (first_row + tile / TILES_PER_LINE) % TILES_PER_ROW;
+            let column = (first_column + tile % TILES_PER_LINE) % TILES_PER_ROW;
+            let tile_number = self.vram[bg_map + row * TILES_PER_ROW + column] as usize;
+            let tile_index = match self.lcd_control.unsigned_tile_data {
+                false if tile_number < 128 => tile_number + 256,
+                _ => tile_number,
+            };
+            let start = tile_index * BYTES_PER_TILE;
+            transfer.extend_from_slice(&self.vram[start..start + BYTES_PER_TILE]);
+        }
+        transfer
+    }
+
+    pub fn read_oam(&self, address: usize) -> u8 {
+        self.oam.read(address)
+    }
+
+    pub fn write_oam(&mut self, value: u8, address: usize) {
+        self.oam.write(value, address)
+    }
+
+    pub fn vram_bank(&self) -> usize {
+        self.vram_bank
+    }
+
     pub fn read_vram(&self, address: usize) -> u8 {
-        self.vram[address]
+        self.vram[self.vram_bank * VRAM_SIZE + address]
     }
 
     pub fn write_vram(&mut self, value: u8, address: usize) {
-        self.vram[address] = value;
+        let bank_offset = self.vram_bank * VRAM_SIZE;
+        self.vram[bank_offset + address] = value;
 
         // Addresses outside this range are not in the tile set.
         if address >= 0x1800 {
             return;
         }
+        self.update_tile_row(self.vram_bank, address);
+    }
 
+    fn update_tile_row(&mut self, bank: usize, address: usize) {
         // Determine the even address corresponding to this address.
-        let even_address = address & 0xFFFE;
+        let even_address = bank * VRAM_SIZE + (address & 0xFFFE);
         let byte1 = self.vram[even_address];
         let byte2 = self.vram[even_address + 1];
 
         // Each row is 16 bytes, and every 2 bytes is a new row.
-        let tile_index = address / 16;
+        let tile_index = bank * TILES_PER_BANK + address / 16;
         let row_index = (address % 16) / 2;
 
         for pixel_index in 0..8 {
@@ -126,102 +412,476 @@ impl PPU {
         }
     }
 
-    pub fn step(&mut self, cycles: u8) -> InterruptsToSet {
-        self.cycles += cycles as u16;
+    pub fn hold_register_writes(&mut self) {
+        self.holding_writes = true;
+    }
 
+    // Runs the cycles of an instruction, applying any register writes it made at the start of its
+    // last machine cycle.
+    pub fn step(&mut self, cycles: u8, machine_cycle: u8) -> InterruptsToSet {
         let mut interrupts: InterruptsToSet = Default::default();
+        self.entered_hblank = false;
+        self.holding_writes = false;
+        let cycles_before_writes = match self.held_writes.is_empty() {
+            true => cycles,
+            false => cycles.saturating_sub(machine_cycle),
+        };
+        self.run(cycles_before_writes, &mut interrupts);
+        for (address, value) in std::mem::take(&mut self.held_writes) {
+            self.write_io_register(value, address);
+        }
+        self.run(cycles - cycles_before_writes, &mut interrupts);
+        interrupts
+    }
+
+    fn run(&mut self, cycles: u8, interrupts: &mut InterruptsToSet) {
+        if !self.lcd_control.display_enabled {
+            // Colours picked while the LCD is off show on the blank screen straight away.
+            if self.apply_next_dmg_colours() {
+                self.publish_blank_frame();
+            }
+            return;
+        }
+        self.cycles += cycles as u16;
+
+        // An instruction can take long enough for the shorter modes to end partway through it.
+        while self.next_mode(interrupts) {
+            self.update_status_line(interrupts);
+        }
+        // The game may have changed which sources are selected or LYC since the last step.
+        self.update_status_line(interrupts);
+    }
+
+    // Moves on to the next mode once the current one is over, returning whether it did.
+    fn next_mode(&mut self, interrupts: &mut InterruptsToSet) -> bool {
         match self.mode {
             PPUMode::HBlank => {
-                if self.cycles >= 200 {
-                    self.cycles = self.cycles % 200;
-                    self.line += 1;
-
-                    if self.line >= LCD_HEIGHT {
-                        self.mode = PPUMode::VBlank;
-                        interrupts.set_interrupt(Interrupt::VBlank);
-                    } else {
-                        self.mode = PPUMode::OAMAccess;
-                    }
+                let hblank_cycles = CYCLES_PER_LINE - OAM_SCAN_CYCLES - self.transfer_cycles;
+                if self.cycles < hblank_cycles {
+                    return false;
+                }
+                self.cycles -= hblank_cycles;
+                self.line += 1;
+
+                if self.line >= LCD_HEIGHT {
+                    self.mode = PPUMode::VBlank;
+                    interrupts.set_interrupt(Interrupt::VBlank);
+                    self.finish_frame();
+                } else {
+                    self.mode = PPUMode::OAMAccess;
                 }
             }
             PPUMode::VBlank => {
-                if self.cycles >= 456 {
-                    self.cycles = self.cycles % 456;
-                    self.line += 1;
+                if self.cycles < CYCLES_PER_LINE {
+                    return false;
+                }
+                self.cycles -= CYCLES_PER_LINE;
+                self.line += 1;
 
-                    if self.line == 154 {
-                        self.mode = PPUMode::OAMAccess;
-                        self.line = 0;
-                    }
+                if self.line == LINES_PER_FRAME {
+                    self.mode = PPUMode::OAMAccess;
+                    self.line = 0;
                 }
             }
             PPUMode::OAMAccess => {
-                if self.cycles >= 80 {
-                    self.cycles = self.cycles % 80;
-                    self.mode = PPUMode::VRAMAccess;
+                if self.cycles < OAM_SCAN_CYCLES {
+                    return false;
+                }
+                self.cycles -= OAM_SCAN_CYCLES;
+                self.mode = PPUMode::VRAMAccess;
+                match self.pixel_fifo.take() {
+                    Some(mut pixel_fifo) => {
+                        pixel_fifo.start_line(self);
+                        self.pixel_fifo = Some(pixel_fifo);
+                    }
+                    None => self.transfer_cycles = self.pixel_transfer_cycles(),
                 }
             }
             PPUMode::VRAMAccess => {
-                if self.cycles >= 172 {
-                    self.cycles = self.cycles % 172;
-                    self.mode = PPUMode::HBlank;
-                    self.queue_current_line_to_render();
+                if !self.run_pixel_transfer() {
+                    return false;
                 }
+                self.cycles -= self.transfer_cycles;
+                self.mode = PPUMode::HBlank;
+                self.entered_hblank = true;
             }
         }
+        true
+    }
 
-        interrupts
+    // Returns whether the current line has been drawn, which with the pixel FIFO can take longer
+    // than the usual time.
+    fn run_pixel_transfer(&mut self) -> bool {
+        let mut pixel_fifo = match self.pixel_fifo.take() {
+            Some(pixel_fifo) => pixel_fifo,
+            None => {
+                if self.cycles < self.transfer_cycles {
+                    return false;
+                }
+                self.render_current_line();
+                return true;
+            }
+        };
+        let finished = pixel_fifo.run(self, self.cycles);
+        if finished {
+            self.transfer_cycles = pixel_fifo.dots();
+            let pixel_offset = (self.line as usize) * (LCD_WIDTH as usize);
+            self.framebuffer[pixel_offset..(pixel_offset + LCD_WIDTH as usize)]
+                .copy_from_slice(pixel_fifo.line());
+            if pixel_fifo.drew_window() {
+                self.window_line += 1;
+            }
+        }
+        self.pixel_fifo = Some(pixel_fifo);
+        finished
+    }
+
+    // How long the pixel FIFO would take to draw the line with the registers and sprites as they
+    // are at the start of pixel transfer, for the line renderer to take the same time. This
+    // follows the penalties in the Pan Docs, which the pixel FIFO gets from fetching.
+    fn pixel_transfer_cycles(&self) -> u16 {
+        // The pixels scrolled off the left are shifted out and thrown away first.
+        let fine_scroll = (self.scroll.horiz % 8) as i16;
+        let mut cycles = PIXEL_TRANSFER_CYCLES + fine_scroll as u16;
+        let window_start = match self.window_shown() {
+            true => {
+                // A window starting off the left of the screen has the pixels there thrown away
+                // too.
+                let window_start = self.window.x as i16 - 7;
+                cycles += WINDOW_START_CYCLES + (-window_start).max(0) as u16;
+                Some(window_start)
+            }
+            false => None,
+        };
+        if !self.lcd_control.sprites_enabled {
+            return cycles;
+        }
+
+        let sprite_height = if self.lcd_control.tall_sprites { 16 } else { 8 };
+        let mut sprites = self.oam.sprites_on_line(self.line, sprite_height);
+        sprites.retain(|sprite| sprite.x >= -8 && sprite.x < LCD_WIDTH as i16);
+        sprites.sort_by_key(|sprite| sprite.x);
+        // Each sprite waits for the fetch of the tile under its first pixel to get far enough
+        // along, which only the first sprite in a tile has to do. Sprites partly off the left of
+        // the screen are all fetched at the first pixel.
+        let mut penalised_tile = None;
+        for sprite in sprites.iter() {
+            let x = sprite.x.max(0);
+            // Window tiles are numbered apart from the BG's.
+            let (tile, column) = match window_start {
+                Some(window_start) if x >= window_start => {
+                    ((x - window_start) / 8 + 0x100, x - window_start)
+                }
+                _ => ((x + fine_scroll) / 8, x + fine_scroll),
+            };
+            if penalised_tile != Some(tile) {
+                penalised_tile = Some(tile);
+                cycles += (7 - column % 8 - 2).max(0) as u16;
+            }
+            cycles += SPRITE_FETCH_CYCLES;
+        }
+        cycles
     }
 
-    fn queue_current_line_to_render(&mut self) {
-        if self.line < LCD_HEIGHT {
-            self.lines_to_render.jobs.insert(self.line, self.scroll);
+    fn update_status_line(&mut self, interrupts: &mut InterruptsToSet) {
+        let mode_selected = match self.mode {
+            PPUMode::HBlank => (self.status_interrupts & (1 << 3)) != 0,
+            PPUMode::VBlank => (self.status_interrupts & (1 << 4)) != 0,
+            PPUMode::OAMAccess => (self.status_interrupts & (1 << 5)) != 0,
+            PPUMode::VRAMAccess => false,
+        };
+        let line_selected =
+            (self.status_interrupts & (1 << 6)) != 0 && self.line == self.line_compare;
+        let status_line = mode_selected || line_selected;
+        if status_line && !self.status_line {
+            interrupts.set_interrupt(Interrupt::LCDStat);
         }
+        self.status_line = status_line;
     }
 
-    pub fn render(&mut self) {
-        let mut current_framebuffer = self.framebuffer.clone();
-        for (line, scroll) in self.lines_to_render.jobs.iter() {
-            let rendered_line = self.render_line(*line, *scroll);
-            let pixel_offset = (*line as usize) * (LCD_WIDTH as usize);
-            let current_rendered_line = &mut current_framebuffer.as_mut_slice()
-                [pixel_offset..(pixel_offset + LCD_WIDTH as usize)];
-            current_rendered_line.clone_from_slice(&rendered_line);
+    fn mode_number(&self) -> u8 {
+        match self.mode {
+            PPUMode::HBlank => 0,
+            PPUMode::VBlank => 1,
+            PPUMode::OAMAccess => 2,
+            PPUMode::VRAMAccess => 3,
         }
-        self.framebuffer = current_framebuffer;
-        self.lines_to_render.jobs.clear();
+    }
+
+    // Whether the last step moved from pixel transfer into H-Blank, which triggers H-Blank DMA.
+    pub fn entered_hblank(&self) -> bool {
+        self.entered_hblank
+    }
 
-        // Only try to lock here. If we fail, we will drop the frame as opposed to messing up our audio stream.
-        let displayable_framebuffer = self.displayable_framebuffer.try_lock();
-        if let Ok(mut displayable_framebuffer) = displayable_framebuffer {
-            *displayable_framebuffer = self.framebuffer.clone();
+    // Draws the line with the registers as they are when pixel transfer ends, so that changes
+    // made between lines show from the next one.
+    fn render_current_line(&mut self) {
+        if self.line >= LCD_HEIGHT {
+            return;
+        }
+        // The window only moves on to its next line when it's drawn, so hiding it for some lines
+        // carries on from where it left off rather than skipping ahead.
+        let window_shown = self.window_shown();
+        let window_line = match window_shown {
+            true => Some(self.window_line),
+            false => None,
+        };
+        let rendered_line = self.render_line(self.line, window_line);
+        let pixel_offset = (self.line as usize) * (LCD_WIDTH as usize);
+        self.framebuffer[pixel_offset..(pixel_offset + LCD_WIDTH as usize)]
+            .copy_from_slice(&rendered_line);
+        if window_shown {
+            self.window_line += 1;
         }
     }
 
-    fn render_line(&self, line: Line, scroll: Scroll) -> [u32; LCD_WIDTH as usize] {
-        const BG_OFFSET: usize = BG_MAP_START - VRAM_BEGIN;
-        const PIXEL_DIMENSION_PER_TILE: usize = 8;
-        const TILES_PER_ROW: usize = 0x20;
+    fn window_shown(&self) -> bool {
+        self.lcd_control.window_enabled
+            && (self.lcd_control.bg_enabled || self.cgb_mode)
+            && self.line >= self.window.y
+            && self.window.x < LCD_WIDTH + 7
+    }
+
+    // Hands the frame over at the start of V-Blank, when the last line has been drawn.
+    fn finish_frame(&mut self) {
+        self.window_line = 0;
+        self.apply_next_dmg_colours();
+        self.publish_framebuffer();
+    }
+
+    // The screen is blank while the LCD is off, and there are no V-Blanks to hand over frames
+    // until it's turned back on.
+    fn publish_blank_frame(&mut self) {
+        let blank = match self.cgb_mode {
+            true => 0xFFFFFF,
+            false => self.dmg_colours.bg[0],
+        };
+        self.framebuffer.iter_mut().for_each(|pixel| *pixel = blank);
+        self.publish_framebuffer();
+    }
+
+    // Returns whether there were new colours.
+    fn apply_next_dmg_colours(&mut self) -> bool {
+        let next_dmg_colours = match self.next_dmg_colours.try_lock() {
+            Ok(mut next_dmg_colours) => next_dmg_colours.take(),
+            Err(_) => None,
+        };
+        match next_dmg_colours {
+            Some(colours) => {
+                self.set_dmg_colours(colours);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Never waits on the frontend, which picks up the latest frame whenever it's ready.
+    fn publish_framebuffer(&mut self) {
+        let displayable_framebuffer = self.displayable_framebuffer.buffer();
+        match &mut self.sgb {
+            Some(sgb) => sgb.compose(&self.framebuffer, displayable_framebuffer),
+            None => displayable_framebuffer.copy_from_slice(&self.framebuffer),
+        }
+        self.displayable_framebuffer.publish();
+    }
 
-        let pixel_row = line.wrapping_add(scroll.vert);
+    fn render_line(&self, line: Line, window_line: Option<u8>) -> [u32; LCD_WIDTH as usize] {
         let mut rendered_line = [0; LCD_WIDTH as usize];
+        let mut background = [BackgroundPixel {
+            value: PixelValue::Zero,
+            priority: false,
+        }; LCD_WIDTH as usize];
 
-        for column in 0..LCD_WIDTH as u8 {
-            let pixel_column = column.wrapping_add(scroll.horiz);
-            let tile_row = (pixel_row as usize) / PIXEL_DIMENSION_PER_TILE;
-            let tile_column = (pixel_column as usize) / PIXEL_DIMENSION_PER_TILE;
-            let tile_address = BG_OFFSET + tile_row * TILES_PER_ROW + tile_column;
-            let tile_byte = self.vram[tile_address];
-            rendered_line[column as usize] =
-                self.get_pixel_colour_from_tile(tile_byte, pixel_row % 8, (pixel_column % 8) as u8);
+        // On the DMG the BG enable bit blanks both the BG and the window.
+        let bg_visible = self.lcd_control.bg_enabled || self.cgb_mode;
+        let window_map = self.tile_map(self.lcd_control.window_alternate_tile_map);
+        let bg_map = self.tile_map(self.lcd_control.bg_alternate_tile_map);
+
+        for column in 0..LCD_WIDTH {
+            // The window's left edge is at WX - 7.
+            let window_column = column as i16 - (self.window.x as i16 - 7);
+            let (tile_map, pixel_row, pixel_column) = match window_line {
+                Some(window_line) if window_column >= 0 => {
+                    (window_map, window_line, window_column as u8)
+                }
+                _ => (
+                    bg_map,
+                    line.wrapping_add(self.scroll.vert),
+                    column.wrapping_add(self.scroll.horiz),
+                ),
+            };
+            let (colour, pixel) = if bg_visible {
+                self.background_pixel(tile_map, pixel_row, pixel_column)
+            } else {
+                (
+                    self.palette
+                        .get_colour(&PixelValue::Zero, &self.dmg_colours.bg),
+                    background[column as usize],
+                )
+            };
+            rendered_line[column as usize] = colour;
+            background[column as usize] = pixel;
+        }
+
+        if self.lcd_control.sprites_enabled {
+            self.render_sprites(line, &mut rendered_line, &background);
         }
         rendered_line
     }
 
+    fn tile_map(&self, alternate: bool) -> usize {
+        let start = if alternate {
+            BG_MAP_ALTERNATE_START
+        } else {
+            BG_MAP_START
+        };
+        start - VRAM_BEGIN
+    }
+
+    fn background_pixel(&self, tile_map: usize, row: u8, column: u8) -> (u32, BackgroundPixel) {
+        const PIXEL_DIMENSION_PER_TILE: usize = 8;
+        const TILES_PER_ROW: usize = 0x20;
+
+        let map_offset = tile_map
+            + (row as usize / PIXEL_DIMENSION_PER_TILE) * TILES_PER_ROW
+            + (column as usize / PIXEL_DIMENSION_PER_TILE);
+        let tile_number = self.vram[map_offset] as usize;
+        // The CGB keeps the attributes for each tile at the same position in VRAM bank 1.
+        let attributes = match self.cgb_mode {
+            true => TileAttributes::from(self.vram[VRAM_SIZE + map_offset]),
+            false => TileAttributes::default(),
+        };
+        // Tile numbers are signed and relative to 0x9000 unless unsigned tile data is selected.
+        let tile_index = match self.lcd_control.unsigned_tile_data {
+            false if tile_number < 128 => tile_number + 256,
+            _ => tile_number,
+        };
+        let value = self.tile_pixel(
+            attributes.vram_bank * TILES_PER_BANK + tile_index,
+            row as usize % PIXEL_DIMENSION_PER_TILE,
+            column as usize % PIXEL_DIMENSION_PER_TILE,
+            &attributes,
+        );
+        let colour = self.background_colour(&attributes, &value);
+        let pixel = BackgroundPixel {
+            value,
+            priority: attributes.priority,
+        };
+        (colour, pixel)
+    }
+
+    fn tile_pixel(
+        &self,
+        tile_index: usize,
+        row: usize,
+        column: usize,
+        attributes: &TileAttributes,
+    ) -> PixelValue {
+        let row = if attributes.y_flip { 7 - row } else { row };
+        let column = if attributes.x_flip {
+            7 - column
+        } else {
+            column
+        };
+        self.tile_set[tile_index].pixels[row][column]
+    }
+
+    fn render_sprites(
+        &self,
+        line: Line,
+        rendered_line: &mut [u32; LCD_WIDTH as usize],
+        background: &[BackgroundPixel; LCD_WIDTH as usize],
+    ) {
+        let sprite_height = if self.lcd_control.tall_sprites { 16 } else { 8 };
+        let mut sprites = self.oam.sprites_on_line(line, sprite_height);
+        // On the DMG the sprite furthest left wins and ties go to OAM order, which the stable sort
+        // keeps. The CGB only uses OAM order.
+        if !self.cgb_mode {
+            sprites.sort_by_key(|sprite| sprite.x);
+        }
+
+        let mut covered = [false; LCD_WIDTH as usize];
+        for sprite in sprites.iter() {
+            for (pixel_column, column) in (sprite.x..sprite.x + 8).enumerate() {
+                if column < 0 || column >= LCD_WIDTH as i16 || covered[column as usize] {
+                    continue;
+                }
+                let column = column as usize;
+                let value = self.sprite_pixel(sprite, line, sprite_height, pixel_column);
+                if value == PixelValue::Zero {
+                    continue;
+                }
+                // The highest priority opaque sprite pixel hides the others even when it is then
+                // drawn behind the background.
+                covered[column] = true;
+                if self.background_wins(&background[column], sprite) {
+                    continue;
+                }
+                rendered_line[column] = self.sprite_colour(sprite, &value);
+            }
+        }
+    }
+
+    fn background_colour(&self, attributes: &TileAttributes, value: &PixelValue) -> u32 {
+        match self.cgb_mode {
+            true => self
+                .bg_colour_palettes
+                .get_colour(attributes.colour_palette, value),
+            false => self.palette.get_colour(value, &self.dmg_colours.bg),
+        }
+    }
+
+    fn sprite_colour(&self, sprite: &Sprite, value: &PixelValue) -> u32 {
+        match self.cgb_mode {
+            true => self
+                .sprite_colour_palettes
+                .get_colour(sprite.attributes.colour_palette, value),
+            false => {
+                let palette = sprite.attributes.dmg_palette;
+                self.sprite_palettes[palette].get_colour(value, &self.dmg_colours.sprites[palette])
+            }
+        }
+    }
+
+    fn sprite_pixel(&self, sprite: &Sprite, line: Line, height: i16, column: usize) -> PixelValue {
+        let mut row = line as i16 - sprite.y;
+        if sprite.attributes.y_flip {
+            row = height - 1 - row;
+        }
+        // Tall sprites use an even tile for the top half and the following tile for the bottom.
+        let tile_number = match height {
+            16 => (sprite.tile & 0xFE) as usize + (row / 8) as usize,
+            _ => sprite.tile as usize,
+        };
+        let vram_bank = match self.cgb_mode {
+            true => sprite.attributes.vram_bank,
+            false => 0,
+        };
+        let column = if sprite.attributes.x_flip {
+            7 - column
+        } else {
+            column
+        };
+        self.tile_set[vram_bank * TILES_PER_BANK + tile_number].pixels[(row % 8) as usize][column]
+    }
+
+    fn background_wins(&self, background: &BackgroundPixel, sprite: &Sprite) -> bool {
+        if background.value == PixelValue::Zero {
+            return false;
+        }
+        match self.cgb_mode {
+            // Clearing LCDC bit 0 on the CGB puts sprites above everything.
+            true => {
+                self.lcd_control.bg_enabled && (background.priority || sprite.attributes.priority)
+            }
+            false => sprite.attributes.priority,
+        }
+    }
+
     fn get_pixel_colour_from_tile(&self, tile: u8, row: u8, col: u8) -> u32 {
         let tile = self.tile_set[tile as usize];
         let tile_pixel = tile.pixels[row as usize][col as usize];
-        self.palette.get_colour(&tile_pixel)
+        self.palette.get_colour(&tile_pixel, &self.dmg_colours.bg)
     }
 
     #[allow(dead_code)]
@@ -246,3 +906,106 @@ impl PPU {
         framebuffer
     }
 }
+
+// The DMG colours are a display setting rather than part of the state, so they are left alone.
+impl Snapshot for PPU {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.cgb_mode);
+        state.bytes(&self.vram);
+        state.usize(self.vram_bank);
+        state.u8(self.mode_number());
+        state.u16(self.cycles);
+        state.u16(self.transfer_cycles);
+        state.bool(self.entered_hblank);
+        state.u8(self.line);
+        state.u8(u8::from(&self.lcd_control));
+        state.u8(self.status_interrupts);
+        state.u8(self.line_compare);
+        state.bool(self.status_line);
+        state.bool(self.latched_coincidence);
+        state.u8(self.scroll.horiz);
+        state.u8(self.scroll.vert);
+        state.u8(self.window.x);
+        state.u8(self.window.y);
+        state.u8(self.window_line);
+        state.u8(u8::from(&self.palette));
+        state.u8(u8::from(&self.sprite_palettes[0]));
+        state.u8(u8::from(&self.sprite_palettes[1]));
+        self.bg_colour_palettes.save_state(state);
+        self.sprite_colour_palettes.save_state(state);
+        self.oam.save_state(state);
+        if let Some(sgb) = &self.sgb {
+            sgb.save_state(state);
+        }
+        state.bool(self.pixel_fifo.is_some());
+        if let Some(pixel_fifo) = &self.pixel_fifo {
+            pixel_fifo.save_state(state);
+        }
+        if !state.includes_output() {
+            return;
+        }
+        for pixel in self.framebuffer.iter() {
+            state.u32(*pixel);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) {
+        self.cgb_mode = state.bool();
+        state.bytes(&mut self.vram);
+        self.vram_bank = state.usize() % VRAM_BANKS;
+        self.mode = match state.u8() {
+            0 => PPUMode::HBlank,
+            1 => PPUMode::VBlank,
+            2 => PPUMode::OAMAccess,
+            _ => PPUMode::VRAMAccess,
+        };
+        self.cycles = state.u16();
+        self.transfer_cycles = state.u16().min(CYCLES_PER_LINE - OAM_SCAN_CYCLES);
+        self.entered_hblank = state.bool();
+        self.line = state.u8() % LINES_PER_FRAME;
+        self.lcd_control = LcdControl::from(state.u8());
+        self.status_interrupts = state.u8() & 0x78;
+        self.line_compare = state.u8();
+        self.status_line = state.bool();
+        self.latched_coincidence = state.bool();
+        self.scroll.horiz = state.u8();
+        self.scroll.vert = state.u8();
+        self.window.x = state.u8();
+        self.window.y = state.u8();
+        self.window_line = state.u8();
+        self.palette = Palette::from(state.u8());
+        self.sprite_palettes[0] = Palette::from(state.u8());
+        self.sprite_palettes[1] = Palette::from(state.u8());
+        self.bg_colour_palettes.load_state(state);
+        self.sprite_colour_palettes.load_state(state);
+        self.oam.load_state(state);
+        if let Some(sgb) = &mut self.sgb {
+            sgb.load_state(state);
+        }
+        // States can be loaded whether or not they were saved with the pixel FIFO in use. Without
+        // its state, the FIFO catches up on the line so far from the start.
+        let saved_pixel_fifo = state.bool();
+        match &mut self.pixel_fifo {
+            Some(pixel_fifo) if saved_pixel_fifo => pixel_fifo.load_state(state),
+            _ if saved_pixel_fifo => PixelFifo::default().load_state(state),
+            _ => {}
+        }
+        if let (Some(mut pixel_fifo), false) = (self.pixel_fifo.take(), saved_pixel_fifo) {
+            pixel_fifo.start_line(self);
+            if let PPUMode::VRAMAccess = self.mode {
+                pixel_fifo.run(self, self.cycles);
+            }
+            self.pixel_fifo = Some(pixel_fifo);
+        }
+        for pixel in self.framebuffer.iter_mut() {
+            *pixel = state.u32();
+        }
+
+        for bank in 0..VRAM_BANKS {
+            for address in (0..0x1800).step_by(2) {
+                self.update_tile_row(bank, address);
+            }
+        }
+        self.publish_framebuffer();
+    }
+}
@@ -0,0 +1,85 @@
+use super::tile::TileAttributes;
+use crate::snapshot::{Snapshot, StateReader, StateWriter};
+
+pub const OAM_SIZE: usize = 0xA0;
+const SPRITE_COUNT: usize = OAM_SIZE / 4;
+const MAX_SPRITES_PER_LINE: usize = 10;
+
+#[derive(Copy, Clone)]
+pub(super) struct Sprite {
+    // Screen coordinates of the top left corner, which can be partially off screen.
+    pub y: i16,
+    pub x: i16,
+    pub tile: u8,
+    pub attributes: TileAttributes,
+}
+
+// Sprites as they're laid out in OAM: Y + 16, X + 8, the tile number and the attributes.
+impl From<[u8; 4]> for Sprite {
+    fn from(entry: [u8; 4]) -> Self {
+        Sprite {
+            y: entry[0] as i16 - 16,
+            x: entry[1] as i16 - 8,
+            tile: entry[2],
+            attributes: TileAttributes::from(entry[3]),
+        }
+    }
+}
+
+impl From<&Sprite> for [u8; 4] {
+    fn from(sprite: &Sprite) -> [u8; 4] {
+        [
+            (sprite.y + 16) as u8,
+            (sprite.x + 8) as u8,
+            sprite.tile,
+            u8::from(&sprite.attributes),
+        ]
+    }
+}
+
+pub(super) struct ObjectAttributeMemory {
+    bytes: [u8; OAM_SIZE],
+}
+
+impl Default for ObjectAttributeMemory {
+    fn default() -> Self {
+        ObjectAttributeMemory {
+            bytes: [0; OAM_SIZE],
+        }
+    }
+}
+
+impl ObjectAttributeMemory {
+    pub fn read(&self, address: usize) -> u8 {
+        self.bytes[address]
+    }
+
+    pub fn write(&mut self, value: u8, address: usize) {
+        self.bytes[address] = value;
+    }
+
+    fn sprite(&self, index: usize) -> Sprite {
+        let entry = &self.bytes[index * 4..index * 4 + 4];
+        Sprite::from([entry[0], entry[1], entry[2], entry[3]])
+    }
+
+    // The first ten sprites in OAM order that overlap the line, as selected during the OAM scan.
+    pub fn sprites_on_line(&self, line: u8, sprite_height: i16) -> Vec<Sprite> {
+        let line = line as i16;
+        (0..SPRITE_COUNT)
+            .map(|index| self.sprite(index))
+            .filter(|sprite| line >= sprite.y && line < sprite.y + sprite_height)
+            .take(MAX_SPRITES_PER_LINE)
+            .collect()
+    }
+}
+
+impl Snapshot for ObjectAttributeMemory {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.bytes);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) {
+        state.bytes(&mut self.bytes);
+    }
+}
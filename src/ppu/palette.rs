@@ -1,3 +1,5 @@
+use crate::snapshot::{Snapshot, StateReader, StateWriter};
+
 #[derive(Copy, Clone)]
 enum Shade {
     White,
@@ -7,16 +9,39 @@ enum Shade {
 }
 
 impl Shade {
-    fn colour(&self) -> u32 {
-        match self {
-            Shade::White => 0xF8F8F8,
-            Shade::LightGray => 0xA8A8A8,
-            Shade::DarkGray => 0x505050,
-            Shade::Black => 0x000000,
+    fn colour(&self, colours: &ShadeColours) -> u32 {
+        colours[u8::from(self) as usize]
+    }
+}
+
+// The colour shown for each shade, from white to black.
+pub type ShadeColours = [u32; 4];
+
+pub const GREY_SHADES: ShadeColours = [0xF8F8F8, 0xA8A8A8, 0x505050, 0x000000];
+
+// How a DMG game's shades are displayed. The CGB colourises DMG games with separate colours for
+// the BG and each sprite palette.
+#[derive(Copy, Clone)]
+pub struct DmgColours {
+    pub bg: ShadeColours,
+    pub sprites: [ShadeColours; 2],
+}
+
+impl DmgColours {
+    pub const fn uniform(colours: ShadeColours) -> Self {
+        DmgColours {
+            bg: colours,
+            sprites: [colours, colours],
         }
     }
 }
 
+impl Default for DmgColours {
+    fn default() -> Self {
+        DmgColours::uniform(GREY_SHADES)
+    }
+}
+
 impl From<&Shade> for u8 {
     fn from(shade: &Shade) -> Self {
         match *shade {
@@ -45,12 +70,12 @@ pub(super) struct Palette {
 }
 
 impl Palette {
-    pub fn get_colour(&self, tile_pixel: &PixelValue) -> u32 {
+    pub fn get_colour(&self, tile_pixel: &PixelValue, colours: &ShadeColours) -> u32 {
         match tile_pixel {
-            PixelValue::Zero => self.shades[0].colour(),
-            PixelValue::One => self.shades[1].colour(),
-            PixelValue::Two => self.shades[2].colour(),
-            PixelValue::Three => self.shades[3].colour(),
+            PixelValue::Zero => self.shades[0].colour(colours),
+            PixelValue::One => self.shades[1].colour(colours),
+            PixelValue::Two => self.shades[2].colour(colours),
+            PixelValue::Three => self.shades[3].colour(colours),
         }
     }
 
@@ -118,6 +143,78 @@ impl From<u8> for Palette {
     }
 }
 
+const COLOUR_PALETTE_RAM_SIZE: usize = 64;
+
+// CGB palette RAM behind BCPS/BCPD or OCPS/OCPD: 8 palettes of 4 colours, each colour a little
+// endian 15-bit BGR value.
+pub(super) struct ColourPaletteRam {
+    data: [u8; COLOUR_PALETTE_RAM_SIZE],
+    index: u8,
+    auto_increment: bool,
+}
+
+impl Default for ColourPaletteRam {
+    fn default() -> Self {
+        ColourPaletteRam {
+            data: [0xFF; COLOUR_PALETTE_RAM_SIZE],
+            index: 0,
+            auto_increment: false,
+        }
+    }
+}
+
+impl ColourPaletteRam {
+    pub fn read_specification(&self) -> u8 {
+        0x40 | ((self.auto_increment as u8) << 7) | self.index
+    }
+
+    pub fn write_specification(&mut self, value: u8) {
+        self.index = value & 0x3F;
+        self.auto_increment = (value & 0x80) != 0;
+    }
+
+    pub fn read_data(&self) -> u8 {
+        self.data[self.index as usize]
+    }
+
+    pub fn write_data(&mut self, value: u8) {
+        self.data[self.index as usize] = value;
+        if self.auto_increment {
+            self.index = (self.index + 1) & 0x3F;
+        }
+    }
+
+    pub fn get_colour(&self, palette: u8, tile_pixel: &PixelValue) -> u32 {
+        let offset = (palette as usize * 4 + tile_pixel.index()) * 2;
+        colour_from_bgr555(u16::from_le_bytes([
+            self.data[offset],
+            self.data[offset + 1],
+        ]))
+    }
+}
+
+impl Snapshot for ColourPaletteRam {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.data);
+        state.u8(self.read_specification());
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) {
+        state.bytes(&mut self.data);
+        self.write_specification(state.u8());
+    }
+}
+
+// Converts a 15-bit BGR colour, as used by the CGB and the SGB, to the framebuffer's 24-bit RGB.
+pub fn colour_from_bgr555(colour: u16) -> u32 {
+    let red = (colour & 0x1F) as u32;
+    let green = ((colour >> 5) & 0x1F) as u32;
+    let blue = ((colour >> 10) & 0x1F) as u32;
+    // Scale each 5-bit channel to 8 bits so that 0x1F maps to 0xFF.
+    let scale = |channel: u32| (channel << 3) | (channel >> 2);
+    (scale(red) << 16) | (scale(green) << 8) | scale(blue)
+}
+
 #[derive(Copy, Clone, Eq, PartialEq, Hash)]
 pub enum PixelValue {
     Zero,
@@ -126,7 +223,27 @@ pub enum PixelValue {
     Three,
 }
 
+impl From<u8> for PixelValue {
+    fn from(value: u8) -> Self {
+        match value & 0b11 {
+            0 => PixelValue::Zero,
+            1 => PixelValue::One,
+            2 => PixelValue::Two,
+            _ => PixelValue::Three,
+        }
+    }
+}
+
 impl PixelValue {
+    pub fn index(&self) -> usize {
+        match self {
+            PixelValue::Zero => 0,
+            PixelValue::One => 1,
+            PixelValue::Two => 2,
+            PixelValue::Three => 3,
+        }
+    }
+
     fn bit_position(&self) -> u8 {
         match self {
             PixelValue::Zero => 0,
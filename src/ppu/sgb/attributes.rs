@@ -0,0 +1,152 @@
+use crate::snapshot::{Snapshot, StateReader, StateWriter};
+
+// Which of the four SGB palettes colours each 8x8 cell of the Game Boy screen.
+pub const COLUMNS: usize = 20;
+pub const ROWS: usize = 18;
+pub const CELLS: usize = COLUMNS * ROWS;
+
+// ATTR_TRN sends 45 attribute files, each packing a whole screen at 2 bits per cell.
+pub const ATTRIBUTE_FILE_SIZE: usize = CELLS / 4;
+pub const ATTRIBUTE_FILES: usize = 45;
+
+pub struct AttributeMap {
+    cells: [u8; CELLS],
+}
+
+impl Default for AttributeMap {
+    fn default() -> Self {
+        AttributeMap { cells: [0; CELLS] }
+    }
+}
+
+impl AttributeMap {
+    pub fn palette(&self, column: usize, row: usize) -> usize {
+        self.cells[row * COLUMNS + column] as usize
+    }
+
+    fn set(&mut self, column: usize, row: usize, palette: u8) {
+        if column < COLUMNS && row < ROWS {
+            self.cells[row * COLUMNS + column] = palette & 0b11;
+        }
+    }
+
+    // ATTR_BLK: each data set is a control byte, the inside, border and outside palettes, then the
+    // corners of a rectangle in cells.
+    pub fn apply_blocks(&mut self, data: &[u8]) {
+        let count = data[0] as usize;
+        for block in data[1..].chunks_exact(6).take(count) {
+            let control = block[0] & 0b111;
+            let inside = block[1] & 0b11;
+            let outside = (block[1] >> 4) & 0b11;
+            // Changing only the inside or only the outside changes the border to match.
+            let border = match control {
+                0b001 => Some(inside),
+                0b100 => Some(outside),
+                _ if (control & 0b010) != 0 => Some((block[1] >> 2) & 0b11),
+                _ => None,
+            };
+            let (left, top, right, bottom) = (
+                block[2] as usize,
+                block[3] as usize,
+                block[4] as usize,
+                block[5] as usize,
+            );
+            for row in 0..ROWS {
+                for column in 0..COLUMNS {
+                    let within = (left..=right).contains(&column) && (top..=bottom).contains(&row);
+                    let on_edge = within
+                        && (column == left || column == right || row == top || row == bottom);
+                    let palette = match (within, on_edge) {
+                        (true, true) => border,
+                        (true, false) if (control & 0b001) != 0 => Some(inside),
+                        (false, _) if (control & 0b100) != 0 => Some(outside),
+                        _ => None,
+                    };
+                    if let Some(palette) = palette {
+                        self.set(column, row, palette);
+                    }
+                }
+            }
+        }
+    }
+
+    // ATTR_LIN: each byte gives a line number, a palette and whether the line is a row or a column.
+    pub fn apply_lines(&mut self, data: &[u8]) {
+        let count = data[0] as usize;
+        for &line in data[1..].iter().take(count) {
+            let number = (line & 0x1F) as usize;
+            let palette = (line >> 5) & 0b11;
+            if (line & 0x80) != 0 {
+                (0..COLUMNS).for_each(|column| self.set(column, number, palette));
+            } else {
+                (0..ROWS).for_each(|row| self.set(number, row, palette));
+            }
+        }
+    }
+
+    // ATTR_DIV: splits the screen at a row or column, with separate palettes for either side and
+    // the dividing line itself.
+    pub fn apply_division(&mut self, data: &[u8]) {
+        let after = data[0] & 0b11;
+        let before = (data[0] >> 2) & 0b11;
+        let on_line = (data[0] >> 4) & 0b11;
+        let horizontal = (data[0] & 0x40) != 0;
+        let split = data[1] as usize;
+        for row in 0..ROWS {
+            for column in 0..COLUMNS {
+                let position = if horizontal { row } else { column };
+                let palette = match position {
+                    _ if position < split => before,
+                    _ if position == split => on_line,
+                    _ => after,
+                };
+                self.set(column, row, palette);
+            }
+        }
+    }
+
+    // ATTR_CHR: a run of palettes at 2 bits per cell, written left to right or top to bottom from
+    // a starting cell and wrapping around the screen.
+    pub fn apply_cells(&mut self, data: &[u8]) {
+        let (mut column, mut row) = (data[0] as usize, data[1] as usize);
+        let count = u16::from_le_bytes([data[2], data[3]]) as usize;
+        let vertical = data[4] != 0;
+        for index in 0..count.min(CELLS) {
+            let palette = match data.get(5 + index / 4) {
+                Some(byte) => (byte >> (6 - (index % 4) * 2)) & 0b11,
+                None => break,
+            };
+            self.set(column, row, palette);
+            if vertical {
+                row += 1;
+                if row >= ROWS {
+                    row = 0;
+                    column = (column + 1) % COLUMNS;
+                }
+            } else {
+                column += 1;
+                if column >= COLUMNS {
+                    column = 0;
+                    row = (row + 1) % ROWS;
+                }
+            }
+        }
+    }
+
+    pub fn apply_file(&mut self, file: &[u8]) {
+        for (index, cell) in self.cells.iter_mut().enumerate() {
+            *cell = (file[index / 4] >> (6 - (index % 4) * 2)) & 0b11;
+        }
+    }
+}
+
+impl Snapshot for AttributeMap {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.cells);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) {
+        state.bytes(&mut self.cells);
+        self.cells.iter_mut().for_each(|cell| *cell &= 0b11);
+    }
+}
@@ -0,0 +1,93 @@
+use crate::snapshot::{Snapshot, StateReader, StateWriter};
+
+// The picture the SNES draws around the Game Boy screen, built from 8x8 tiles in the SNES 4 bits
+// per pixel format.
+pub const WIDTH: usize = 256;
+pub const HEIGHT: usize = 224;
+
+const TILES: usize = 256;
+const TILE_SIZE: usize = 32;
+const MAP_COLUMNS: usize = 32;
+const MAP_SIZE: usize = 0x800;
+const PALETTES: usize = 4;
+const COLOURS_PER_PALETTE: usize = 16;
+
+pub struct Border {
+    tiles: Vec<u8>,
+    map: Vec<u16>,
+    palettes: [[u16; COLOURS_PER_PALETTE]; PALETTES],
+}
+
+impl Default for Border {
+    fn default() -> Self {
+        Border {
+            tiles: vec![0; TILES * TILE_SIZE],
+            map: vec![0; MAP_SIZE / 2],
+            palettes: [[0; COLOURS_PER_PALETTE]; PALETTES],
+        }
+    }
+}
+
+impl Border {
+    // CHR_TRN sends half of the tiles at a time.
+    pub fn load_tiles(&mut self, upper_half: bool, data: &[u8]) {
+        let start = if upper_half { TILES / 2 * TILE_SIZE } else { 0 };
+        let half = &mut self.tiles[start..start + TILES / 2 * TILE_SIZE];
+        half.copy_from_slice(&data[..half.len()]);
+    }
+
+    // PCT_TRN sends the tile map followed by the border's palettes.
+    pub fn load_map(&mut self, data: &[u8]) {
+        for (entry, bytes) in self.map.iter_mut().zip(data[..MAP_SIZE].chunks_exact(2)) {
+            *entry = u16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+        let colours = data[MAP_SIZE..].chunks_exact(2);
+        for (colour, bytes) in self.palettes.iter_mut().flatten().zip(colours) {
+            *colour = u16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+    }
+
+    // The colour of the border at a point, or None where it is transparent.
+    pub fn pixel(&self, x: usize, y: usize) -> Option<u16> {
+        let entry = self.map[(y / 8) * MAP_COLUMNS + x / 8];
+        let tile = (entry & 0xFF) as usize;
+        // The border uses SNES palettes 4 to 7.
+        let palette = ((entry >> 10) & 0b11) as usize;
+        let row = match (entry & 0x8000) != 0 {
+            true => 7 - y % 8,
+            false => y % 8,
+        };
+        let bit = match (entry & 0x4000) != 0 {
+            true => x % 8,
+            false => 7 - x % 8,
+        };
+        // The first two bit planes are interleaved by row, followed by the other two.
+        let data = &self.tiles[tile * TILE_SIZE..(tile + 1) * TILE_SIZE];
+        let plane = |offset: usize| ((data[offset + row * 2] >> bit) & 1) as usize;
+        let value = plane(0) | plane(1) << 1 | plane(16) << 2 | plane(17) << 3;
+        match value {
+            0 => None,
+            _ => Some(self.palettes[palette][value]),
+        }
+    }
+}
+
+impl Snapshot for Border {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.tiles);
+        self.map.iter().for_each(|&entry| state.u16(entry));
+        self.palettes
+            .iter()
+            .flatten()
+            .for_each(|&colour| state.u16(colour));
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) {
+        state.bytes(&mut self.tiles);
+        self.map.iter_mut().for_each(|entry| *entry = state.u16());
+        self.palettes
+            .iter_mut()
+            .flatten()
+            .for_each(|colour| *colour = state.u16());
+    }
+}
@@ -0,0 +1,237 @@
+mod attributes;
+mod border;
+
+use super::palette::{colour_from_bgr555, ShadeColours};
+use super::{LCD_HEIGHT, LCD_WIDTH};
+use crate::snapshot::{Snapshot, StateReader, StateWriter};
+use attributes::{AttributeMap, ATTRIBUTE_FILES, ATTRIBUTE_FILE_SIZE};
+use border::Border;
+
+pub use border::{HEIGHT as SGB_HEIGHT, WIDTH as SGB_WIDTH};
+
+// In SGB mode lines are rendered as shade numbers, which are then coloured using the palette of the
+// screen cell they are in.
+pub const SHADE_NUMBERS: ShadeColours = [0, 1, 2, 3];
+
+// The VRAM transfer commands send 4KB of tile data by showing it on screen.
+pub const TRANSFER_SIZE: usize = 0x1000;
+
+// Where the Game Boy screen sits within the border.
+const SCREEN_X: usize = 48;
+const SCREEN_Y: usize = 40;
+
+const PAL01: u8 = 0x00;
+const PAL23: u8 = 0x01;
+const PAL03: u8 = 0x02;
+const PAL12: u8 = 0x03;
+const ATTR_BLK: u8 = 0x04;
+const ATTR_LIN: u8 = 0x05;
+const ATTR_DIV: u8 = 0x06;
+const ATTR_CHR: u8 = 0x07;
+const PAL_SET: u8 = 0x0A;
+const PAL_TRN: u8 = 0x0B;
+const CHR_TRN: u8 = 0x13;
+const PCT_TRN: u8 = 0x14;
+const ATTR_TRN: u8 = 0x15;
+const ATTR_SET: u8 = 0x16;
+const MASK_EN: u8 = 0x17;
+
+const SYSTEM_PALETTES: usize = 512;
+
+type SgbPalette = [u16; 4];
+
+const DEFAULT_PALETTE: SgbPalette = [0x7FFF, 0x5294, 0x294A, 0x0000];
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum ScreenMask {
+    None,
+    Freeze,
+    Black,
+    Colour0,
+}
+
+pub struct SuperGameBoy {
+    palettes: [SgbPalette; 4],
+    system_palettes: Vec<SgbPalette>,
+    attributes: AttributeMap,
+    attribute_files: Vec<u8>,
+    border: Border,
+    mask: ScreenMask,
+    screen: Vec<u32>,
+}
+
+impl Default for SuperGameBoy {
+    fn default() -> Self {
+        SuperGameBoy {
+            palettes: [DEFAULT_PALETTE; 4],
+            system_palettes: vec![DEFAULT_PALETTE; SYSTEM_PALETTES],
+            attributes: Default::default(),
+            attribute_files: vec![0; ATTRIBUTE_FILES * ATTRIBUTE_FILE_SIZE],
+            border: Default::default(),
+            mask: ScreenMask::None,
+            screen: vec![0; LCD_WIDTH as usize * LCD_HEIGHT as usize],
+        }
+    }
+}
+
+// Whether the command copies data out of VRAM.
+pub fn transfers_vram(command: &[u8]) -> bool {
+    matches!(command[0] >> 3, PAL_TRN | CHR_TRN | PCT_TRN | ATTR_TRN)
+}
+
+impl SuperGameBoy {
+    // The command's packets are concatenated, with the command and packet count in the first byte.
+    pub fn handle_command(&mut self, command: &[u8], transfer: &[u8]) {
+        let data = &command[1..];
+        match command[0] >> 3 {
+            PAL01 => self.set_palettes(0, 1, data),
+            PAL23 => self.set_palettes(2, 3, data),
+            PAL03 => self.set_palettes(0, 3, data),
+            PAL12 => self.set_palettes(1, 2, data),
+            ATTR_BLK => self.attributes.apply_blocks(data),
+            ATTR_LIN => self.attributes.apply_lines(data),
+            ATTR_DIV => self.attributes.apply_division(data),
+            ATTR_CHR => self.attributes.apply_cells(data),
+            PAL_SET => self.apply_system_palettes(data),
+            PAL_TRN => {
+                for (palette, colours) in self.system_palettes.iter_mut().zip(transfer.chunks(8)) {
+                    for (colour, bytes) in palette.iter_mut().zip(colours.chunks_exact(2)) {
+                        *colour = u16::from_le_bytes([bytes[0], bytes[1]]);
+                    }
+                }
+            }
+            CHR_TRN => self.border.load_tiles((data[0] & 1) != 0, transfer),
+            PCT_TRN => self.border.load_map(transfer),
+            ATTR_TRN => {
+                let size = self.attribute_files.len();
+                self.attribute_files.copy_from_slice(&transfer[..size]);
+            }
+            ATTR_SET => {
+                self.apply_attribute_file(data[0]);
+                if (data[0] & 0x40) != 0 {
+                    self.mask = ScreenMask::None;
+                }
+            }
+            MASK_EN => {
+                self.mask = match data[0] & 0b11 {
+                    0 => ScreenMask::None,
+                    1 => ScreenMask::Freeze,
+                    2 => ScreenMask::Black,
+                    _ => ScreenMask::Colour0,
+                }
+            }
+            // Sound, multiplayer and SNES program commands are not supported.
+            _ => {}
+        }
+    }
+
+    // Colour 0 is shared by all four palettes, so setting it for one sets it for all of them.
+    fn set_palettes(&mut self, first: usize, second: usize, data: &[u8]) {
+        let colour = |index: usize| u16::from_le_bytes([data[index * 2], data[index * 2 + 1]]);
+        for palette in self.palettes.iter_mut() {
+            palette[0] = colour(0);
+        }
+        for shade in 1..4 {
+            self.palettes[first][shade] = colour(shade);
+            self.palettes[second][shade] = colour(shade + 3);
+        }
+    }
+
+    // PAL_SET picks the four palettes from those sent by PAL_TRN and can also apply an attribute
+    // file and cancel the screen mask.
+    fn apply_system_palettes(&mut self, data: &[u8]) {
+        for (index, palette) in self.palettes.iter_mut().enumerate() {
+            let number = u16::from_le_bytes([data[index * 2], data[index * 2 + 1]]) as usize;
+            *palette = self.system_palettes[number % SYSTEM_PALETTES];
+        }
+        let shared_colour = self.palettes[0][0];
+        for palette in self.palettes.iter_mut() {
+            palette[0] = shared_colour;
+        }
+        let flags = data[8];
+        if (flags & 0x80) != 0 {
+            self.apply_attribute_file(flags);
+        }
+        if (flags & 0x40) != 0 {
+            self.mask = ScreenMask::None;
+        }
+    }
+
+    fn apply_attribute_file(&mut self, number: u8) {
+        let number = (number & 0x3F) as usize;
+        if number < ATTRIBUTE_FILES {
+            let start = number * ATTRIBUTE_FILE_SIZE;
+            self.attributes
+                .apply_file(&self.attribute_files[start..start + ATTRIBUTE_FILE_SIZE]);
+        }
+    }
+
+    // Draws the Game Boy screen, given as shade numbers, inside the border.
+    pub fn compose(&mut self, screen: &[u32], output: &mut [u32]) {
+        if self.mask != ScreenMask::Freeze {
+            self.screen.copy_from_slice(screen);
+        }
+        for (index, pixel) in output.iter_mut().enumerate() {
+            let (x, y) = (index % SGB_WIDTH, index / SGB_WIDTH);
+            let colour = match self.border.pixel(x, y) {
+                Some(colour) => colour,
+                None => self.screen_pixel(x, y),
+            };
+            *pixel = colour_from_bgr555(colour);
+        }
+    }
+
+    // Outside the Game Boy screen and behind a transparent border is the shared colour 0.
+    fn screen_pixel(&self, x: usize, y: usize) -> u16 {
+        let backdrop = self.palettes[0][0];
+        let (x, y) = match (x.checked_sub(SCREEN_X), y.checked_sub(SCREEN_Y)) {
+            (Some(x), Some(y)) if x < LCD_WIDTH as usize && y < LCD_HEIGHT as usize => (x, y),
+            _ => return backdrop,
+        };
+        match self.mask {
+            ScreenMask::Black => 0x0000,
+            ScreenMask::Colour0 => backdrop,
+            ScreenMask::None | ScreenMask::Freeze => {
+                let shade = self.screen[y * LCD_WIDTH as usize + x] as usize;
+                self.palettes[self.attributes.palette(x / 8, y / 8)][shade]
+            }
+        }
+    }
+}
+
+impl Snapshot for SuperGameBoy {
+    fn save_state(&self, state: &mut StateWriter) {
+        let palettes = self.palettes.iter().chain(self.system_palettes.iter());
+        palettes.flatten().for_each(|&colour| state.u16(colour));
+        self.attributes.save_state(state);
+        state.bytes(&self.attribute_files);
+        self.border.save_state(state);
+        state.u8(match self.mask {
+            ScreenMask::None => 0,
+            ScreenMask::Freeze => 1,
+            ScreenMask::Black => 2,
+            ScreenMask::Colour0 => 3,
+        });
+        self.screen.iter().for_each(|&shade| state.u8(shade as u8));
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) {
+        let palettes = self
+            .palettes
+            .iter_mut()
+            .chain(self.system_palettes.iter_mut());
+        palettes.flatten().for_each(|colour| *colour = state.u16());
+        self.attributes.load_state(state);
+        state.bytes(&mut self.attribute_files);
+        self.border.load_state(state);
+        self.mask = match state.u8() {
+            1 => ScreenMask::Freeze,
+            2 => ScreenMask::Black,
+            3 => ScreenMask::Colour0,
+            _ => ScreenMask::None,
+        };
+        self.screen
+            .iter_mut()
+            .for_each(|shade| *shade = (state.u8() & 0b11) as u32);
+    }
+}
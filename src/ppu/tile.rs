@@ -12,3 +12,39 @@ impl Tile {
         }
     }
 }
+
+// The CGB BG attribute map and OAM attributes share a layout, except that bit 4 (the DMG palette)
+// is only used by sprites.
+#[derive(Copy, Clone, Default)]
+pub(super) struct TileAttributes {
+    pub priority: bool,
+    pub y_flip: bool,
+    pub x_flip: bool,
+    pub dmg_palette: usize,
+    pub vram_bank: usize,
+    pub colour_palette: u8,
+}
+
+impl From<u8> for TileAttributes {
+    fn from(value: u8) -> Self {
+        TileAttributes {
+            priority: (value & (1 << 7)) != 0,
+            y_flip: (value & (1 << 6)) != 0,
+            x_flip: (value & (1 << 5)) != 0,
+            dmg_palette: ((value >> 4) & 1) as usize,
+            vram_bank: ((value >> 3) & 1) as usize,
+            colour_palette: value & 0b111,
+        }
+    }
+}
+
+impl From<&TileAttributes> for u8 {
+    fn from(attributes: &TileAttributes) -> u8 {
+        (attributes.priority as u8) << 7
+            | (attributes.y_flip as u8) << 6
+            | (attributes.x_flip as u8) << 5
+            | (attributes.dmg_palette as u8) << 4
+            | (attributes.vram_bank as u8) << 3
+            | attributes.colour_palette
+    }
+}
@@ -0,0 +1,160 @@
+use super::SerialEndpoint;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Each message is two bytes: a kind and the byte shifted out by the sender.
+const MESSAGE_TRANSFER: u8 = 0x01;
+const MESSAGE_REPLY: u8 = 0x02;
+
+// How long the clocking side waits for the other emulator to answer a transfer before treating
+// the cable as unplugged. The other side only answers between its own emulation bursts, and
+// emulation carries on meanwhile with the transfer still in progress.
+const REPLY_TIMEOUT: Duration = Duration::from_millis(100);
+
+enum Message {
+    Transfer(u8),
+    Reply(u8),
+}
+
+// Connects two emulator instances. Whichever side starts an internally clocked transfer sends its
+// byte, and the transfer completes once the other side's byte arrives in reply; the other side
+// answers the next time it polls, whether or not it has an externally clocked transfer pending.
+pub struct LinkCable {
+    stream: Arc<Mutex<Option<TcpStream>>>,
+    messages: Receiver<Message>,
+    // The byte sent for the transfer we're clocking, and when, until the other side answers.
+    pending_transfer: Option<(u8, Instant)>,
+}
+
+impl LinkCable {
+    pub fn listen(port: u16) -> Self {
+        Self::spawn(move || {
+            let listener = TcpListener::bind(("127.0.0.1", port))?;
+            eprintln!("Waiting for link cable connection on 127.0.0.1:{}", port);
+            let (stream, peer) = listener.accept()?;
+            eprintln!("Link cable connected to {}", peer);
+            Ok(stream)
+        })
+    }
+
+    pub fn connect(address: String) -> Self {
+        Self::spawn(move || {
+            let stream = TcpStream::connect(&address)?;
+            eprintln!("Link cable connected to {}", address);
+            Ok(stream)
+        })
+    }
+
+    fn spawn<F>(open: F) -> Self
+    where
+        F: FnOnce() -> std::io::Result<TcpStream> + Send + 'static,
+    {
+        let stream = Arc::new(Mutex::new(None));
+        let (sender, messages) = mpsc::channel();
+        let shared_stream = Arc::clone(&stream);
+        thread::spawn(move || match open() {
+            Ok(stream) => {
+                let _ = stream.set_nodelay(true);
+                match stream.try_clone() {
+                    Ok(writer) => {
+                        *shared_stream.lock().unwrap() = Some(writer);
+                        Self::receive(stream, sender);
+                    }
+                    Err(err) => eprintln!("Link cable failed: {}", err),
+                }
+            }
+            Err(err) => eprintln!("Link cable failed: {}", err),
+        });
+        LinkCable {
+            stream,
+            messages,
+            pending_transfer: None,
+        }
+    }
+
+    fn receive(mut stream: TcpStream, sender: Sender<Message>) {
+        let mut message = [0; 2];
+        while stream.read_exact(&mut message).is_ok() {
+            let message = match message[0] {
+                MESSAGE_TRANSFER => Message::Transfer(message[1]),
+                MESSAGE_REPLY => Message::Reply(message[1]),
+                _ => continue,
+            };
+            if sender.send(message).is_err() {
+                return;
+            }
+        }
+        eprintln!("Link cable disconnected");
+    }
+
+    fn send(&mut self, kind: u8, value: u8) -> bool {
+        let mut stream = self.stream.lock().unwrap();
+        let sent = match stream.as_mut() {
+            Some(stream) => stream.write_all(&[kind, value]).is_ok(),
+            None => false,
+        };
+        if !sent {
+            *stream = None;
+        }
+        sent
+    }
+}
+
+impl SerialEndpoint for LinkCable {
+    fn start_transfer(&mut self, outgoing: u8) -> Option<u8> {
+        if !self.send(MESSAGE_TRANSFER, outgoing) {
+            return Some(0xFF);
+        }
+        self.pending_transfer = Some((outgoing, Instant::now()));
+        None
+    }
+
+    fn transfer_reply(&mut self) -> Option<u8> {
+        let (outgoing, started) = match self.pending_transfer {
+            Some(pending_transfer) => pending_transfer,
+            // Nothing is in flight after loading a state saved mid-transfer.
+            None => return Some(0xFF),
+        };
+        loop {
+            match self.messages.try_recv() {
+                Ok(Message::Reply(incoming)) => {
+                    self.pending_transfer = None;
+                    return Some(incoming);
+                }
+                // Both sides started a transfer at once. Real hardware would garble the data,
+                // answer with ours so the other side does not stall.
+                Ok(Message::Transfer(_)) => {
+                    self.send(MESSAGE_REPLY, outgoing);
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.pending_transfer = None;
+                    return Some(0xFF);
+                }
+            }
+        }
+        if started.elapsed() >= REPLY_TIMEOUT {
+            self.pending_transfer = None;
+            return Some(0xFF);
+        }
+        None
+    }
+
+    fn poll_external(&mut self, outgoing: u8) -> Option<u8> {
+        loop {
+            match self.messages.try_recv() {
+                Ok(Message::Transfer(incoming)) => {
+                    self.send(MESSAGE_REPLY, outgoing);
+                    return Some(incoming);
+                }
+                // A late reply to a transfer that already timed out.
+                Ok(Message::Reply(_)) => continue,
+                Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => return None,
+            }
+        }
+    }
+}
@@ -0,0 +1,227 @@
+pub mod link_cable;
+pub mod printer;
+
+use crate::cpu::interrupts::{Interrupt, InterruptsToSet};
+use crate::snapshot::{Snapshot, StateReader, StateWriter};
+use std::io::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+// The internal clock shifts one bit every 512 cycles (8192 Hz).
+const CYCLES_PER_BIT: u32 = 512;
+const CYCLES_PER_TRANSFER: u32 = CYCLES_PER_BIT * 8;
+
+pub trait SerialEndpoint: Send {
+    // Called when the Game Boy starts driving the clock. Returns the byte shifted in from the
+    // other side if it's known straight away, otherwise transfer_reply is polled until it is.
+    fn start_transfer(&mut self, outgoing: u8) -> Option<u8>;
+
+    fn transfer_reply(&mut self) -> Option<u8> {
+        Some(0xFF)
+    }
+
+    // Called while the other side may be driving the clock. Returns the byte shifted in if the
+    // other side completed a transfer, in which case it has received `outgoing`.
+    fn poll_external(&mut self, _outgoing: u8) -> Option<u8> {
+        None
+    }
+}
+
+pub struct Disconnected;
+
+impl SerialEndpoint for Disconnected {
+    fn start_transfer(&mut self, _outgoing: u8) -> Option<u8> {
+        Some(0xFF)
+    }
+}
+
+// Prints every byte sent, which is how most test ROMs report their results.
+pub struct StdoutLogger;
+
+impl SerialEndpoint for StdoutLogger {
+    fn start_transfer(&mut self, outgoing: u8) -> Option<u8> {
+        let stdout = std::io::stdout();
+        let mut stdout = stdout.lock();
+        let _ = stdout.write_all(&[outgoing]);
+        let _ = stdout.flush();
+        Some(0xFF)
+    }
+}
+
+#[derive(Debug)]
+pub enum SerialConfig {
+    Disconnected,
+    Stdout,
+    Printer(PathBuf),
+    Listen(u16),
+    Connect(String),
+}
+
+impl FromStr for SerialConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "disconnected" => Ok(SerialConfig::Disconnected),
+            "stdout" => Ok(SerialConfig::Stdout),
+            "printer" => Ok(SerialConfig::Printer(PathBuf::from("."))),
+            _ if s.starts_with("printer:") => {
+                Ok(SerialConfig::Printer(PathBuf::from(&s["printer:".len()..])))
+            }
+            _ if s.starts_with("listen:") => s["listen:".len()..]
+                .parse::<u16>()
+                .map(SerialConfig::Listen)
+                .map_err(|err| format!("Invalid link cable port: {}", err)),
+            _ if s.starts_with("connect:") => {
+                Ok(SerialConfig::Connect(s["connect:".len()..].to_string()))
+            }
+            _ => Err(format!(
+                "Unknown serial endpoint {} (expected disconnected, stdout, printer[:<dir>], listen:<port> or connect:<host:port>)",
+                s
+            )),
+        }
+    }
+}
+
+impl SerialConfig {
+    pub fn into_endpoint(self) -> Box<dyn SerialEndpoint> {
+        match self {
+            SerialConfig::Disconnected => Box::new(Disconnected),
+            SerialConfig::Stdout => Box::new(StdoutLogger),
+            SerialConfig::Printer(directory) => Box::new(printer::Printer::new(directory)),
+            SerialConfig::Listen(port) => Box::new(link_cable::LinkCable::listen(port)),
+            SerialConfig::Connect(address) => Box::new(link_cable::LinkCable::connect(address)),
+        }
+    }
+}
+
+pub struct Serial {
+    data: u8,
+    transfer_requested: bool,
+    internal_clock: bool,
+    // Byte shifted in by an internally clocked transfer, applied when the transfer completes.
+    incoming: u8,
+    // An internally clocked transfer can't complete until the other side has answered.
+    awaiting_reply: bool,
+    cycles_remaining: u32,
+    cycles_until_poll: u32,
+    endpoint: Box<dyn SerialEndpoint>,
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Self {
+            data: 0,
+            transfer_requested: false,
+            internal_clock: false,
+            incoming: 0xFF,
+            awaiting_reply: false,
+            cycles_remaining: 0,
+            cycles_until_poll: 0,
+            endpoint: Box::new(Disconnected),
+        }
+    }
+}
+
+impl Serial {
+    pub fn connect(&mut self, endpoint: Box<dyn SerialEndpoint>) {
+        self.endpoint = endpoint;
+    }
+
+    pub fn step(&mut self, cycles: u8) -> InterruptsToSet {
+        let mut interrupts = InterruptsToSet::default();
+        let cycles = cycles as u32;
+
+        if self.transfer_requested && self.internal_clock {
+            if self.awaiting_reply {
+                if let Some(incoming) = self.endpoint.transfer_reply() {
+                    self.incoming = incoming;
+                    self.awaiting_reply = false;
+                }
+            }
+            if self.cycles_remaining > cycles {
+                self.cycles_remaining -= cycles;
+            } else {
+                self.cycles_remaining = 0;
+                if !self.awaiting_reply {
+                    self.data = self.incoming;
+                    self.transfer_requested = false;
+                    interrupts.set_interrupt(Interrupt::Serial);
+                }
+            }
+            return interrupts;
+        }
+
+        // The other side can clock a transfer at any time, but it cannot be faster than our own
+        // internal clock so there is no need to check more often than once per bit.
+        if self.cycles_until_poll > cycles {
+            self.cycles_until_poll -= cycles;
+            return interrupts;
+        }
+        self.cycles_until_poll = CYCLES_PER_BIT;
+        if let Some(incoming) = self.endpoint.poll_external(self.data) {
+            self.data = incoming;
+            if self.transfer_requested {
+                self.transfer_requested = false;
+                interrupts.set_interrupt(Interrupt::Serial);
+            }
+        }
+        interrupts
+    }
+
+    pub fn supports_io_register(&self, address: usize) -> bool {
+        matches!(address, 0xFF01 | 0xFF02)
+    }
+
+    pub fn read_io_register(&self, address: usize) -> u8 {
+        match address {
+            0xFF01 => self.data,
+            0xFF02 => 0x7E | ((self.transfer_requested as u8) << 7) | (self.internal_clock as u8),
+            _ => panic!("Reading from unknown serial register!"),
+        }
+    }
+
+    pub fn write_io_register(&mut self, value: u8, address: usize) {
+        match address {
+            0xFF01 => self.data = value,
+            0xFF02 => {
+                self.transfer_requested = (value & (1 << 7)) != 0;
+                self.internal_clock = (value & 1) != 0;
+                if self.transfer_requested && self.internal_clock {
+                    match self.endpoint.start_transfer(self.data) {
+                        Some(incoming) => {
+                            self.incoming = incoming;
+                            self.awaiting_reply = false;
+                        }
+                        None => self.awaiting_reply = true,
+                    }
+                    self.cycles_remaining = CYCLES_PER_TRANSFER;
+                }
+            }
+            _ => panic!("Writing to unknown serial register!"),
+        }
+    }
+}
+
+// The endpoint is left connected as it is.
+impl Snapshot for Serial {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.data);
+        state.bool(self.transfer_requested);
+        state.bool(self.internal_clock);
+        state.u8(self.incoming);
+        state.bool(self.awaiting_reply);
+        state.u32(self.cycles_remaining);
+        state.u32(self.cycles_until_poll);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) {
+        self.data = state.u8();
+        self.transfer_requested = state.bool();
+        self.internal_clock = state.bool();
+        self.incoming = state.u8();
+        self.awaiting_reply = state.bool();
+        self.cycles_remaining = state.u32();
+        self.cycles_until_poll = state.u32();
+    }
+}
@@ -0,0 +1,283 @@
+use super::SerialEndpoint;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MAGIC: [u8; 2] = [0x88, 0x33];
+const ALIVE: u8 = 0x81;
+
+const COMMAND_INITIALIZE: u8 = 0x01;
+const COMMAND_PRINT: u8 = 0x02;
+const COMMAND_DATA: u8 = 0x04;
+const COMMAND_STATUS: u8 = 0x0F;
+
+const STATUS_CHECKSUM_ERROR: u8 = 1 << 0;
+const STATUS_PRINTING: u8 = 1 << 1;
+const STATUS_IMAGE_FULL: u8 = 1 << 2;
+const STATUS_UNPROCESSED_DATA: u8 = 1 << 3;
+
+const TILES_PER_ROW: usize = 20;
+const BYTES_PER_TILE: usize = 16;
+const WIDTH: usize = TILES_PER_ROW * 8;
+// The printer's memory holds 9 data packets of 2 tile rows each.
+const MAX_IMAGE_BYTES: usize = 0x280 * 9;
+
+// Games poll the status until the printing bit clears. Real prints take seconds, but nothing
+// depends on that so report it for a few polls only.
+const STATUS_POLLS_WHILE_PRINTING: u8 = 4;
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum PacketState {
+    Magic1,
+    Magic2,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    Alive,
+    Status,
+}
+
+// Emulates a Game Boy Printer on the other end of the link cable. Every print command writes the
+// image received so far to a PNG in the output directory.
+pub struct Printer {
+    output_directory: PathBuf,
+    state: PacketState,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    data: Vec<u8>,
+    checksum: u16,
+    expected_checksum: u16,
+    status: u8,
+    polls_until_printed: u8,
+    image: Vec<u8>,
+    prints: u32,
+}
+
+impl Printer {
+    pub fn new(output_directory: PathBuf) -> Self {
+        Printer {
+            output_directory,
+            state: PacketState::Magic1,
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: Vec::new(),
+            checksum: 0,
+            expected_checksum: 0,
+            status: 0,
+            polls_until_printed: 0,
+            image: Vec::new(),
+            prints: 0,
+        }
+    }
+
+    fn receive(&mut self, byte: u8) -> u8 {
+        match self.state {
+            PacketState::Magic1 if byte == MAGIC[0] => self.state = PacketState::Magic2,
+            PacketState::Magic1 => {}
+            PacketState::Magic2 => {
+                self.state = if byte == MAGIC[1] {
+                    PacketState::Command
+                } else {
+                    PacketState::Magic1
+                };
+            }
+            PacketState::Command => {
+                self.command = byte;
+                self.checksum = byte as u16;
+                self.state = PacketState::Compression;
+            }
+            PacketState::Compression => {
+                self.compressed = (byte & 1) != 0;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                self.state = PacketState::LengthLow;
+            }
+            PacketState::LengthLow => {
+                self.length = byte as u16;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                self.state = PacketState::LengthHigh;
+            }
+            PacketState::LengthHigh => {
+                self.length |= (byte as u16) << 8;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                self.data.clear();
+                self.state = if self.length == 0 {
+                    PacketState::ChecksumLow
+                } else {
+                    PacketState::Data
+                };
+            }
+            PacketState::Data => {
+                self.data.push(byte);
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                if self.data.len() == self.length as usize {
+                    self.state = PacketState::ChecksumLow;
+                }
+            }
+            PacketState::ChecksumLow => {
+                self.expected_checksum = byte as u16;
+                self.state = PacketState::ChecksumHigh;
+            }
+            PacketState::ChecksumHigh => {
+                self.expected_checksum |= (byte as u16) << 8;
+                self.process_packet();
+                self.state = PacketState::Alive;
+            }
+            PacketState::Alive => {
+                self.state = PacketState::Status;
+                return ALIVE;
+            }
+            PacketState::Status => {
+                self.state = PacketState::Magic1;
+                return self.status;
+            }
+        }
+        0x00
+    }
+
+    fn process_packet(&mut self) {
+        if self.checksum != self.expected_checksum {
+            self.status |= STATUS_CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !STATUS_CHECKSUM_ERROR;
+
+        match self.command {
+            COMMAND_INITIALIZE => {
+                self.image.clear();
+                self.status = 0;
+                self.polls_until_printed = 0;
+            }
+            COMMAND_DATA => {
+                // An empty data packet marks the end of the image.
+                if self.data.is_empty() {
+                    return;
+                }
+                let data = if self.compressed {
+                    decompress(&self.data)
+                } else {
+                    self.data.clone()
+                };
+                let space = MAX_IMAGE_BYTES - self.image.len();
+                self.image.extend(data.into_iter().take(space));
+                self.status |= STATUS_UNPROCESSED_DATA;
+                if self.image.len() == MAX_IMAGE_BYTES {
+                    self.status |= STATUS_IMAGE_FULL;
+                }
+            }
+            COMMAND_PRINT => {
+                // The data is sheets, margins, palette and exposure.
+                let palette = self.data.get(2).copied().unwrap_or(0);
+                let sheets = self.data.first().copied().unwrap_or(1);
+                if sheets > 0 && !self.image.is_empty() {
+                    self.print(palette);
+                }
+                self.image.clear();
+                self.status = STATUS_PRINTING;
+                self.polls_until_printed = STATUS_POLLS_WHILE_PRINTING;
+            }
+            COMMAND_STATUS if self.polls_until_printed > 0 => {
+                self.polls_until_printed -= 1;
+                if self.polls_until_printed == 0 {
+                    self.status &= !STATUS_PRINTING;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn print(&mut self, palette: u8) {
+        let pixels = render(&self.image, palette);
+        let height = pixels.len() / WIDTH;
+        // A PNG can't be empty, and the printer only prints whole rows of tiles.
+        if height == 0 {
+            eprintln!("Skipped a print with less than a row of tiles");
+            return;
+        }
+        self.prints += 1;
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+        let path = self
+            .output_directory
+            .join(format!("print-{}-{}.png", timestamp, self.prints));
+        match save_png(&path, &pixels, height) {
+            Ok(()) => eprintln!("Printed {}", path.display()),
+            Err(err) => eprintln!("Could not save print to {}: {}", path.display(), err),
+        }
+    }
+}
+
+impl SerialEndpoint for Printer {
+    fn start_transfer(&mut self, outgoing: u8) -> Option<u8> {
+        Some(self.receive(outgoing))
+    }
+}
+
+// A control byte with the top bit set repeats the next byte (control & 0x7F) + 2 times, otherwise
+// the next (control + 1) bytes are copied as they are.
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len() * 2);
+    let mut bytes = data.iter();
+    while let Some(&control) = bytes.next() {
+        if (control & 0x80) != 0 {
+            let length = (control & 0x7F) as usize + 2;
+            if let Some(&value) = bytes.next() {
+                output.resize(output.len() + length, value);
+            }
+        } else {
+            let length = control as usize + 1;
+            output.extend(bytes.by_ref().take(length));
+        }
+    }
+    output
+}
+
+// Lays out the tiles 20 to a row and maps each pixel through the palette to a grey level.
+fn render(image: &[u8], palette: u8) -> Vec<u8> {
+    // The printer treats a palette of 0 the same as the identity palette.
+    let palette = if palette == 0 { 0xE4 } else { palette };
+    let tile_rows = image.len() / (TILES_PER_ROW * BYTES_PER_TILE);
+    let mut pixels = vec![0; tile_rows * 8 * WIDTH];
+    for (tile_index, tile) in image.chunks_exact(BYTES_PER_TILE).enumerate() {
+        let tile_row = tile_index / TILES_PER_ROW;
+        if tile_row >= tile_rows {
+            break;
+        }
+        let tile_column = tile_index % TILES_PER_ROW;
+        for row in 0..8 {
+            let low = tile[row * 2];
+            let high = tile[row * 2 + 1];
+            for column in 0..8 {
+                let bit = 7 - column;
+                let value = (((high >> bit) & 1) << 1) | ((low >> bit) & 1);
+                let shade = (palette >> (value * 2)) & 0b11;
+                let y = tile_row * 8 + row;
+                let x = tile_column * 8 + column;
+                pixels[y * WIDTH + x] = match shade {
+                    0 => 0xFF,
+                    1 => 0xAA,
+                    2 => 0x55,
+                    _ => 0x00,
+                };
+            }
+        }
+    }
+    pixels
+}
+
+fn save_png(path: &Path, pixels: &[u8], height: usize) -> Result<(), png::EncodingError> {
+    let file = File::create(path)?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), WIDTH as u32, height as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(pixels)
+}
@@ -0,0 +1,149 @@
+pub mod rewind;
+
+// Each part of the machine writes its state as bytes in a fixed order and reads it back in the same
+// order. Only emulated state is included, not frontend settings such as the colour scheme or where
+// the serial port is connected.
+pub trait Snapshot {
+    fn save_state(&self, state: &mut StateWriter);
+    fn load_state(&mut self, state: &mut StateReader);
+}
+
+pub struct StateWriter {
+    bytes: Vec<u8>,
+    output: bool,
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        StateWriter {
+            bytes: Vec::new(),
+            output: true,
+        }
+    }
+}
+
+impl StateWriter {
+    // Leaves out what only feeds the picture and sound, which depends on frontend settings and on
+    // when the frontend collects frames and samples, so that states can be compared. These states
+    // can't be loaded.
+    pub fn comparable() -> Self {
+        StateWriter {
+            bytes: Vec::new(),
+            output: false,
+        }
+    }
+
+    pub fn includes_output(&self) -> bool {
+        self.output
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn usize(&mut self, value: usize) {
+        self.u32(value as u32);
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    // For data whose length can change, unlike the fixed size arrays written by `bytes`.
+    pub fn vec(&mut self, bytes: &[u8]) {
+        self.usize(bytes.len());
+        self.bytes(bytes);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+// Reading past the end gives zeros and marks the state as truncated, so that a bad state can be
+// detected once it has all been read instead of at every field.
+pub struct StateReader<'a> {
+    bytes: &'a [u8],
+    truncated: bool,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        StateReader {
+            bytes,
+            truncated: false,
+        }
+    }
+
+    pub fn u8(&mut self) -> u8 {
+        let mut value = [0; 1];
+        self.bytes(&mut value);
+        value[0]
+    }
+
+    pub fn bool(&mut self) -> bool {
+        self.u8() != 0
+    }
+
+    pub fn u16(&mut self) -> u16 {
+        let mut value = [0; 2];
+        self.bytes(&mut value);
+        u16::from_le_bytes(value)
+    }
+
+    pub fn u32(&mut self) -> u32 {
+        let mut value = [0; 4];
+        self.bytes(&mut value);
+        u32::from_le_bytes(value)
+    }
+
+    pub fn u64(&mut self) -> u64 {
+        let mut value = [0; 8];
+        self.bytes(&mut value);
+        u64::from_le_bytes(value)
+    }
+
+    pub fn usize(&mut self) -> usize {
+        self.u32() as usize
+    }
+
+    pub fn bytes(&mut self, output: &mut [u8]) {
+        if output.len() > self.bytes.len() {
+            self.truncated = true;
+            self.bytes = &[];
+            output.iter_mut().for_each(|byte| *byte = 0);
+            return;
+        }
+        let (read, rest) = self.bytes.split_at(output.len());
+        output.copy_from_slice(read);
+        self.bytes = rest;
+    }
+
+    pub fn vec(&mut self) -> Vec<u8> {
+        let length = self.usize().min(self.bytes.len());
+        let mut bytes = vec![0; length];
+        self.bytes(&mut bytes);
+        bytes
+    }
+
+    // Whether everything was read, with nothing missing or left over.
+    pub fn is_complete(&self) -> bool {
+        !self.truncated && self.bytes.is_empty()
+    }
+}
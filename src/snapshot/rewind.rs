@@ -0,0 +1,190 @@
+use crate::cpu::{CPU, CYCLES_PER_FRAME};
+use std::collections::VecDeque;
+
+// Snapshots taken as the game runs, so that it can be played backwards. Only the latest snapshot
+// is kept whole. Each older one is kept as its difference from the one after it, which is small as
+// little of memory and VRAM changes from frame to frame. The oldest are dropped to stay within the
+// memory budget.
+pub struct Rewind {
+    cycles_per_snapshot: u32,
+    cycles_since_snapshot: u32,
+    budget: usize,
+    latest: Vec<u8>,
+    deltas: VecDeque<Vec<u8>>,
+    deltas_size: usize,
+}
+
+impl Rewind {
+    pub fn new(frames_per_snapshot: u32, budget: usize) -> Self {
+        Rewind {
+            cycles_per_snapshot: frames_per_snapshot.max(1) * CYCLES_PER_FRAME,
+            cycles_since_snapshot: 0,
+            budget,
+            latest: Vec::new(),
+            deltas: VecDeque::new(),
+            deltas_size: 0,
+        }
+    }
+
+    // Called between runs of the CPU with the cycles that were run.
+    pub fn record(&mut self, cpu: &CPU, cycles: u32) {
+        self.cycles_since_snapshot += cycles;
+        if self.cycles_since_snapshot < self.cycles_per_snapshot {
+            return;
+        }
+        self.cycles_since_snapshot %= self.cycles_per_snapshot;
+
+        let snapshot = cpu.snapshot();
+        if !self.latest.is_empty() {
+            let delta = encode_delta(&snapshot, &self.latest);
+            self.deltas_size += delta.len();
+            self.deltas.push_back(delta);
+        }
+        self.latest = snapshot;
+        while self.latest.len() + self.deltas_size > self.budget {
+            match self.deltas.pop_front() {
+                Some(delta) => self.deltas_size -= delta.len(),
+                None => break,
+            }
+        }
+    }
+
+    // Goes back to the snapshot before the latest, returning false once there are none left.
+    pub fn step_back(&mut self, cpu: &mut CPU) -> bool {
+        let delta = match self.deltas.pop_back() {
+            Some(delta) => delta,
+            None => return false,
+        };
+        self.deltas_size -= delta.len();
+        self.latest = match decode_delta(&self.latest, &delta) {
+            Ok(older) => older,
+            Err(err) => {
+                eprintln!("Could not rewind: {}", err);
+                self.deltas.clear();
+                self.deltas_size = 0;
+                return false;
+            }
+        };
+        self.cycles_since_snapshot = 0;
+        match cpu.restore(&self.latest) {
+            Ok(()) => true,
+            Err(err) => {
+                eprintln!("Could not rewind: {}", err);
+                false
+            }
+        }
+    }
+}
+
+// A delta is the length of the older snapshot, then runs of the bytes that differ from the newer
+// snapshot XORed with it, each after the number of bytes that are the same.
+fn encode_delta(newer: &[u8], older: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::new();
+    write_length(&mut delta, older.len());
+    let difference = |index: usize| older[index] ^ newer.get(index).copied().unwrap_or(0);
+    let mut index = 0;
+    while index < older.len() {
+        let unchanged_start = index;
+        while index < older.len() && difference(index) == 0 {
+            index += 1;
+        }
+        let changed_start = index;
+        while index < older.len() && difference(index) != 0 {
+            index += 1;
+        }
+        write_length(&mut delta, changed_start - unchanged_start);
+        write_length(&mut delta, index - changed_start);
+        delta.extend((changed_start..index).map(difference));
+    }
+    delta
+}
+
+fn decode_delta(newer: &[u8], delta: &[u8]) -> Result<Vec<u8>, String> {
+    let corrupt = || "The rewind delta is corrupt".to_string();
+    let mut delta = delta.iter().copied();
+    let length = read_length(&mut delta).ok_or_else(corrupt)?;
+    let mut older: Vec<u8> = (0..length)
+        .map(|index| newer.get(index).copied().unwrap_or(0))
+        .collect();
+    let mut index = 0;
+    while index < length {
+        let unchanged = read_length(&mut delta).ok_or_else(corrupt)?;
+        let changed = read_length(&mut delta).ok_or_else(corrupt)?;
+        let changed_start = index.checked_add(unchanged).ok_or_else(corrupt)?;
+        let end = changed_start.checked_add(changed).ok_or_else(corrupt)?;
+        if end == index || end > length {
+            return Err(corrupt());
+        }
+        for byte in older[changed_start..end].iter_mut() {
+            *byte ^= delta.next().ok_or_else(corrupt)?;
+        }
+        index = end;
+    }
+    Ok(older)
+}
+
+// Lengths are written 7 bits at a time, with the top bit set on all but the last byte.
+fn write_length(output: &mut Vec<u8>, mut length: usize) {
+    while length >= 0x80 {
+        output.push((length as u8 & 0x7F) | 0x80);
+        length >>= 7;
+    }
+    output.push(length as u8);
+}
+
+// Gives None if the input ends first or the length doesn't fit in a usize.
+fn read_length(input: &mut impl Iterator<Item = u8>) -> Option<usize> {
+    let mut length = 0usize;
+    for (position, byte) in input.enumerate() {
+        let bits = (byte & 0x7F) as usize;
+        let shift = position as u32 * 7;
+        if shift >= usize::BITS || (bits << shift) >> shift != bits {
+            return None;
+        }
+        length |= bits << shift;
+        if (byte & 0x80) == 0 {
+            return Some(length);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(newer: &[u8], older: &[u8]) {
+        let delta = encode_delta(newer, older);
+        assert_eq!(decode_delta(newer, &delta).as_deref(), Ok(older));
+    }
+
+    #[test]
+    fn deltas_round_trip() {
+        let older: Vec<u8> = (0..600).map(|index| (index * 7) as u8).collect();
+        let mut newer = older.clone();
+        round_trip(&newer, &older);
+
+        for byte in newer[10..300].iter_mut() {
+            *byte = !*byte;
+        }
+        newer[450] ^= 1;
+        round_trip(&newer, &older);
+        round_trip(&newer[..200], &older);
+        newer.extend_from_slice(&[1; 0x90]);
+        round_trip(&newer, &older);
+        round_trip(&older, &[]);
+    }
+
+    #[test]
+    fn corrupt_deltas_are_rejected() {
+        let older = [1u8; 0x100];
+        let delta = encode_delta(&[0; 0x100], &older);
+        assert!(decode_delta(&[], &delta[..delta.len() - 1]).is_err());
+        // A run past the end of the snapshot.
+        assert!(decode_delta(&[], &[0x04, 0x02, 0x03, 1, 2, 3]).is_err());
+        // A run that doesn't move forward.
+        assert!(decode_delta(&[], &[0x04, 0x00, 0x00]).is_err());
+        // A length that doesn't fit in a usize.
+        assert!(decode_delta(&[], &[0xFF; 12]).is_err());
+    }
+}
@@ -1,6 +1,97 @@
 pub mod frame_sequencer;
+pub mod triple_buffer;
 
 #[allow(dead_code)]
 pub fn dump_bytes(bytes: &[u8], filename: &str) {
     std::fs::write(filename, bytes).unwrap();
 }
+
+// The CRC-32 used by zip and PNG, for identifying ROMs and checking that states match.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0xEDB88320,
+                _ => crc >> 1,
+            };
+        }
+    }
+    !crc
+}
+
+// SHA-1, which BizHawk movies identify ROMs with.
+pub fn sha1(bytes: &[u8]) -> [u8; 20] {
+    let mut hash: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut message = bytes.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(bytes.len() as u64 * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut words = [0u32; 80];
+        for (index, word) in block.chunks(4).enumerate() {
+            words[index] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for index in 16..80 {
+            words[index] =
+                (words[index - 3] ^ words[index - 8] ^ words[index - 14] ^ words[index - 16])
+                    .rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = hash;
+        for (index, &word) in words.iter().enumerate() {
+            let (f, k) = match index {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (value, added) in hash.iter_mut().zip([a, b, c, d, e].iter()) {
+            *value = value.wrapping_add(*added);
+        }
+    }
+
+    let mut digest = [0; 20];
+    for (output, value) in digest.chunks_mut(4).zip(hash.iter()) {
+        output.copy_from_slice(&value.to_be_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_checksums() {
+        let sha1_hex = |bytes: &[u8]| -> String {
+            sha1(bytes)
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect()
+        };
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(sha1_hex(b"abc"), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        // Long enough that the padding needs a second block.
+        assert_eq!(
+            sha1_hex(&[b'a'; 64]),
+            "0098ba824b5c16427bd7a1122a5a442a25ec644d"
+        );
+    }
+}
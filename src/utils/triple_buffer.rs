@@ -0,0 +1,143 @@
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+
+// Hands whole values from one thread to another without either waiting on the other. The writer
+// and the reader each own one of three buffers, and the third sits between them holding the
+// latest value published. Publishing swaps the writer's buffer for the middle one, and reading
+// swaps the reader's buffer for it if anything new has been published since. Neither side ever
+// touches a buffer the other owns, so the reader never sees a value half written, and a value the
+// reader missed is simply overwritten by the next.
+const INDEX_MASK: u8 = 0b011;
+// Set in the middle index when the buffer there hasn't been read yet.
+const PUBLISHED: u8 = 0b100;
+
+struct Buffers<T> {
+    buffers: [UnsafeCell<T>; 3],
+    middle: AtomicU8,
+}
+
+// SAFETY: each buffer is only ever accessed by whichever side owns its index, and ownership only
+// changes hands through the atomic swaps of the middle index.
+unsafe impl<T: Send> Sync for Buffers<T> {}
+
+pub struct TripleBufferWriter<T> {
+    buffers: Arc<Buffers<T>>,
+    index: u8,
+}
+
+pub struct TripleBufferReader<T> {
+    buffers: Arc<Buffers<T>>,
+    index: u8,
+}
+
+pub fn triple_buffer<T: Clone>(initial: T) -> (TripleBufferWriter<T>, TripleBufferReader<T>) {
+    let buffers = Arc::new(Buffers {
+        buffers: [
+            UnsafeCell::new(initial.clone()),
+            UnsafeCell::new(initial.clone()),
+            UnsafeCell::new(initial),
+        ],
+        middle: AtomicU8::new(1),
+    });
+    let writer = TripleBufferWriter {
+        buffers: Arc::clone(&buffers),
+        index: 0,
+    };
+    let reader = TripleBufferReader { buffers, index: 2 };
+    (writer, reader)
+}
+
+impl<T> TripleBufferWriter<T> {
+    // The buffer to fill in before publishing it, which holds whatever was published two
+    // values ago.
+    pub fn buffer(&mut self) -> &mut T {
+        // SAFETY: the writer owns the buffer at its index until it publishes it, and the borrow of
+        // self keeps the reference from outliving that.
+        unsafe { &mut *self.buffers.buffers[self.index as usize].get() }
+    }
+
+    pub fn publish(&mut self) {
+        let middle = self
+            .buffers
+            .middle
+            .swap(self.index | PUBLISHED, Ordering::AcqRel);
+        self.index = middle & INDEX_MASK;
+    }
+}
+
+impl<T> TripleBufferReader<T> {
+    // The latest value published, or the one read last time if nothing has been since.
+    pub fn read(&mut self) -> &T {
+        if (self.buffers.middle.load(Ordering::Relaxed) & PUBLISHED) != 0 {
+            let middle = self.buffers.middle.swap(self.index, Ordering::AcqRel);
+            self.index = middle & INDEX_MASK;
+        }
+        // SAFETY: the reader owns the buffer at its index until it swaps it for the middle one,
+        // which takes the mutable borrow of self that this reference holds.
+        unsafe { &*self.buffers.buffers[self.index as usize].get() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn reads_the_initial_value_until_something_is_published() {
+        let (_writer, mut reader) = triple_buffer(0);
+        assert_eq!(*reader.read(), 0);
+        assert_eq!(*reader.read(), 0);
+    }
+
+    #[test]
+    fn reads_values_in_the_order_they_are_published() {
+        let (mut writer, mut reader) = triple_buffer(0);
+        for value in 1..=5 {
+            *writer.buffer() = value;
+            writer.publish();
+            assert_eq!(*reader.read(), value);
+        }
+    }
+
+    #[test]
+    fn reads_the_latest_value_and_keeps_it_until_the_next() {
+        let (mut writer, mut reader) = triple_buffer(0);
+        for value in 1..=3 {
+            *writer.buffer() = value;
+            writer.publish();
+        }
+        assert_eq!(*reader.read(), 3);
+        assert_eq!(*reader.read(), 3);
+        *writer.buffer() = 4;
+        assert_eq!(*reader.read(), 3);
+        writer.publish();
+        assert_eq!(*reader.read(), 4);
+    }
+
+    #[test]
+    fn values_are_never_torn_or_older_across_threads() {
+        const LAST: u64 = 100_000;
+        let (mut writer, mut reader) = triple_buffer([0u64; 64]);
+        let writer_thread = thread::spawn(move || {
+            for value in 1..=LAST {
+                writer.buffer().iter_mut().for_each(|word| *word = value);
+                writer.publish();
+            }
+        });
+        let mut last_read = 0;
+        while last_read != LAST {
+            let words = reader.read();
+            assert!(words.iter().all(|&word| word == words[0]), "torn read");
+            assert!(
+                words[0] >= last_read,
+                "read {} after {}",
+                words[0],
+                last_read
+            );
+            last_read = words[0];
+        }
+        writer_thread.join().unwrap();
+    }
+}